@@ -0,0 +1,4 @@
+ALTER TABLE entries
+  DROP COLUMN captured_at,
+  DROP COLUMN photo_latitude,
+  DROP COLUMN photo_longitude;
@@ -0,0 +1,4 @@
+ALTER TABLE entries
+  ADD COLUMN captured_at     TIMESTAMP        DEFAULT NULL,
+  ADD COLUMN photo_latitude  DOUBLE PRECISION DEFAULT NULL,
+  ADD COLUMN photo_longitude DOUBLE PRECISION DEFAULT NULL;
@@ -0,0 +1,10 @@
+CREATE TABLE attachments (
+  id        SERIAL PRIMARY KEY,
+  entry_id  INTEGER NOT NULL REFERENCES entries (id),
+  position  INTEGER NOT NULL,
+  caption   VARCHAR,
+  mime_type VARCHAR NOT NULL,
+  size      BIGINT  NOT NULL DEFAULT 0
+);
+
+CREATE INDEX attachments_entry_id_position ON attachments (entry_id, position);
@@ -0,0 +1,3 @@
+-- attachments uploaded directly to storage are pending until confirmed
+ALTER TABLE attachments
+  ADD COLUMN uploaded BOOLEAN NOT NULL DEFAULT 't';
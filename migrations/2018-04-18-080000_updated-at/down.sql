@@ -0,0 +1,10 @@
+DROP TRIGGER touch_entry ON attachments;
+DROP FUNCTION touch_attachment_entry();
+
+DROP TRIGGER set_updated_at ON entries;
+DROP TRIGGER set_updated_at ON journeys;
+
+ALTER TABLE entries
+  DROP COLUMN updated_at;
+ALTER TABLE journeys
+  DROP COLUMN updated_at;
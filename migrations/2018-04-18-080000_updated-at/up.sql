@@ -0,0 +1,24 @@
+ALTER TABLE journeys
+  ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT now();
+ALTER TABLE entries
+  ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT now();
+
+SELECT diesel_manage_updated_at('journeys');
+SELECT diesel_manage_updated_at('entries');
+
+-- changes to the attachments of an entry change the entry
+CREATE OR REPLACE FUNCTION touch_attachment_entry() RETURNS trigger AS $$
+BEGIN
+    IF (TG_OP = 'DELETE') THEN
+        UPDATE entries SET updated_at = current_timestamp
+        WHERE id = OLD.entry_id;
+    ELSE
+        UPDATE entries SET updated_at = current_timestamp
+        WHERE id = NEW.entry_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER touch_entry AFTER INSERT OR UPDATE OR DELETE ON attachments
+    FOR EACH ROW EXECUTE PROCEDURE touch_attachment_entry();
@@ -0,0 +1,6 @@
+DROP INDEX attachments_sha256;
+
+ALTER TABLE attachments
+  DROP COLUMN sha256;
+
+DROP TABLE media_blobs;
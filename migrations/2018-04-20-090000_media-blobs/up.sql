@@ -0,0 +1,11 @@
+-- media stored once under its SHA-256 hash, referenced by attachments
+CREATE TABLE media_blobs (
+  sha256   VARCHAR PRIMARY KEY,
+  refcount INTEGER NOT NULL DEFAULT 0
+);
+
+-- attachments without a hash are stored under their own ID
+ALTER TABLE attachments
+  ADD COLUMN sha256 VARCHAR REFERENCES media_blobs (sha256);
+
+CREATE INDEX attachments_sha256 ON attachments (sha256);
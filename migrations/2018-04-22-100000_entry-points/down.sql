@@ -0,0 +1,13 @@
+ALTER TABLE entries
+  ADD COLUMN coordinates VARCHAR;
+
+UPDATE entries
+SET coordinates = latitude || ',' || longitude
+WHERE latitude IS NOT NULL;
+
+ALTER TABLE entries
+  DROP CONSTRAINT entries_point,
+  DROP COLUMN latitude,
+  DROP COLUMN longitude,
+  DROP COLUMN accuracy,
+  DROP COLUMN altitude;
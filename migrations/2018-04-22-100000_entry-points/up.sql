@@ -0,0 +1,30 @@
+ALTER TABLE entries
+  ADD COLUMN latitude  DOUBLE PRECISION
+    CHECK (latitude BETWEEN -90 AND 90),
+  ADD COLUMN longitude DOUBLE PRECISION
+    CHECK (longitude BETWEEN -180 AND 180),
+  ADD COLUMN accuracy  DOUBLE PRECISION
+    CHECK (accuracy >= 0),
+  ADD COLUMN altitude  DOUBLE PRECISION,
+  ADD CONSTRAINT entries_point
+    CHECK ((latitude IS NULL) = (longitude IS NULL));
+
+-- convert coordinates given as "lat,lon" or "lat lon", other values are lost
+UPDATE entries
+SET latitude = parsed.lat, longitude = parsed.lon
+FROM (
+  SELECT id, m[1]::DOUBLE PRECISION AS lat, m[2]::DOUBLE PRECISION AS lon
+  FROM (
+    SELECT id, regexp_matches(
+      coordinates,
+      '^\s*([-+]?[0-9]+(?:\.[0-9]+)?)\s*[,\s]\s*([-+]?[0-9]+(?:\.[0-9]+)?)\s*$'
+    ) AS m
+    FROM entries
+  ) matches
+) parsed
+WHERE entries.id = parsed.id
+  AND parsed.lat BETWEEN -90 AND 90
+  AND parsed.lon BETWEEN -180 AND 180;
+
+ALTER TABLE entries
+  DROP COLUMN coordinates;
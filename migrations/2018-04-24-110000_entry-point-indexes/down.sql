@@ -0,0 +1,5 @@
+DROP INDEX entries_earth;
+DROP INDEX entries_lat_lon;
+
+DROP EXTENSION IF EXISTS earthdistance;
+DROP EXTENSION IF EXISTS cube;
@@ -0,0 +1,12 @@
+-- distances on the earth's surface, with cube-based GiST indexes
+CREATE EXTENSION IF NOT EXISTS cube;
+CREATE EXTENSION IF NOT EXISTS earthdistance;
+
+-- bounding box queries
+CREATE INDEX entries_lat_lon ON entries (latitude, longitude)
+  WHERE latitude IS NOT NULL;
+
+-- radius and nearest queries
+CREATE INDEX entries_earth ON entries
+  USING gist (ll_to_earth(latitude, longitude))
+  WHERE latitude IS NOT NULL;
@@ -0,0 +1,2 @@
+DROP TRIGGER touch_journey ON entries;
+DROP FUNCTION touch_entry_journey();
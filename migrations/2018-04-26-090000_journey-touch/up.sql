@@ -0,0 +1,17 @@
+-- changes to the entries of a journey change the journey
+CREATE OR REPLACE FUNCTION touch_entry_journey() RETURNS trigger AS $$
+BEGIN
+    IF (TG_OP <> 'INSERT') THEN
+        UPDATE journeys SET updated_at = current_timestamp
+        WHERE id = OLD.journey_id;
+    END IF;
+    IF (TG_OP <> 'DELETE') THEN
+        UPDATE journeys SET updated_at = current_timestamp
+        WHERE id = NEW.journey_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER touch_journey AFTER INSERT OR UPDATE OR DELETE ON entries
+    FOR EACH ROW EXECUTE PROCEDURE touch_entry_journey();
@@ -0,0 +1,14 @@
+-- tracks recorded along journeys, imported from GPS devices
+CREATE TABLE track_points (
+  id          SERIAL PRIMARY KEY,
+  journey_id  INTEGER          NOT NULL
+                               REFERENCES journeys (id) ON DELETE CASCADE,
+  recorded_at TIMESTAMP,
+  latitude    DOUBLE PRECISION NOT NULL
+                               CHECK (latitude BETWEEN -90 AND 90),
+  longitude   DOUBLE PRECISION NOT NULL
+                               CHECK (longitude BETWEEN -180 AND 180),
+  altitude    DOUBLE PRECISION
+);
+
+CREATE INDEX track_points_journey ON track_points (journey_id, recorded_at);
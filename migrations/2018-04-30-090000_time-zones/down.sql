@@ -0,0 +1,17 @@
+ALTER TABLE entries
+  DROP COLUMN time_zone;
+ALTER TABLE users
+  DROP COLUMN time_zone;
+
+ALTER TABLE track_points
+  ALTER COLUMN recorded_at TYPE TIMESTAMP
+    USING recorded_at AT TIME ZONE 'UTC';
+ALTER TABLE entries
+  ALTER COLUMN created TYPE TIMESTAMP USING created AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_at TYPE TIMESTAMP USING updated_at AT TIME ZONE 'UTC';
+ALTER TABLE journeys
+  ALTER COLUMN start_date TYPE TIMESTAMP USING start_date AT TIME ZONE 'UTC',
+  ALTER COLUMN end_date TYPE TIMESTAMP USING end_date AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_at TYPE TIMESTAMP USING updated_at AT TIME ZONE 'UTC';
+ALTER TABLE users
+  ALTER COLUMN date TYPE TIMESTAMP USING date AT TIME ZONE 'UTC';
@@ -0,0 +1,24 @@
+-- instants are stored in UTC, naive timestamps were written in UTC
+ALTER TABLE users
+  ALTER COLUMN date TYPE TIMESTAMPTZ USING date AT TIME ZONE 'UTC';
+ALTER TABLE journeys
+  ALTER COLUMN start_date TYPE TIMESTAMPTZ USING start_date AT TIME ZONE 'UTC',
+  ALTER COLUMN end_date TYPE TIMESTAMPTZ USING end_date AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING updated_at AT TIME ZONE 'UTC';
+ALTER TABLE entries
+  ALTER COLUMN created TYPE TIMESTAMPTZ USING created AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING updated_at AT TIME ZONE 'UTC';
+ALTER TABLE track_points
+  ALTER COLUMN recorded_at TYPE TIMESTAMPTZ
+    USING recorded_at AT TIME ZONE 'UTC';
+
+-- IANA time zones in which times are shown, existing users and entries keep
+-- the central European time they were shown in so far
+ALTER TABLE users
+  ADD COLUMN time_zone VARCHAR NOT NULL DEFAULT 'Europe/Amsterdam';
+ALTER TABLE users
+  ALTER COLUMN time_zone SET DEFAULT 'UTC';
+ALTER TABLE entries
+  ADD COLUMN time_zone VARCHAR NOT NULL DEFAULT 'Europe/Amsterdam';
+ALTER TABLE entries
+  ALTER COLUMN time_zone SET DEFAULT 'UTC';
@@ -0,0 +1,16 @@
+-- when the server received an entry, which may be long after it was written
+-- offline; existing entries were received when they were created
+ALTER TABLE entries
+  ADD COLUMN received_at TIMESTAMPTZ;
+
+-- backfilling is not a change to the entries or their journeys, so it must
+-- not bump `updated_at` through the triggers
+ALTER TABLE entries DISABLE TRIGGER set_updated_at;
+ALTER TABLE entries DISABLE TRIGGER touch_journey;
+UPDATE entries SET received_at = created;
+ALTER TABLE entries ENABLE TRIGGER set_updated_at;
+ALTER TABLE entries ENABLE TRIGGER touch_journey;
+
+ALTER TABLE entries
+  ALTER COLUMN received_at SET DEFAULT now(),
+  ALTER COLUMN received_at SET NOT NULL;
@@ -0,0 +1,30 @@
+DROP TRIGGER record_deletion ON attachments;
+DROP TRIGGER record_deletion ON entries;
+DROP TRIGGER record_deletion ON journeys;
+DROP FUNCTION record_deletion();
+
+DROP TABLE deletions;
+
+DROP INDEX attachments_updated_at;
+DROP INDEX entries_user_id_updated_at;
+DROP INDEX journeys_user_id_updated_at;
+
+DROP TRIGGER set_version ON entries;
+DROP TRIGGER set_version ON journeys;
+DROP FUNCTION set_version();
+
+ALTER TABLE entries
+  DROP COLUMN version;
+ALTER TABLE journeys
+  DROP COLUMN version;
+
+DROP TRIGGER set_updated_at ON attachments;
+
+ALTER TABLE attachments
+  DROP COLUMN user_id,
+  DROP COLUMN uuid,
+  DROP COLUMN updated_at;
+ALTER TABLE entries
+  DROP COLUMN uuid;
+ALTER TABLE journeys
+  DROP COLUMN uuid;
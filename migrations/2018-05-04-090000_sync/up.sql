@@ -0,0 +1,79 @@
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+-- rows are identified across devices by UUIDs, which clients may generate
+ALTER TABLE journeys
+  ADD COLUMN uuid UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+ALTER TABLE entries
+  ADD COLUMN uuid UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+ALTER TABLE attachments
+  ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  ADD COLUMN uuid       UUID        NOT NULL UNIQUE DEFAULT gen_random_uuid(),
+  ADD COLUMN user_id    INTEGER     REFERENCES users (id);
+
+-- attachments keep their owner, so that their deletion is recorded for the
+-- owner even when their entry is gone
+ALTER TABLE attachments DISABLE TRIGGER touch_entry;
+UPDATE attachments
+SET user_id = entries.user_id
+FROM entries
+WHERE entries.id = attachments.entry_id;
+ALTER TABLE attachments ENABLE TRIGGER touch_entry;
+
+ALTER TABLE attachments
+  ALTER COLUMN user_id SET NOT NULL;
+
+SELECT diesel_manage_updated_at('attachments');
+
+-- the version of a journey or entry changes with the row itself, whereas
+-- `updated_at` also changes with the entries of a journey and the
+-- attachments of an entry
+ALTER TABLE journeys
+  ADD COLUMN version TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE entries
+  ADD COLUMN version TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE OR REPLACE FUNCTION set_version() RETURNS trigger AS $$
+BEGIN
+    IF (to_jsonb(NEW) - 'updated_at' - 'version'
+        IS DISTINCT FROM to_jsonb(OLD) - 'updated_at' - 'version') THEN
+        NEW.version := current_timestamp;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER set_version BEFORE UPDATE ON journeys
+    FOR EACH ROW EXECUTE PROCEDURE set_version();
+CREATE TRIGGER set_version BEFORE UPDATE ON entries
+    FOR EACH ROW EXECUTE PROCEDURE set_version();
+
+CREATE INDEX journeys_user_id_updated_at ON journeys (user_id, updated_at);
+CREATE INDEX entries_user_id_updated_at ON entries (user_id, updated_at);
+CREATE INDEX attachments_updated_at ON attachments (updated_at);
+
+-- rows that were deleted, so that clients can delete them too
+CREATE TABLE deletions (
+  id         SERIAL PRIMARY KEY,
+  user_id    INTEGER REFERENCES users (id) ON DELETE CASCADE,
+  kind       VARCHAR     NOT NULL,
+  row_id     INTEGER     NOT NULL,
+  uuid       UUID        NOT NULL,
+  deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX deletions_user_id_deleted_at ON deletions (user_id, deleted_at);
+
+CREATE OR REPLACE FUNCTION record_deletion() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO deletions (user_id, kind, row_id, uuid)
+    VALUES (OLD.user_id, TG_TABLE_NAME, OLD.id, OLD.uuid);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_deletion AFTER DELETE ON journeys
+    FOR EACH ROW EXECUTE PROCEDURE record_deletion();
+CREATE TRIGGER record_deletion AFTER DELETE ON entries
+    FOR EACH ROW EXECUTE PROCEDURE record_deletion();
+CREATE TRIGGER record_deletion AFTER DELETE ON attachments
+    FOR EACH ROW EXECUTE PROCEDURE record_deletion();
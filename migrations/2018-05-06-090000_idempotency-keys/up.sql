@@ -0,0 +1,14 @@
+-- responses to requests with an idempotency key, to replay them on retries
+CREATE TABLE idempotency_keys (
+  user_id      INTEGER     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  key          VARCHAR     NOT NULL,
+  request_hash VARCHAR,
+  status       INTEGER,
+  location     VARCHAR,
+  body         TEXT,
+  created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, key)
+);
+
+-- stale keys of all users are removed by age
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
@@ -0,0 +1,4 @@
+ALTER TABLE attachments
+  DROP CONSTRAINT attachments_entry_id_position;
+
+CREATE INDEX attachments_entry_id_position ON attachments (entry_id, position);
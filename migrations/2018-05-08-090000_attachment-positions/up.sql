@@ -0,0 +1,18 @@
+-- number the attachments of each entry consecutively, as concurrent uploads
+-- could give attachments the same position
+UPDATE attachments
+SET position = numbered.position
+FROM (
+  SELECT id,
+         (ROW_NUMBER() OVER (PARTITION BY entry_id ORDER BY position, id)
+          - 1)::INTEGER AS position
+  FROM attachments
+) numbered
+WHERE attachments.id = numbered.id
+  AND attachments.position <> numbered.position;
+
+-- deferred, as reordering moves attachments through taken positions
+DROP INDEX attachments_entry_id_position;
+ALTER TABLE attachments
+  ADD CONSTRAINT attachments_entry_id_position
+  UNIQUE (entry_id, position) DEFERRABLE INITIALLY DEFERRED;
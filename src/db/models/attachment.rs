@@ -0,0 +1,554 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use diesel;
+use diesel::dsl::max;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Integer, Text};
+
+use db::models::entry::Entry;
+use db::schema::attachments;
+use uuid::Uuid;
+
+#[derive(Queryable, Identifiable, Associations, Serialize, PartialEq, Debug)]
+#[table_name = "attachments"]
+#[belongs_to(Entry)]
+pub struct Attachment {
+    pub id: i32,
+    pub entry_id: i32,
+    pub position: i32,
+    pub caption: Option<String>,
+    pub mime_type: String,
+    pub size: i64,
+    pub uploaded: bool,
+    pub sha256: Option<String>,
+    pub updated_at: DateTime<Utc>,
+    pub uuid: Uuid,
+    /// The owner of the entry of the attachment.
+    pub user_id: i32,
+}
+
+impl Attachment {
+    /// The storage key of the media of the attachment. Media is stored under
+    /// its hash once it is known, and under the upload key before.
+    pub fn key(&self) -> String {
+        match self.sha256 {
+            Some(ref hash) => format!("media/{}", hash),
+            None => self.upload_key(),
+        }
+    }
+
+    /// The storage key under which the media of the attachment is uploaded.
+    pub fn upload_key(&self) -> String {
+        format!("attachments/{}", self.id)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "attachments"]
+pub struct NewAttachment {
+    pub entry_id: i32,
+    pub position: i32,
+    pub caption: Option<String>,
+    pub mime_type: String,
+    pub size: i64,
+    pub uploaded: bool,
+    pub user_id: i32,
+}
+
+/// Creates an attachment record at the end of the attachments of an entry.
+/// Its size is zero until its media is stored.
+pub fn create(
+    eid: i32,
+    mime: &str,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    insert(eid, mime, 0, true, conn)
+}
+
+/// Creates an attachment record for media that the client uploads directly
+/// to storage. It is hidden until the upload is confirmed. Until then, its
+/// size is the declared size, which counts towards the storage quota.
+pub fn create_pending(
+    eid: i32,
+    mime: &str,
+    declared_size: i64,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    insert(eid, mime, declared_size, false, conn)
+}
+
+/// Locks the row of an entry until the end of the transaction, so that the
+/// positions of its attachments are changed by one transaction at a time.
+fn lock_entry(eid: i32, conn: &PgConnection) -> diesel::QueryResult<()> {
+    sql_query("SELECT id FROM entries WHERE id = $1 FOR UPDATE")
+        .bind::<Integer, _>(eid)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+fn insert(
+    eid: i32,
+    mime: &str,
+    bytes: i64,
+    is_uploaded: bool,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    use db::schema::attachments::dsl::*;
+    use db::schema::entries;
+
+    conn.transaction(|| {
+        lock_entry(eid, conn)?;
+
+        let owner = entries::table
+            .find(eid)
+            .select(entries::user_id)
+            .first::<i32>(conn)?;
+
+        let last = attachments
+            .filter(entry_id.eq(eid))
+            .select(max(position))
+            .first::<Option<i32>>(conn)?;
+
+        let attachment = NewAttachment {
+            entry_id: eid,
+            position: last.map_or(0, |p| p + 1),
+            caption: None,
+            mime_type: mime.to_string(),
+            size: bytes,
+            uploaded: is_uploaded,
+            user_id: owner,
+        };
+
+        diesel::insert_into(attachments)
+            .values(&attachment)
+            .get_result::<Attachment>(conn)
+    }).map(|attachment| {
+        info!("Created attachment {:?}", attachment);
+        attachment
+    })
+}
+
+/// Records the size of the stored media of an attachment.
+pub fn set_size(
+    aid: i32,
+    bytes: i64,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    use db::schema::attachments::dsl::*;
+
+    diesel::update(attachments.find(aid))
+        .set(size.eq(bytes))
+        .get_result(conn)
+}
+
+/// Marks a pending attachment as uploaded, and records its size.
+pub fn confirm(
+    aid: i32,
+    bytes: i64,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    use db::schema::attachments::dsl::*;
+
+    diesel::update(attachments.find(aid))
+        .set((size.eq(bytes), uploaded.eq(true)))
+        .get_result(conn)
+}
+
+/// Marks a pending attachment whose media was streamed to storage as
+/// uploaded, records its size and links it to the media with the given
+/// SHA-256 hash, all in one transaction.
+/// Returns the linked attachment, and whether the media was not stored yet.
+pub fn complete(
+    aid: i32,
+    bytes: i64,
+    hash: &str,
+    conn: &PgConnection,
+) -> diesel::QueryResult<(Attachment, bool)> {
+    conn.transaction(|| {
+        confirm(aid, bytes, conn)?;
+        link(aid, hash, conn)
+    })
+}
+
+/// Locks the row of the media with the given SHA-256 hash, if any, until
+/// the end of the transaction, so that its references are counted by one
+/// transaction at a time.
+fn lock_blob(hash: &str, conn: &PgConnection) -> diesel::QueryResult<()> {
+    sql_query("SELECT sha256 FROM media_blobs WHERE sha256 = $1 FOR UPDATE")
+        .bind::<Text, _>(hash)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Links an attachment to the media with the given SHA-256 hash, adding a
+/// reference to it.
+/// Returns the linked attachment, and whether the media was not stored yet.
+pub fn link(
+    aid: i32,
+    hash: &str,
+    conn: &PgConnection,
+) -> diesel::QueryResult<(Attachment, bool)> {
+    use db::schema::attachments::dsl::*;
+    use db::schema::media_blobs;
+
+    conn.transaction(|| {
+        lock_blob(hash, conn)?;
+
+        let refs = diesel::insert_into(media_blobs::table)
+            .values((
+                media_blobs::sha256.eq(hash),
+                media_blobs::refcount.eq(1),
+            ))
+            .on_conflict(media_blobs::sha256)
+            .do_update()
+            .set(media_blobs::refcount.eq(media_blobs::refcount + 1))
+            .returning(media_blobs::refcount)
+            .get_result::<i32>(conn)?;
+
+        let attachment = diesel::update(attachments.find(aid))
+            .set(sha256.eq(hash))
+            .get_result::<Attachment>(conn)?;
+
+        Ok((attachment, refs == 1))
+    })
+}
+
+/// Undoes linking an attachment to media that could not be stored under its
+/// hash, so that its media is kept under its upload key.
+pub fn unlink(
+    attachment: &Attachment,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Attachment> {
+    use db::schema::attachments::dsl::*;
+
+    conn.transaction(|| {
+        // the media was never stored under the hash, so there is nothing to
+        // remove from storage
+        release(attachment, conn)?;
+
+        diesel::update(attachments.find(attachment.id))
+            .set(sha256.eq(None::<String>))
+            .get_result::<Attachment>(conn)
+    })
+}
+
+/// Removes the reference of a deleted attachment to its media.
+/// Returns the storage key of the media if it is no longer referenced, and
+/// is to be removed from storage.
+pub fn release(
+    attachment: &Attachment,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Option<String>> {
+    use db::schema::media_blobs::dsl::*;
+
+    let hash = match attachment.sha256 {
+        Some(ref hash) => hash,
+        None => return Ok(Some(attachment.key())),
+    };
+
+    conn.transaction(|| {
+        lock_blob(hash, conn)?;
+
+        let refs = diesel::update(media_blobs.find(hash))
+            .set(refcount.eq(refcount - 1))
+            .returning(refcount)
+            .get_result::<i32>(conn)?;
+
+        if refs > 0 {
+            return Ok(None);
+        }
+
+        diesel::delete(media_blobs.find(hash)).execute(conn)?;
+        Ok(Some(attachment.key()))
+    })
+}
+
+/// Loads the pending attachments that have not changed since `before`, as
+/// their uploads were never confirmed.
+pub fn stale_pending(
+    before: DateTime<Utc>,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Vec<Attachment>> {
+    use db::schema::attachments::dsl::*;
+
+    attachments
+        .filter(uploaded.eq(false))
+        .filter(updated_at.lt(before))
+        .load::<Attachment>(conn)
+}
+
+/// Loads the uploaded attachments of an entry, ordered by position.
+pub fn load(
+    entry: &Entry,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Vec<Attachment>> {
+    use db::schema::attachments::dsl::*;
+
+    Attachment::belonging_to(entry)
+        .filter(uploaded.eq(true))
+        .order(position.asc())
+        .load::<Attachment>(conn)
+}
+
+/// Loads the uploaded attachments of each entry, ordered by position.
+pub fn load_for(
+    entries: &[Entry],
+    conn: &PgConnection,
+) -> diesel::QueryResult<Vec<Vec<Attachment>>> {
+    use db::schema::attachments::dsl::*;
+
+    let result = Attachment::belonging_to(entries)
+        .filter(uploaded.eq(true))
+        .order(position.asc())
+        .load::<Attachment>(conn)?
+        .grouped_by(entries);
+
+    Ok(result)
+}
+
+/// Locks the owner of an entry until the end of the transaction, so that
+/// its storage is reserved by one transaction at a time.
+/// Returns the ID of the owner.
+pub fn lock_owner(eid: i32, conn: &PgConnection) -> diesel::QueryResult<i32> {
+    use db::schema::entries;
+
+    let owner = entries::table
+        .find(eid)
+        .select(entries::user_id)
+        .first::<i32>(conn)?;
+    sql_query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+        .bind::<Integer, _>(owner)
+        .execute(conn)?;
+
+    Ok(owner)
+}
+
+/// Sums up the sizes of the media attached to the entries of a user, per
+/// journey ID. Pending uploads count with their declared size.
+pub fn usage(
+    uid: i32,
+    conn: &PgConnection,
+) -> diesel::QueryResult<BTreeMap<i32, i64>> {
+    use db::schema::{attachments, entries};
+
+    let sizes = attachments::table
+        .inner_join(entries::table)
+        .filter(entries::user_id.eq(uid))
+        .select((entries::journey_id, attachments::size))
+        .load::<(i32, i64)>(conn)?;
+
+    let mut usage = BTreeMap::new();
+    for (journey, bytes) in sizes {
+        *usage.entry(journey).or_insert(0) += bytes;
+    }
+
+    Ok(usage)
+}
+
+/// Orders the attachments of an entry as in `order`, which must contain the
+/// IDs of all of its uploaded attachments exactly once. Pending attachments
+/// keep their order after them.
+/// Returns `false` without changes if `order` does not match.
+pub fn reorder(
+    eid: i32,
+    order: &[i32],
+    conn: &PgConnection,
+) -> diesel::QueryResult<bool> {
+    use db::schema::attachments::dsl::*;
+
+    conn.transaction(|| {
+        lock_entry(eid, conn)?;
+
+        let mut current = attachments
+            .filter(entry_id.eq(eid))
+            .filter(uploaded.eq(true))
+            .select(id)
+            .load::<i32>(conn)?;
+        current.sort();
+
+        let mut requested = order.to_vec();
+        requested.sort();
+
+        if current != requested {
+            return Ok(false);
+        }
+
+        let pending = attachments
+            .filter(entry_id.eq(eid))
+            .filter(uploaded.eq(false))
+            .order(position.asc())
+            .select(id)
+            .load::<i32>(conn)?;
+
+        for (pos, aid) in order.iter().chain(&pending).enumerate() {
+            diesel::update(attachments.find(aid))
+                .set(position.eq(pos as i32))
+                .execute(conn)?;
+        }
+
+        Ok(true)
+    })
+}
+
+/// Deletes an attachment record, and moves up the attachments after it.
+/// Returns the storage key of its media if it is no longer referenced.
+pub fn delete(
+    attachment: &Attachment,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Option<String>> {
+    use db::schema::attachments::dsl::*;
+
+    conn.transaction(|| {
+        lock_entry(attachment.entry_id, conn)?;
+        diesel::delete(attachments.find(attachment.id)).execute(conn)?;
+
+        let after = attachments
+            .filter(entry_id.eq(attachment.entry_id))
+            .filter(position.gt(attachment.position));
+        diesel::update(after)
+            .set(position.eq(position - 1))
+            .execute(conn)?;
+
+        info!("Deleted attachment {}", attachment.id);
+        release(attachment, conn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db;
+    use db::models::entry::{self, NewEntry};
+
+    fn create_entry(conn: &PgConnection) -> Entry {
+        let new_entry = NewEntry {
+            user_id: 1,
+            journey_id: 1,
+            description: None,
+            point: None,
+            location: None,
+            time_zone: None,
+            created: None,
+            uuid: None,
+        };
+
+        entry::create(&new_entry, conn).expect("failed to create entry")
+    }
+
+    #[test]
+    fn positions() {
+        let conn = db::get_test_conn();
+        let entry = create_entry(&conn);
+
+        let first = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        let second = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        let third = create(entry.id, "audio/mp4", &conn)
+            .expect("failed to create attachment");
+        assert_eq!((first.position, second.position), (0, 1));
+
+        let reordered = reorder(entry.id, &[third.id, first.id], &conn)
+            .expect("failed to reorder");
+        assert!(!reordered);
+
+        reorder(entry.id, &[third.id, first.id, second.id], &conn)
+            .expect("failed to reorder");
+        delete(&third, &conn).expect("failed to delete attachment");
+
+        let loaded = load_for(&[entry], &conn)
+            .expect("failed to load attachments")
+            .remove(0)
+            .into_iter()
+            .map(|a| (a.id, a.position))
+            .collect::<Vec<_>>();
+
+        assert_eq!(loaded, vec![(first.id, 0), (second.id, 1)]);
+    }
+
+    #[test]
+    fn pending_positions() {
+        let conn = db::get_test_conn();
+        let entry = create_entry(&conn);
+
+        let first = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        let pending = create_pending(entry.id, "image/png", 10, &conn)
+            .expect("failed to create attachment");
+        let second = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+
+        reorder(entry.id, &[second.id, first.id], &conn)
+            .expect("failed to reorder");
+
+        let positions = attachments::table
+            .filter(attachments::entry_id.eq(entry.id))
+            .order(attachments::position.asc())
+            .select((attachments::id, attachments::position))
+            .load::<(i32, i32)>(&conn)
+            .expect("failed to load attachments");
+
+        assert_eq!(
+            positions,
+            vec![(second.id, 0), (first.id, 1), (pending.id, 2)]
+        );
+    }
+
+    #[test]
+    fn shared_media() {
+        let conn = db::get_test_conn();
+        let entry = create_entry(&conn);
+        let hash = "e3b0c44298fc1c149afbf4c8996fb924\
+                    27ae41e4649b934ca495991b7852b855";
+
+        let first = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        let second = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        assert_eq!(first.key(), format!("attachments/{}", first.id));
+
+        let (first, new) = link(first.id, hash, &conn).expect("failed to link");
+        assert!(new);
+        assert_eq!(first.key(), format!("media/{}", hash));
+        let (second, new) =
+            link(second.id, hash, &conn).expect("failed to link");
+        assert!(!new);
+
+        let unreferenced =
+            delete(&first, &conn).expect("failed to delete attachment");
+        assert_eq!(unreferenced, None);
+        let unreferenced =
+            delete(&second, &conn).expect("failed to delete attachment");
+        assert_eq!(unreferenced, Some(format!("media/{}", hash)));
+    }
+
+    #[test]
+    fn usage_by_journey() {
+        let conn = db::get_test_conn();
+        let entry = create_entry(&conn);
+        let before = usage(entry.user_id, &conn)
+            .expect("failed to sum up usage")
+            .get(&entry.journey_id)
+            .cloned()
+            .unwrap_or(0);
+
+        let first = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        let second = create(entry.id, "image/png", &conn)
+            .expect("failed to create attachment");
+        set_size(first.id, 100, &conn).expect("failed to set size");
+        set_size(second.id, 20, &conn).expect("failed to set size");
+
+        let after = usage(entry.user_id, &conn)
+            .expect("failed to sum up usage")
+            .get(&entry.journey_id)
+            .cloned()
+            .unwrap_or(0);
+
+        assert_eq!(after - before, 120);
+    }
+}
@@ -1,9 +1,16 @@
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel;
 use diesel::prelude::*;
+use serde::{Deserialize, Deserializer};
 
+use db::models::attachment::{self, Attachment};
 use db::models::journey::Journey;
 use db::schema::entries;
+use geo::Point;
+use geo::gazetteer::Gazetteer;
+use geo::zone::{self, DEFAULT_TIME_ZONE};
+use media::metadata::PhotoMetadata;
+use uuid::Uuid;
 
 #[derive(Queryable, Identifiable, Associations, Serialize, PartialEq, Debug)]
 #[table_name = "entries"]
@@ -12,24 +19,125 @@ pub struct Entry {
     pub id: i32,
     pub journey_id: i32,
     pub user_id: i32,
-    pub created: NaiveDateTime,
+    pub created: DateTime<Utc>,
     pub archived: bool,
     pub description: Option<String>,
-    pub coordinates: Option<String>,
     pub location: Option<String>,
+    pub captured_at: Option<NaiveDateTime>,
+    pub photo_latitude: Option<f64>,
+    pub photo_longitude: Option<f64>,
+    pub updated_at: DateTime<Utc>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub accuracy: Option<f64>,
+    pub altitude: Option<f64>,
+    pub time_zone: String,
+    pub received_at: DateTime<Utc>,
+    pub uuid: Uuid,
+    /// Changes with the entry itself, unlike `updated_at`, which also
+    /// changes with its attachments.
+    pub version: DateTime<Utc>,
 }
 
-#[derive(Insertable, Deserialize)]
-#[table_name = "entries"]
+impl Entry {
+    /// The location of the entry, if it has one.
+    pub fn point(&self) -> Option<Point> {
+        Point::from_columns(
+            self.latitude,
+            self.longitude,
+            self.accuracy,
+            self.altitude,
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct NewEntry {
     pub user_id: i32,
     pub journey_id: i32,
     pub description: Option<String>,
-    pub coordinates: Option<String>,
+    pub point: Option<Point>,
     pub location: Option<String>,
+    pub time_zone: Option<String>,
+    /// When the entry was written, if it is synced later.
+    pub created: Option<DateTime<Utc>>,
+    /// The identifier the client generated for the entry, if any.
+    pub uuid: Option<Uuid>,
 }
 
-/// Creates an entry record in the database
+impl NewEntry {
+    /// Whether the entry has a valid point and time zone, and falls within
+    /// `journey`. Entries without a creation time are created `now`, so the
+    /// journey must not have ended.
+    pub fn fits(&self, journey: &Journey, now: DateTime<Utc>) -> bool {
+        let valid_point = self.point.map_or(true, |p| p.is_valid());
+        let valid_time_zone = self
+            .time_zone
+            .as_ref()
+            .map_or(true, |tz| zone::is_valid(tz));
+        let in_journey = match self.created {
+            Some(created) => journey.accepts(created, now),
+            None => journey.end_date.is_none(),
+        };
+
+        valid_point && valid_time_zone && in_journey
+    }
+}
+
+/// Changes to an entry. The description is always replaced, the point only
+/// if given. A given location replaces the current one, and `null` clears
+/// it. Without a location, an entry that is moved is named after the place
+/// nearest to its new point, if any is known.
+#[derive(Deserialize)]
+pub struct EntryUpdate {
+    pub description: Option<String>,
+    pub point: Option<Point>,
+    #[serde(default, deserialize_with = "present")]
+    pub location: Option<Option<String>>,
+}
+
+/// Deserializes a field that is present as `Some`, even if it is `null`, so
+/// that it can be told apart from a missing field.
+fn present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Fills in what an entry leaves out: without a location, it is named after
+/// the place nearest to its point. Without a time zone, it is in the time
+/// zone at its point, or else the time zone of the user.
+pub fn fill_in(
+    entry: &mut NewEntry,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> diesel::QueryResult<()> {
+    use db::schema::users;
+
+    if entry.location.is_none() {
+        entry.location = entry.point.and_then(|p| gazetteer.locate(&p));
+    }
+
+    if entry.time_zone.is_none() {
+        let local = entry.point.and_then(|p| gazetteer.time_zone(&p));
+        entry.time_zone = match local {
+            Some(time_zone) => Some(time_zone),
+            None => Some(
+                users::table
+                    .find(entry.user_id)
+                    .select(users::time_zone)
+                    .first(conn)?,
+            ),
+        };
+    }
+
+    Ok(())
+}
+
+/// Creates an entry record in the database, in the default time zone if it
+/// has none. Entries without a capture time are created when received.
 pub fn create(
     entry: &NewEntry,
     conn: &PgConnection,
@@ -37,8 +145,27 @@ pub fn create(
     use db::schema::entries::dsl::*;
     debug!("creating entry record in db");
 
+    let point = entry.point;
+    let received = Utc::now();
+
     diesel::insert_into(entries)
-        .values(entry)
+        .values((
+            created.eq(entry.created.unwrap_or(received)),
+            received_at.eq(received),
+            user_id.eq(entry.user_id),
+            journey_id.eq(entry.journey_id),
+            description.eq(&entry.description),
+            location.eq(&entry.location),
+            latitude.eq(point.map(|p| p.latitude)),
+            longitude.eq(point.map(|p| p.longitude)),
+            accuracy.eq(point.and_then(|p| p.accuracy)),
+            altitude.eq(point.and_then(|p| p.altitude)),
+            time_zone.eq(entry
+                .time_zone
+                .as_ref()
+                .map_or(DEFAULT_TIME_ZONE, String::as_str)),
+            entry.uuid.map(|id| uuid.eq(id)),
+        ))
         .get_result::<Entry>(conn)
         .map(|entry| {
             info!("Created entry {:?}", entry);
@@ -50,6 +177,69 @@ pub fn create(
         })
 }
 
+/// Replaces the contents of an entry with `entry`, unless the entry changed
+/// since its version `base`.
+/// Returns the replaced entry, or `None` if it changed meanwhile.
+pub fn replace(
+    entry_id: i32,
+    base: DateTime<Utc>,
+    entry: &NewEntry,
+    archive: bool,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Option<Entry>> {
+    use db::schema::entries::dsl::*;
+
+    let point = entry.point;
+    let target = entries.find(entry_id).filter(version.eq(base));
+
+    diesel::update(target)
+        .set((
+            journey_id.eq(entry.journey_id),
+            entry.created.map(|time| created.eq(time)),
+            archived.eq(archive),
+            description.eq(&entry.description),
+            location.eq(&entry.location),
+            latitude.eq(point.map(|p| p.latitude)),
+            longitude.eq(point.map(|p| p.longitude)),
+            accuracy.eq(point.and_then(|p| p.accuracy)),
+            altitude.eq(point.and_then(|p| p.altitude)),
+            entry.time_zone.as_ref().map(|tz| time_zone.eq(tz)),
+        ))
+        .get_result::<Entry>(conn)
+        .optional()
+}
+
+/// Applies `changes` to an entry in a single update.
+/// Returns the updated entry.
+pub fn update(
+    entry_id: i32,
+    changes: &EntryUpdate,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Entry> {
+    use db::schema::entries::dsl::*;
+
+    let point = changes.point;
+    let name = match changes.location {
+        Some(ref name) => Some(name.clone()),
+        // an unknown place keeps the current name
+        None => point.and_then(|p| gazetteer.locate(&p)).map(Some),
+    };
+
+    conn.transaction(|| {
+        diesel::update(entries.find(entry_id))
+            .set((
+                description.eq(&changes.description),
+                name.as_ref().map(|name| location.eq(name)),
+                point.map(|p| latitude.eq(p.latitude)),
+                point.map(|p| longitude.eq(p.longitude)),
+                point.map(|p| accuracy.eq(p.accuracy)),
+                point.map(|p| altitude.eq(p.altitude)),
+            ))
+            .get_result::<Entry>(conn)
+    })
+}
+
 /// Deletes an entry from the database
 pub fn archive(entry_id: i32, conn: &PgConnection) -> diesel::QueryResult<()> {
     use db::schema::entries::dsl::*;
@@ -63,10 +253,102 @@ pub fn archive(entry_id: i32, conn: &PgConnection) -> diesel::QueryResult<()> {
     Ok(())
 }
 
+/// The storage key of the image of an entry from before attachments existed.
+pub fn legacy_image_key(entry_id: i32) -> String {
+    entry_id.to_string()
+}
+
+/// Permanently deletes an entry of a user along with its attachments.
+/// Returns the storage keys of the media of the entry that is no longer
+/// referenced, which is to be removed from storage afterwards.
+/// Fails with `NotFound` if the entry does not exist or belongs to another
+/// user.
+pub fn purge(
+    entry_id: i32,
+    user_id: i32,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Vec<String>> {
+    use db::schema::{attachments, entries};
+
+    conn.transaction(|| {
+        entries::table
+            .find(entry_id)
+            .filter(entries::user_id.eq(user_id))
+            .select(entries::id)
+            .first::<i32>(conn)?;
+
+        let target =
+            attachments::table.filter(attachments::entry_id.eq(entry_id));
+        let removed = diesel::delete(target).get_results::<Attachment>(conn)?;
+
+        let mut keys = Vec::new();
+        for attachment in &removed {
+            keys.extend(attachment::release(attachment, conn)?);
+        }
+
+        diesel::delete(entries::table.find(entry_id)).execute(conn)?;
+
+        keys.push(legacy_image_key(entry_id));
+        info!("Purged entry {}", entry_id);
+
+        Ok(keys)
+    })
+}
+
+/// Stores the metadata of the photo of an entry.
+/// If the entry has no coordinates yet, they are filled in from the photo,
+/// and an entry without a location is named after the nearest place.
+pub fn set_photo_metadata(
+    entry_id: i32,
+    metadata: &PhotoMetadata,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Entry> {
+    use db::schema::entries::dsl::*;
+
+    conn.transaction(|| {
+        if let Some(point) = metadata.point() {
+            let target = entries.find(entry_id).filter(latitude.is_null());
+            let located = diesel::update(target)
+                .set((
+                    latitude.eq(point.latitude),
+                    longitude.eq(point.longitude),
+                ))
+                .execute(conn)?;
+
+            let name = if located > 0 {
+                gazetteer.locate(&point)
+            } else {
+                None
+            };
+            if let Some(name) = name {
+                let target =
+                    entries.find(entry_id).filter(location.is_null());
+                diesel::update(target)
+                    .set(location.eq(name))
+                    .execute(conn)?;
+            }
+        }
+
+        diesel::update(entries.find(entry_id))
+            .set((
+                captured_at.eq(metadata.captured_at),
+                photo_latitude.eq(metadata.latitude),
+                photo_longitude.eq(metadata.longitude),
+            ))
+            .get_result::<Entry>(conn)
+            .map(|entry| {
+                debug!("Stored photo metadata of entry {}", entry_id);
+                entry
+            })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use db;
+    use serde_json;
 
     #[test]
     fn create_entry() {
@@ -76,8 +358,11 @@ mod tests {
         let new_entry = NewEntry {
             journey_id: 2,
             description: Some("asdf".to_string()),
-            coordinates: None,
+            point: None,
             location: Some("barcelona".to_string()),
+            time_zone: None,
+            created: None,
+            uuid: None,
         };
 
         let expected =
@@ -99,8 +384,11 @@ mod tests {
         let new_entry = NewEntry {
             journey_id: 1,
             description: None,
-            coordinates: None,
+            point: None,
             location: None,
+            time_zone: None,
+            created: None,
+            uuid: None,
         };
 
         let entry = create(&new_entry, &conn).expect("failed to create entry");
@@ -113,4 +401,50 @@ mod tests {
             Err(e) => panic!("failed to archive entry -- {:?}", e),
         }
     }
+
+    #[test]
+    fn kept_location() {
+        use geo::gazetteer::Gazetteer;
+        let conn = db::get_test_conn();
+
+        let new_entry = NewEntry {
+            user_id: 1,
+            journey_id: 1,
+            description: None,
+            point: None,
+            location: Some("Base camp".to_string()),
+            time_zone: None,
+            created: None,
+            uuid: None,
+        };
+        let entry = create(&new_entry, &conn).expect("failed to create entry");
+
+        let changes = EntryUpdate {
+            description: None,
+            point: Some(Point::new(-75.1, 123.3)),
+            location: None,
+        };
+        let unknown = Gazetteer::new(Vec::new());
+        let updated = update(entry.id, &changes, &unknown, &conn)
+            .expect("failed to update entry");
+
+        assert_eq!(updated.latitude, Some(-75.1));
+        assert_eq!(updated.location, Some("Base camp".to_string()));
+    }
+
+    #[test]
+    fn cleared_location() {
+        let parse = |json: &str| {
+            serde_json::from_str::<EntryUpdate>(json)
+                .expect("failed to parse update")
+                .location
+        };
+
+        assert_eq!(parse("{}"), None);
+        assert_eq!(parse(r#"{"location":null}"#), Some(None));
+        assert_eq!(
+            parse(r#"{"location":"Ghent"}"#),
+            Some(Some("Ghent".to_string()))
+        );
+    }
 }
@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+
+use db::schema::idempotency_keys;
+
+/// A request with an idempotency key. Until the request has been handled, it
+/// has no response.
+#[derive(Queryable, Debug, PartialEq)]
+pub struct IdempotentRequest {
+    pub user_id: i32,
+    pub key: String,
+    /// The hash of the request, to tell retries from other requests.
+    pub request_hash: Option<String>,
+    pub status: Option<i32>,
+    pub location: Option<String>,
+    pub body: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The response to a request with an idempotency key.
+#[derive(AsChangeset)]
+#[table_name = "idempotency_keys"]
+pub struct StoredResponse<'a> {
+    pub request_hash: &'a str,
+    pub status: i32,
+    pub location: Option<&'a str>,
+    pub body: &'a str,
+}
+
+/// Claims an idempotency key of a user for a request being handled, after
+/// removing the keys of all users that were created before `expired`, and
+/// those claimed before `abandoned` that have no response.
+/// Returns `None` if the key was claimed, or the earlier request with the key
+/// otherwise.
+pub fn claim(
+    user_id: i32,
+    key: &str,
+    expired: DateTime<Utc>,
+    abandoned: DateTime<Utc>,
+    conn: &PgConnection,
+) -> QueryResult<Option<IdempotentRequest>> {
+    use db::schema::idempotency_keys::dsl;
+
+    let stale = dsl::idempotency_keys.filter(
+        dsl::created_at
+            .lt(expired)
+            .or(dsl::status.is_null().and(dsl::created_at.lt(abandoned))),
+    );
+    diesel::delete(stale).execute(conn)?;
+
+    let claimed = diesel::insert_into(dsl::idempotency_keys)
+        .values((dsl::user_id.eq(user_id), dsl::key.eq(key)))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    if claimed > 0 {
+        return Ok(None);
+    }
+
+    dsl::idempotency_keys
+        .find((user_id, key))
+        .first(conn)
+        .optional()
+}
+
+/// Stores the response to the request that claimed an idempotency key.
+pub fn complete(
+    user_id: i32,
+    key: &str,
+    response: &StoredResponse,
+    conn: &PgConnection,
+) -> QueryResult<()> {
+    use db::schema::idempotency_keys::dsl;
+
+    diesel::update(dsl::idempotency_keys.find((user_id, key)))
+        .set(response)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Releases an idempotency key without a response, so that the request can
+/// be retried.
+pub fn release(
+    user_id: i32,
+    key: &str,
+    conn: &PgConnection,
+) -> QueryResult<()> {
+    use db::schema::idempotency_keys::dsl;
+
+    let target = dsl::idempotency_keys
+        .find((user_id, key))
+        .filter(dsl::status.is_null());
+    diesel::delete(target).execute(conn)?;
+
+    Ok(())
+}
@@ -1,27 +1,65 @@
-use chrono::NaiveDateTime;
+use std::env;
+
+use chrono::{DateTime, Duration, Utc};
 use db::models::user::UserInfo;
 use db::schema::journeys;
 use diesel;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use geo::Point;
+use geo::zone;
+use geo::stats::{RouteStats, Stop};
+use uuid::Uuid;
 
-#[derive(Queryable, Identifiable, Associations, Debug, Serialize,
-         Deserialize)]
+#[derive(Queryable, Identifiable, Associations, Debug, Serialize)]
 #[belongs_to(UserInfo, foreign_key = "user_id")]
 pub struct Journey {
     pub id: i32,
     pub user_id: i32,
     pub title: String,
     pub archived: bool,
-    pub start_date: NaiveDateTime,
-    pub end_date: Option<NaiveDateTime>,
+    pub start_date: DateTime<Utc>,
+    pub end_date: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+    pub uuid: Uuid,
+    /// Changes only when the journey itself does, unlike `updated_at`, which
+    /// also changes with its entries.
+    pub version: DateTime<Utc>,
+}
+
+lazy_static! {
+    /// Seconds that capture times may lie outside of their journey, to allow
+    /// for clock differences between devices, defaults to 5 minutes.
+    static ref CAPTURE_TOLERANCE: i64 = env::var("CAPTURE_TOLERANCE")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(5 * 60);
+}
+
+impl Journey {
+    /// Whether something captured at `time` falls within the journey: after
+    /// its start, and before its end or `now` if it has not ended.
+    pub fn accepts(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let tolerance = Duration::seconds(*CAPTURE_TOLERANCE);
+        let end = self.end_date.unwrap_or(now);
+
+        self.start_date - tolerance <= time && time <= end + tolerance
+    }
 }
 
-#[derive(Insertable, Deserialize)]
+#[derive(Deserialize)]
+pub struct JourneyUpdate {
+    pub id: i32,
+    pub title: String,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
 #[table_name = "journeys"]
 pub struct NewJourney {
     pub user_id: i32,
     pub title: String,
+    /// The identifier the client generated for the journey, if any.
+    pub uuid: Option<Uuid>,
 }
 
 /// inserts journey into database
@@ -34,3 +72,81 @@ pub fn create(
         .get_result::<Journey>(conn)
 }
 // TODO: transfer complexity to models from endpoints
+
+/// Computes the route statistics of a journey from its entries that are not
+/// archived, in the order they were created. Days are counted in the time
+/// zone of each entry.
+pub fn stats(journey_id: i32, conn: &PgConnection) -> QueryResult<RouteStats> {
+    use db::schema::{attachments, entries};
+    use diesel::dsl::count_star;
+
+    let stops = entries::table
+        .filter(entries::journey_id.eq(journey_id))
+        .filter(entries::archived.eq(false))
+        .order(entries::created.asc())
+        .select((
+            entries::id,
+            entries::created,
+            entries::latitude,
+            entries::longitude,
+            entries::time_zone,
+        ))
+        .load::<(i32, DateTime<Utc>, Option<f64>, Option<f64>, String)>(conn)?
+        .into_iter()
+        .map(|(entry_id, created, latitude, longitude, time_zone)| Stop {
+            entry_id,
+            time: zone::local_time(created, &time_zone),
+            point: Point::from_columns(latitude, longitude, None, None),
+        })
+        .collect::<Vec<_>>();
+
+    let photos = attachments::table
+        .inner_join(entries::table)
+        .filter(entries::journey_id.eq(journey_id))
+        .filter(entries::archived.eq(false))
+        .filter(attachments::uploaded.eq(true))
+        .filter(attachments::mime_type.like("image/%"))
+        .select(count_star())
+        .first::<i64>(conn)?;
+
+    Ok(RouteStats::compute(&stops, photos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn journey(end_date: Option<DateTime<Utc>>) -> Journey {
+        let start_date = Utc.ymd(2018, 4, 1).and_hms(8, 0, 0);
+        Journey {
+            id: 1,
+            user_id: 1,
+            title: "Trip".to_string(),
+            archived: false,
+            start_date,
+            end_date,
+            updated_at: start_date,
+            uuid: Uuid::nil(),
+            version: start_date,
+        }
+    }
+
+    #[test]
+    fn capture_window() {
+        let start = Utc.ymd(2018, 4, 1).and_hms(8, 0, 0);
+        let end = Utc.ymd(2018, 4, 3).and_hms(20, 0, 0);
+        let now = Utc.ymd(2018, 4, 10).and_hms(12, 0, 0);
+        let ended = journey(Some(end));
+
+        assert!(ended.accepts(start, now));
+        assert!(ended.accepts(start - Duration::minutes(4), now));
+        assert!(!ended.accepts(start - Duration::hours(1), now));
+        assert!(ended.accepts(end + Duration::minutes(4), now));
+        assert!(!ended.accepts(end + Duration::days(1), now));
+
+        let active = journey(None);
+        assert!(active.accepts(now, now));
+        assert!(!active.accepts(now + Duration::hours(1), now));
+    }
+}
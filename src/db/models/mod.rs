@@ -1,3 +1,7 @@
+pub mod attachment;
 pub mod entry;
+pub mod idempotency;
 pub mod journey;
+pub mod sync;
+pub mod track;
 pub mod user;
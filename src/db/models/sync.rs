@@ -0,0 +1,445 @@
+use chrono::{DateTime, Utc};
+use diesel;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+use uuid::Uuid;
+
+use db::models::attachment::Attachment;
+use db::models::entry::{self, Entry, NewEntry};
+use db::models::journey::Journey;
+use db::schema::{attachments, deletions, entries, journeys};
+use geo::Point;
+use geo::gazetteer::Gazetteer;
+
+/// A journey, entry or attachment that was deleted from the database.
+#[derive(Queryable, Debug)]
+pub struct Deletion {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    /// The table the row was deleted from.
+    pub kind: String,
+    pub row_id: i32,
+    pub uuid: Uuid,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// The journeys, entries and attachments of a user that changed.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub journeys: Vec<Journey>,
+    pub entries: Vec<Entry>,
+    pub attachments: Vec<Attachment>,
+    pub deletions: Vec<Deletion>,
+    /// Set if there were too many changes to load at once, in which case
+    /// the changes after this time were left out.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Changes {
+    /// The time of the latest change, if anything changed.
+    pub fn latest(&self) -> Option<DateTime<Utc>> {
+        let journeys = self.journeys.iter().map(|j| j.updated_at);
+        let entries = self.entries.iter().map(|e| e.updated_at);
+        let attachments = self.attachments.iter().map(|a| a.updated_at);
+        let deletions = self.deletions.iter().map(|d| d.deleted_at);
+
+        journeys
+            .chain(entries)
+            .chain(attachments)
+            .chain(deletions)
+            .max()
+    }
+}
+
+/// The journeys of a user that changed after `since`, or that are not
+/// archived, in the order they changed.
+fn changed_journeys(
+    user_id: i32,
+    since: Option<DateTime<Utc>>,
+) -> journeys::BoxedQuery<'static, Pg> {
+    let target = journeys::table
+        .filter(journeys::user_id.eq(user_id))
+        .order(journeys::updated_at.asc())
+        .into_boxed();
+
+    match since {
+        Some(since) => target.filter(journeys::updated_at.gt(since)),
+        None => target.filter(journeys::archived.eq(false)),
+    }
+}
+
+/// The entries of a user that changed after `since`, or that are not
+/// archived, in the order they changed.
+fn changed_entries(
+    user_id: i32,
+    since: Option<DateTime<Utc>>,
+) -> entries::BoxedQuery<'static, Pg> {
+    let target = entries::table
+        .filter(entries::user_id.eq(user_id))
+        .order(entries::updated_at.asc())
+        .into_boxed();
+
+    match since {
+        Some(since) => target.filter(entries::updated_at.gt(since)),
+        None => target.filter(entries::archived.eq(false)),
+    }
+}
+
+/// The uploaded attachments of a user that changed after `since`, or whose
+/// entries are not archived, in the order they changed.
+fn changed_attachments(
+    user_id: i32,
+    since: Option<DateTime<Utc>>,
+) -> attachments::BoxedQuery<'static, Pg> {
+    let target = attachments::table
+        .filter(attachments::user_id.eq(user_id))
+        .filter(attachments::uploaded.eq(true))
+        .order(attachments::updated_at.asc())
+        .into_boxed();
+
+    match since {
+        Some(since) => target.filter(attachments::updated_at.gt(since)),
+        None => {
+            let active_entries = entries::table
+                .filter(entries::archived.eq(false))
+                .select(entries::id);
+            target.filter(attachments::entry_id.eq_any(active_entries))
+        }
+    }
+}
+
+/// The rows of a user that were deleted after `since`, in the order they
+/// were deleted.
+fn changed_deletions(
+    user_id: i32,
+    since: DateTime<Utc>,
+) -> deletions::BoxedQuery<'static, Pg> {
+    deletions::table
+        .filter(deletions::user_id.eq(user_id))
+        .filter(deletions::deleted_at.gt(since))
+        .order(deletions::deleted_at.asc())
+        .into_boxed()
+}
+
+/// Loads the journeys, entries and uploaded attachments of a user that were
+/// created, updated or archived after `since`, along with those that were
+/// deleted. Without `since`, loads everything that is not archived.
+/// If more than `limit` rows of a kind changed, only the changes up to the
+/// time of its `limit`th change are loaded, including any other changes
+/// made at that time. The time is returned as `Changes::until`.
+pub fn changes(
+    user_id: i32,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+    conn: &PgConnection,
+) -> QueryResult<Changes> {
+    let mut bounds = vec![
+        changed_journeys(user_id, since)
+            .offset(limit - 1)
+            .first::<Journey>(conn)
+            .optional()?
+            .map(|journey| journey.updated_at),
+        changed_entries(user_id, since)
+            .offset(limit - 1)
+            .first::<Entry>(conn)
+            .optional()?
+            .map(|entry| entry.updated_at),
+        changed_attachments(user_id, since)
+            .offset(limit - 1)
+            .first::<Attachment>(conn)
+            .optional()?
+            .map(|attachment| attachment.updated_at),
+    ];
+    if let Some(since) = since {
+        bounds.push(
+            changed_deletions(user_id, since)
+                .offset(limit - 1)
+                .first::<Deletion>(conn)
+                .optional()?
+                .map(|deletion| deletion.deleted_at),
+        );
+    }
+    let until = bounds.into_iter().filter_map(|bound| bound).min();
+
+    let mut journeys = changed_journeys(user_id, since);
+    let mut entries = changed_entries(user_id, since);
+    let mut attachments = changed_attachments(user_id, since);
+    if let Some(until) = until {
+        journeys = journeys.filter(journeys::updated_at.le(until));
+        entries = entries.filter(entries::updated_at.le(until));
+        attachments = attachments.filter(attachments::updated_at.le(until));
+    }
+
+    let deletions = match since {
+        Some(since) => {
+            let mut target = changed_deletions(user_id, since);
+            if let Some(until) = until {
+                target = target.filter(deletions::deleted_at.le(until));
+            }
+            target.load::<Deletion>(conn)?
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Changes {
+        journeys: journeys.load(conn)?,
+        entries: entries.load(conn)?,
+        attachments: attachments.load(conn)?,
+        deletions,
+        until,
+    })
+}
+
+/// A journey as it was changed on a client.
+#[derive(Deserialize, Debug)]
+pub struct JourneyChange {
+    pub uuid: Uuid,
+    /// The version of the journey that was changed, or none if the journey
+    /// was created on the client.
+    pub base_version: Option<DateTime<Utc>>,
+    pub title: String,
+    #[serde(default)]
+    pub archived: bool,
+    pub start_date: Option<DateTime<Utc>>,
+    pub end_date: Option<DateTime<Utc>>,
+}
+
+/// An entry as it was changed on a client.
+#[derive(Deserialize, Debug)]
+pub struct EntryChange {
+    pub uuid: Uuid,
+    /// The version of the entry that was changed, or none if the entry was
+    /// created on the client.
+    pub base_version: Option<DateTime<Utc>>,
+    /// The UUID of the journey of the entry.
+    pub journey: Uuid,
+    pub description: Option<String>,
+    pub point: Option<Point>,
+    pub location: Option<String>,
+    pub time_zone: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+/// Why a change made on a client was not applied.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Conflict {
+    /// The row changed since the version the change was based on, or a new
+    /// row has a UUID that is taken.
+    Modified,
+    /// The row was deleted.
+    Deleted,
+    /// The row belongs to another user.
+    Forbidden,
+    /// The journey of an entry does not exist.
+    UnknownJourney,
+    /// The change is not valid, such as an entry outside of its journey.
+    Invalid,
+}
+
+/// A change that was applied, with the ID and new version of its row.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Applied {
+    pub uuid: Uuid,
+    pub id: i32,
+    pub version: DateTime<Utc>,
+}
+
+impl From<Journey> for Applied {
+    fn from(journey: Journey) -> Self {
+        Applied {
+            uuid: journey.uuid,
+            id: journey.id,
+            version: journey.version,
+        }
+    }
+}
+
+impl From<Entry> for Applied {
+    fn from(entry: Entry) -> Self {
+        Applied {
+            uuid: entry.uuid,
+            id: entry.id,
+            version: entry.version,
+        }
+    }
+}
+
+/// Treats a row of which the UUID was taken meanwhile as modified.
+fn inserted<T>(result: QueryResult<T>) -> QueryResult<Result<T, Conflict>> {
+    match result {
+        Ok(row) => Ok(Ok(row)),
+        Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Ok(Err(Conflict::Modified))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies a change to a journey of a user, creating the journey if it is
+/// new. Changes to journeys that changed since are not applied, while
+/// changes to their entries do not count.
+pub fn apply_journey(
+    user_id: i32,
+    change: &JourneyChange,
+    conn: &PgConnection,
+) -> QueryResult<Result<Applied, Conflict>> {
+    let existing = journeys::table
+        .filter(journeys::uuid.eq(change.uuid))
+        .first::<Journey>(conn)
+        .optional()?;
+
+    let ends_before_start = match (change.start_date, change.end_date) {
+        (Some(start), Some(end)) => end < start,
+        _ => false,
+    };
+    if ends_before_start {
+        return Ok(Err(Conflict::Invalid));
+    }
+
+    let journey = match (existing, change.base_version) {
+        (Some(ref journey), _) if journey.user_id != user_id => {
+            return Ok(Err(Conflict::Forbidden))
+        }
+        (Some(ref journey), Some(base)) if journey.version == base => {
+            let target = journeys::table
+                .find(journey.id)
+                .filter(journeys::version.eq(base));
+            diesel::update(target)
+                .set((
+                    journeys::title.eq(&change.title),
+                    journeys::archived.eq(change.archived),
+                    change.start_date.map(|date| journeys::start_date.eq(date)),
+                    journeys::end_date.eq(change.end_date),
+                ))
+                .get_result::<Journey>(conn)
+                .optional()?
+                .ok_or(Conflict::Modified)
+        }
+        (Some(_), _) => Err(Conflict::Modified),
+        (None, Some(_)) => Err(Conflict::Deleted),
+        (None, None) => inserted(
+            diesel::insert_into(journeys::table)
+                .values((
+                    journeys::user_id.eq(user_id),
+                    journeys::uuid.eq(change.uuid),
+                    journeys::title.eq(&change.title),
+                    journeys::archived.eq(change.archived),
+                    change.start_date.map(|date| journeys::start_date.eq(date)),
+                    journeys::end_date.eq(change.end_date),
+                ))
+                .get_result::<Journey>(conn),
+        )?,
+    };
+
+    Ok(journey.map(Applied::from))
+}
+
+/// Applies a change to an entry of a user, creating the entry if it is new.
+/// Like entries created directly, new entries are located using `gazetteer`.
+/// Changes to entries that changed since are not applied.
+pub fn apply_entry(
+    user_id: i32,
+    change: &EntryChange,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> QueryResult<Result<Applied, Conflict>> {
+    let journey = journeys::table
+        .filter(journeys::uuid.eq(change.journey))
+        .first::<Journey>(conn)
+        .optional()?;
+    let journey = match journey {
+        Some(ref journey) if journey.user_id != user_id => {
+            return Ok(Err(Conflict::Forbidden))
+        }
+        Some(journey) => journey,
+        None => return Ok(Err(Conflict::UnknownJourney)),
+    };
+
+    let existing = entries::table
+        .filter(entries::uuid.eq(change.uuid))
+        .first::<Entry>(conn)
+        .optional()?;
+    let conflict = match (&existing, change.base_version) {
+        (&Some(ref entry), _) if entry.user_id != user_id => {
+            Some(Conflict::Forbidden)
+        }
+        (&Some(ref entry), base) if Some(entry.version) != base => {
+            Some(Conflict::Modified)
+        }
+        (&None, Some(_)) => Some(Conflict::Deleted),
+        _ => None,
+    };
+    if let Some(conflict) = conflict {
+        return Ok(Err(conflict));
+    }
+
+    let mut new_entry = NewEntry {
+        user_id,
+        journey_id: journey.id,
+        description: change.description.clone(),
+        point: change.point,
+        location: change.location.clone(),
+        time_zone: change.time_zone.clone(),
+        created: change
+            .created
+            .or_else(|| existing.as_ref().map(|entry| entry.created)),
+        uuid: Some(change.uuid),
+    };
+    if !new_entry.fits(&journey, Utc::now()) {
+        return Ok(Err(Conflict::Invalid));
+    }
+    entry::fill_in(&mut new_entry, gazetteer, conn)?;
+
+    // entries are created unarchived, and archived afterwards if need be
+    let (entry_id, version, archive) = match existing {
+        Some(existing) => (existing.id, existing.version, change.archived),
+        None => match inserted(entry::create(&new_entry, conn))? {
+            Ok(ref created) if change.archived => {
+                (created.id, created.version, true)
+            }
+            Ok(created) => return Ok(Ok(Applied::from(created))),
+            Err(conflict) => return Ok(Err(conflict)),
+        },
+    };
+
+    let replaced =
+        entry::replace(entry_id, version, &new_entry, archive, conn)?;
+    Ok(replaced.map(Applied::from).ok_or(Conflict::Modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn latest_change() {
+        let time = Utc.ymd(2018, 5, 1).and_hms(12, 0, 0);
+        let mut changes = Changes::default();
+        assert_eq!(changes.latest(), None);
+
+        changes.deletions.push(Deletion {
+            id: 1,
+            user_id: Some(1),
+            kind: "entries".to_string(),
+            row_id: 1,
+            uuid: Uuid::nil(),
+            deleted_at: time,
+        });
+        assert_eq!(changes.latest(), Some(time));
+
+        changes.deletions.push(Deletion {
+            id: 2,
+            user_id: Some(1),
+            kind: "attachments".to_string(),
+            row_id: 3,
+            uuid: Uuid::nil(),
+            deleted_at: time - Duration::hours(1),
+        });
+        assert_eq!(changes.latest(), Some(time));
+    }
+}
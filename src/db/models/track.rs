@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+
+use db::models::entry::Entry;
+use db::schema::{entries, track_points, users};
+use geo::gazetteer::Gazetteer;
+use geo::gpx::Gpx;
+
+/// Track points inserted per statement, well below the bind parameter limit.
+const INSERT_CHUNK: usize = 1000;
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct TrackPoint {
+    pub id: i32,
+    pub journey_id: i32,
+    pub recorded_at: Option<DateTime<Utc>>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+#[derive(Insertable)]
+#[table_name = "track_points"]
+struct NewTrackPoint {
+    journey_id: i32,
+    recorded_at: Option<DateTime<Utc>>,
+    latitude: f64,
+    longitude: f64,
+    altitude: Option<f64>,
+}
+
+/// Loads the track recorded along a journey, in the order it was recorded.
+pub fn load(
+    journey_id: i32,
+    conn: &PgConnection,
+) -> QueryResult<Vec<TrackPoint>> {
+    track_points::table
+        .filter(track_points::journey_id.eq(journey_id))
+        .order((track_points::recorded_at.asc(), track_points::id.asc()))
+        .load(conn)
+}
+
+/// Imports a GPX file into a journey: creates an entry for every waypoint,
+/// created at `default_time` if the waypoint has no time, and adds the track
+/// points to the track of the journey. Entries are located at the nearest
+/// place in `gazetteer`, and in its time zone or else the time zone of the
+/// user. Either everything is imported or nothing is.
+/// Returns the created entries and the number of imported track points.
+pub fn import(
+    journey_id: i32,
+    user_id: i32,
+    gpx: &Gpx,
+    default_time: DateTime<Utc>,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> QueryResult<(Vec<Entry>, usize)> {
+    conn.transaction(|| {
+        let user_time_zone = users::table
+            .find(user_id)
+            .select(users::time_zone)
+            .first::<String>(conn)?;
+
+        let mut created = Vec::with_capacity(gpx.waypoints.len());
+        for waypoint in &gpx.waypoints {
+            let point = waypoint.point;
+            let created_at = waypoint.time.unwrap_or(default_time);
+            let description = waypoint
+                .name
+                .as_ref()
+                .or_else(|| waypoint.description.as_ref())
+                .map(String::as_str);
+            let time_zone = gazetteer
+                .time_zone(&point)
+                .unwrap_or_else(|| user_time_zone.clone());
+
+            let entry = diesel::insert_into(entries::table)
+                .values((
+                    entries::user_id.eq(user_id),
+                    entries::journey_id.eq(journey_id),
+                    entries::created.eq(created_at),
+                    entries::description.eq(description),
+                    entries::location.eq(gazetteer.locate(&point)),
+                    entries::latitude.eq(point.latitude),
+                    entries::longitude.eq(point.longitude),
+                    entries::altitude.eq(point.altitude),
+                    entries::time_zone.eq(time_zone),
+                ))
+                .get_result::<Entry>(conn)?;
+            created.push(entry);
+        }
+
+        let track = gpx.track
+            .iter()
+            .map(|track_point| NewTrackPoint {
+                journey_id,
+                recorded_at: track_point.time,
+                latitude: track_point.point.latitude,
+                longitude: track_point.point.longitude,
+                altitude: track_point.point.altitude,
+            })
+            .collect::<Vec<_>>();
+
+        let mut imported = 0;
+        for chunk in track.chunks(INSERT_CHUNK) {
+            imported += diesel::insert_into(track_points::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+
+        info!(
+            "Imported {} entries and {} track points into journey {}",
+            created.len(),
+            imported,
+            journey_id
+        );
+
+        Ok((created, imported))
+    })
+}
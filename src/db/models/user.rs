@@ -1,5 +1,5 @@
 use bcrypt;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use diesel;
 use diesel::prelude::*;
 use jwt::{decode, Validation};
@@ -14,6 +14,7 @@ use rocket::request::{self, FromRequest};
 use rocket_contrib::Json;
 
 use db::schema::users;
+use geo::zone;
 
 #[derive(Queryable, Debug)]
 pub struct User {
@@ -21,7 +22,8 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password: String,
-    pub date: NaiveDateTime,
+    pub date: DateTime<Utc>,
+    pub time_zone: String,
 }
 
 #[derive(Insertable, AsChangeset, Deserialize)]
@@ -30,6 +32,8 @@ pub struct NewUser {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// The IANA time zone of the user, used for entries without a location.
+    pub time_zone: Option<String>,
 }
 
 type UpdateUser = NewUser;
@@ -85,33 +89,58 @@ pub fn update(
         })
 }
 
-/// Deletes a user, and its owned journeys and entries
-pub fn delete(user: UserInfo, conn: &PgConnection) -> diesel::QueryResult<()> {
-    use db::models::entry::Entry;
+/// Deletes a user, and its owned journeys, entries and attachments, all at
+/// once.
+/// Returns the storage keys of their media that is no longer referenced,
+/// which is to be removed from storage afterwards.
+pub fn delete(
+    user: UserInfo,
+    conn: &PgConnection,
+) -> diesel::QueryResult<Vec<String>> {
+    use db::models::attachment::{self, Attachment};
+    use db::models::entry::{self, Entry};
     use db::models::journey::Journey;
+    use db::schema::{attachments, entries};
     use db::schema::journeys::dsl::*;
     use db::schema::users::dsl::*;
 
-    let mut del_journeys = 0;
-    let mut del_entries = 0;
+    conn.transaction(|| {
+        let mut del_journeys = 0;
+        let mut del_entries = 0;
+        let mut del_attachments = 0;
+        let mut keys = Vec::new();
+
+        for journey in Journey::belonging_to(&user).load::<Journey>(&*conn)? {
+            let entry_ids = Entry::belonging_to(&journey).select(entries::id);
+            let attachments = diesel::delete(
+                attachments::table
+                    .filter(attachments::entry_id.eq_any(entry_ids)),
+            ).get_results::<Attachment>(&*conn)?;
+            del_attachments += attachments.len();
+            for attachment in &attachments {
+                keys.extend(attachment::release(attachment, conn)?);
+            }
 
-    for journey in Journey::belonging_to(&user).load::<Journey>(&*conn)? {
-        del_entries +=
-            diesel::delete(Entry::belonging_to(&journey)).execute(&*conn)?;
+            let entry_ids = diesel::delete(Entry::belonging_to(&journey))
+                .returning(entries::id)
+                .get_results::<i32>(&*conn)?;
+            del_entries += entry_ids.len();
+            keys.extend(entry_ids.into_iter().map(entry::legacy_image_key));
 
-        del_journeys +=
-            diesel::delete(journeys.find(journey.id)).execute(&*conn)?;
-    }
+            del_journeys +=
+                diesel::delete(journeys.find(journey.id)).execute(&*conn)?;
+        }
 
-    let target = users.find(user.id);
-    let del_users = diesel::delete(target).execute(&*conn)?;
+        let target = users.find(user.id);
+        let del_users = diesel::delete(target).execute(&*conn)?;
 
-    debug!(
-        "Deleted {} users, {} journeys, and {} entries",
-        del_users, del_journeys, del_entries
-    );
+        debug!(
+            "Deleted {} users, {} journeys, {} entries, and {} attachments",
+            del_users, del_journeys, del_entries, del_attachments
+        );
 
-    Ok(())
+        Ok(keys)
+    })
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for UserInfo {
@@ -166,7 +195,7 @@ impl FromData for NewUser {
     type Error = ();
 
     /// Request guard for user creation. Will return errors if either the json
-    /// is invalid or if the password failed to hash.
+    /// or the time zone is invalid, or if the password failed to hash.
     fn from_data(
         request: &Request,
         data: Data,
@@ -176,6 +205,12 @@ impl FromData for NewUser {
             .into_outcome(Status::BadRequest)?;
 
         let user = json.into_inner();
+        let valid_time_zone =
+            user.time_zone.as_ref().map_or(true, |tz| zone::is_valid(tz));
+        if !valid_time_zone {
+            return Outcome::Failure((Status::BadRequest, ()));
+        }
+
         hash_password(user)
             .map_err(|_| ())
             .into_outcome(Status::InternalServerError)
@@ -215,6 +250,7 @@ mod tests {
             username: "foo".to_string(),
             email: "foo@bar.com".to_string(),
             password: "asdf".to_string(),
+            time_zone: None,
         };
 
         let expected = create(&new_user, &conn).expect("failed to create user");
@@ -238,6 +274,7 @@ mod tests {
             username: "foo".to_string(),
             email: "foo@bar.com".to_string(),
             password: "asdf".to_string(),
+            time_zone: None,
         };
 
         let user = create(&new_user, &conn).expect("failed to create user");
@@ -266,6 +303,7 @@ mod tests {
             username: "foo".to_string(),
             email: "foo@bar.com".to_string(),
             password: "asdf".to_string(),
+            time_zone: None,
         };
 
         let user = create(&new_user, &conn).expect("failed to create user");
@@ -1,13 +1,63 @@
+table! {
+    attachments (id) {
+        id -> Int4,
+        entry_id -> Int4,
+        position -> Int4,
+        caption -> Nullable<Varchar>,
+        mime_type -> Varchar,
+        size -> Int8,
+        uploaded -> Bool,
+        sha256 -> Nullable<Varchar>,
+        updated_at -> Timestamptz,
+        uuid -> Uuid,
+        user_id -> Int4,
+    }
+}
+
+table! {
+    deletions (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        kind -> Varchar,
+        row_id -> Int4,
+        uuid -> Uuid,
+        deleted_at -> Timestamptz,
+    }
+}
+
 table! {
     entries (id) {
         id -> Int4,
         journey_id -> Int4,
         user_id -> Int4,
-        created -> Timestamp,
+        created -> Timestamptz,
         archived -> Bool,
         description -> Nullable<Varchar>,
-        coordinates -> Nullable<Varchar>,
         location -> Nullable<Varchar>,
+        captured_at -> Nullable<Timestamp>,
+        photo_latitude -> Nullable<Float8>,
+        photo_longitude -> Nullable<Float8>,
+        updated_at -> Timestamptz,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        accuracy -> Nullable<Float8>,
+        altitude -> Nullable<Float8>,
+        time_zone -> Varchar,
+        received_at -> Timestamptz,
+        uuid -> Uuid,
+        version -> Timestamptz,
+    }
+}
+
+table! {
+    idempotency_keys (user_id, key) {
+        user_id -> Int4,
+        key -> Varchar,
+        request_hash -> Nullable<Varchar>,
+        status -> Nullable<Int4>,
+        location -> Nullable<Varchar>,
+        body -> Nullable<Text>,
+        created_at -> Timestamptz,
     }
 }
 
@@ -17,8 +67,29 @@ table! {
         user_id -> Int4,
         title -> Varchar,
         archived -> Bool,
-        start_date -> Timestamp,
-        end_date -> Nullable<Timestamp>,
+        start_date -> Timestamptz,
+        end_date -> Nullable<Timestamptz>,
+        updated_at -> Timestamptz,
+        uuid -> Uuid,
+        version -> Timestamptz,
+    }
+}
+
+table! {
+    media_blobs (sha256) {
+        sha256 -> Varchar,
+        refcount -> Int4,
+    }
+}
+
+table! {
+    track_points (id) {
+        id -> Int4,
+        journey_id -> Int4,
+        recorded_at -> Nullable<Timestamptz>,
+        latitude -> Float8,
+        longitude -> Float8,
+        altitude -> Nullable<Float8>,
     }
 }
 
@@ -28,12 +99,27 @@ table! {
         username -> Varchar,
         email -> Varchar,
         password -> Varchar,
-        date -> Timestamp,
+        date -> Timestamptz,
+        time_zone -> Varchar,
     }
 }
 
+joinable!(attachments -> entries (entry_id));
+joinable!(attachments -> users (user_id));
+joinable!(deletions -> users (user_id));
 joinable!(entries -> journeys (journey_id));
 joinable!(entries -> users (user_id));
+joinable!(idempotency_keys -> users (user_id));
 joinable!(journeys -> users (user_id));
+joinable!(track_points -> journeys (journey_id));
 
-allow_tables_to_appear_in_same_query!(entries, journeys, users,);
+allow_tables_to_appear_in_same_query!(
+    attachments,
+    deletions,
+    entries,
+    idempotency_keys,
+    journeys,
+    media_blobs,
+    track_points,
+    users,
+);
@@ -0,0 +1,808 @@
+use std::cmp;
+use std::env;
+use std::io::{self, Read};
+
+use diesel;
+use diesel::prelude::*;
+
+use rocket::http::Status;
+use rocket::response::{self, status, Responder};
+use rocket::{Data, Request, State};
+use rocket_contrib::Json;
+
+use super::caching::{self, CachePolicy, Cached, Conditional};
+use super::media::{Media, RangeHeader, MEDIA_REDIRECTS, PRESIGNED_URL_TTL};
+use super::{log_db_err, log_err, log_storage_err, ErrStatus};
+use db::DbConn;
+use db::models::attachment::{self, Attachment};
+use db::models::entry::{self, Entry};
+use db::models::user::UserInfo;
+use geo::gazetteer::Gazetteer;
+use media::{self, lifecycle, MediaKind, MediaType, SNIFF_LEN};
+use media::metadata::{Capture, PhotoMetadata, CAPTURE_LEN};
+use media::variants::{ImageSize, VariantGenerator};
+use storage::{Blob, Hashing, SizeLimited, Storage, StorageError};
+
+/// Reads a size limit in bytes from the environment.
+fn size_limit(var: &str, default: u64) -> u64 {
+    env::var(var)
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    /// Maximum size of uploaded images in bytes, defaults to 10 MiB.
+    static ref MAX_UPLOAD_SIZE: u64 =
+        size_limit("MAX_UPLOAD_SIZE", 10 * 1024 * 1024);
+    /// Maximum size of uploaded audio and video in bytes, defaults to 100 MiB.
+    static ref MAX_MEDIA_UPLOAD_SIZE: u64 =
+        size_limit("MAX_MEDIA_UPLOAD_SIZE", 100 * 1024 * 1024);
+    /// Bytes of media each user may store, defaults to 1 GiB.
+    pub static ref STORAGE_QUOTA: u64 =
+        size_limit("STORAGE_QUOTA", 1024 * 1024 * 1024);
+}
+
+/// The maximum upload size for a media type.
+fn max_size(media_type: MediaType) -> u64 {
+    match media_type.kind() {
+        MediaKind::Image => *MAX_UPLOAD_SIZE,
+        MediaKind::Audio | MediaKind::Video => *MAX_MEDIA_UPLOAD_SIZE,
+    }
+}
+
+/// The number of bytes a user may still store.
+fn remaining_quota(owner: i32, conn: &PgConnection) -> QueryResult<u64> {
+    let used = attachment::usage(owner, conn)?.values().sum::<i64>();
+
+    Ok(STORAGE_QUOTA.saturating_sub(used as u64))
+}
+
+/// Creates a pending attachment of an entry that reserves storage of the
+/// owner of the entry: `size` bytes if given, and otherwise as much as the
+/// owner may still store, up to the maximum upload size. The owner is locked
+/// meanwhile, so that concurrent uploads cannot reserve the same storage.
+/// If the owner has not enough storage left, fails with a `PayloadTooLarge`
+/// status and the remaining quota.
+fn reserve(
+    entry_id: i32,
+    media_type: MediaType,
+    size: Option<u64>,
+    conn: &PgConnection,
+) -> Result<Attachment, UploadError> {
+    let reserved = conn.transaction(|| {
+        let owner = attachment::lock_owner(entry_id, conn)?;
+        let remaining = remaining_quota(owner, conn)?;
+        let bytes = match size {
+            Some(size) if size <= remaining => size,
+            None if remaining > 0 => remaining.min(max_size(media_type)),
+            _ => return Ok(Err(remaining)),
+        };
+
+        attachment::create_pending(
+            entry_id,
+            media_type.mime(),
+            bytes as i64,
+            conn,
+        ).map(Ok)
+    }).map_err(log_db_err)?;
+
+    reserved.map_err(|remaining| quota_exceeded(entry_id, remaining))
+}
+
+/// The storage quota of a user, and the bytes of it that are left.
+#[derive(Serialize, Debug)]
+pub struct Quota {
+    quota: u64,
+    remaining: u64,
+}
+
+/// Why an upload failed. Uploads exceeding the storage quota are answered
+/// with the remaining quota.
+#[derive(Debug)]
+pub enum UploadError {
+    Status(ErrStatus),
+    QuotaExceeded(Quota),
+}
+
+impl From<ErrStatus> for UploadError {
+    fn from(status: ErrStatus) -> Self {
+        UploadError::Status(status)
+    }
+}
+
+impl<'r> Responder<'r> for UploadError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            UploadError::Status(status) => status.respond_to(request),
+            UploadError::QuotaExceeded(quota) => {
+                status::Custom(Status::PayloadTooLarge, Json(quota))
+                    .respond_to(request)
+            }
+        }
+    }
+}
+
+fn quota_exceeded(entry_id: i32, remaining: u64) -> UploadError {
+    debug!("Rejected upload exceeding the quota of entry {}", entry_id);
+    UploadError::QuotaExceeded(Quota {
+        quota: *STORAGE_QUOTA,
+        remaining,
+    })
+}
+
+/// Stores the EXIF metadata of an uploaded image with its entry, and
+/// schedules generation of its variants if the image is new.
+fn process_image(
+    attachment: &Attachment,
+    metadata: &PhotoMetadata,
+    is_new: bool,
+    variants: &VariantGenerator,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> Result<(), ErrStatus> {
+    if *metadata != PhotoMetadata::default() {
+        let entry_id = attachment.entry_id;
+        entry::set_photo_metadata(entry_id, metadata, gazetteer, conn)
+            .map_err(log_db_err)?;
+    }
+
+    if is_new {
+        variants.schedule(attachment.key());
+    }
+    Ok(())
+}
+
+/// Moves uploaded media from the upload key of its attachment, which is
+/// linked to its SHA-256 hash already, to a key derived from the hash, so
+/// identical media is stored only once.
+/// If the media is stored already, as `is_new` tells, the upload is removed
+/// instead.
+/// Returns the attachment, and whether its media is new. If this fails, the
+/// media stays under its upload key.
+fn deduplicate(
+    attachment: Attachment,
+    is_new: bool,
+    storage: &Storage,
+    conn: &PgConnection,
+) -> (Attachment, bool) {
+    let upload_key = attachment.upload_key();
+
+    if !is_new {
+        debug!("Attachment {} duplicates stored media", attachment.id);
+        if let Err(e) = storage.delete(&upload_key) {
+            // the sweeper removes it later
+            error!("Failed to delete duplicate upload -- {:?}", e);
+        }
+        return (attachment, false);
+    }
+
+    // moved after linking, so that no transaction is held while the media
+    // is copied in storage
+    match storage.rename(&upload_key, &attachment.key()) {
+        Ok(()) => (attachment, true),
+        Err(e) => {
+            error!("Failed to move upload -- {:?}", e);
+            match attachment::unlink(&attachment, conn) {
+                Ok(unlinked) => (unlinked, true),
+                Err(e) => {
+                    error!("Failed to unlink upload -- {:?}", e);
+                    (attachment, true)
+                }
+            }
+        }
+    }
+}
+
+/// Streams uploaded media to storage as a new attachment of an entry.
+/// The attachment stays hidden until its media is stored.
+/// The media type is detected from its content, and stored along with it.
+/// Media that is stored already is not stored again.
+/// For images, scaled down variants are generated in the background, and the
+/// EXIF capture time and location are stored with the entry. If the entry has
+/// no coordinates yet, the location of the image is used.
+/// Returns the attachment and the metadata of the image, along with the
+/// SHA-256 hash of the media.
+/// If the media exceeds the storage quota of the owner of the entry, fails
+/// with a `PayloadTooLarge` status and the remaining quota.
+pub(super) fn store_upload(
+    entry_id: i32,
+    data: Data,
+    storage: &Storage,
+    variants: &VariantGenerator,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
+) -> Result<(Attachment, PhotoMetadata, String), UploadError> {
+    let (media_type, data) = media::sniff(data.open()).map_err(log_err)?;
+    let media_type = match media_type {
+        Some(media_type) => media_type,
+        None => {
+            debug!("Rejected upload of unsupported media type");
+            let unsupported = status::Custom(Status::UnsupportedMediaType, ());
+            return Err(unsupported.into());
+        }
+    };
+
+    let attachment = reserve(entry_id, media_type, None, conn)?;
+    let reserved = attachment.size as u64;
+    let limited_by_quota = reserved < max_size(media_type);
+    let data = SizeLimited::new(data, reserved);
+    let key = attachment.upload_key();
+
+    let mut data = Hashing::new(Capture::new(data));
+    let size = match storage.put(&key, Some(media_type.mime()), &mut data) {
+        Ok(size) => size,
+        Err(e) => {
+            attachment::delete(&attachment, conn).map_err(log_db_err)?;
+            return Err(match e {
+                StorageError::TooLarge if limited_by_quota => {
+                    quota_exceeded(entry_id, reserved)
+                }
+                e => log_storage_err(e).into(),
+            });
+        }
+    };
+
+    let hash = data.hash();
+    let completed =
+        attachment::complete(attachment.id, size as i64, &hash, conn);
+    let (attachment, is_new) = match completed {
+        Ok(completed) => completed,
+        Err(e) => {
+            attachment::delete(&attachment, conn).map_err(log_db_err)?;
+            lifecycle::delete(storage, &key).map_err(log_storage_err)?;
+            return Err(log_db_err(e).into());
+        }
+    };
+    let (attachment, is_new) = deduplicate(attachment, is_new, storage, conn);
+
+    if media_type.kind() != MediaKind::Image {
+        return Ok((attachment, PhotoMetadata::default(), hash));
+    }
+
+    let metadata = data.get_ref().metadata();
+    process_image(&attachment, &metadata, is_new, variants, gazetteer, conn)?;
+
+    Ok((attachment, metadata, hash))
+}
+
+/// Hashes uploaded media like `store_upload` does, without storing it, to
+/// tell a retry of the upload that created the attachment at `location`
+/// from another upload. At most one byte more than the earlier upload is
+/// read, as longer media differs from it anyway.
+pub(super) fn upload_hash(
+    data: Data,
+    location: Option<&str>,
+    conn: &PgConnection,
+) -> Result<String, ErrStatus> {
+    use db::schema::attachments;
+
+    let attachment_id = location
+        .and_then(|location| location.rsplit('/').next())
+        .and_then(|id| id.parse::<i32>().ok());
+    let size = match attachment_id {
+        Some(id) => attachments::table
+            .find(id)
+            .select(attachments::size)
+            .first::<i64>(conn)
+            .optional()
+            .map_err(log_db_err)?,
+        None => None,
+    };
+    // without the earlier attachment, the upload may be as large as any
+    let limit = size.map_or_else(
+        || cmp::max(*MAX_UPLOAD_SIZE, *MAX_MEDIA_UPLOAD_SIZE),
+        |size| size as u64,
+    );
+
+    let mut data = Hashing::new(data.open().take(limit + 1));
+    io::copy(&mut data, &mut io::sink()).map_err(log_err)?;
+
+    Ok(data.hash())
+}
+
+/// Redirects to a presigned storage URL of an object, if media redirects are
+/// enabled and the storage backend supports them.
+fn redirect(key: &str, storage: &Storage) -> Result<Option<Media>, ErrStatus> {
+    if !*MEDIA_REDIRECTS {
+        return Ok(None);
+    }
+
+    let url = storage
+        .presign_get(key, *PRESIGNED_URL_TTL)
+        .map_err(log_storage_err)?;
+
+    Ok(url.map(Media::Redirect))
+}
+
+/// Wraps media that must not be cached, such as redirects to presigned URLs,
+/// which expire.
+fn uncached(media: Media) -> Cached<Media> {
+    Conditional::default().respond(None, None, CachePolicy::NoStore, media)
+}
+
+/// Streams a stored object with its stored content type, or redirects to it
+/// when media redirects are enabled.
+/// Supports single byte ranges given with a `Range` header, which are
+/// answered with a `PartialContent` status.
+/// Stored objects are never replaced, so their key serves as entity tag and
+/// they are cached according to `policy`.
+/// If a variant is requested that has not been generated (yet), the full
+/// object is served instead, and only cached shortly.
+pub(super) fn serve(
+    key: &str,
+    size: ImageSize,
+    policy: CachePolicy,
+    range: &RangeHeader,
+    conditional: &Conditional,
+    storage: &Storage,
+) -> Result<Cached<Media>, ErrStatus> {
+    let variant = size.key(key);
+    let etag = caching::version_etag(&variant);
+
+    if conditional.is_fresh(Some(&etag), None) {
+        return Ok(Cached::not_modified(etag, policy));
+    }
+
+    if *MEDIA_REDIRECTS || range.is_requested() {
+        let (served, total) = match storage.size(&variant) {
+            Ok(total) => (variant.clone(), total),
+            Err(StorageError::NotFound) if size != ImageSize::Full => {
+                let total = storage.size(key).map_err(log_storage_err)?;
+                (key.to_string(), total)
+            }
+            Err(e) => return Err(log_storage_err(e)),
+        };
+
+        if let Some(redirect) = redirect(&served, storage)? {
+            return Ok(uncached(redirect));
+        }
+
+        let range = match range.resolve(total) {
+            Ok(Some(range)) => range,
+            Ok(None) => {
+                return serve_full(key, size, policy, conditional, storage)
+            }
+            Err(()) => return Ok(uncached(Media::Unsatisfiable { total })),
+        };
+        let (etag, policy) = if served == variant {
+            (etag, policy)
+        } else {
+            (caching::version_etag(key), CachePolicy::Short)
+        };
+
+        let blob = storage
+            .get_range(&served, range.start, range.end)
+            .map_err(log_storage_err)?;
+        let media = Media::Partial { blob, range, total };
+
+        return Ok(conditional.respond(Some(etag), None, policy, media));
+    }
+
+    serve_full(key, size, policy, conditional, storage)
+}
+
+/// Streams a whole stored object, or the object under `key` if its variant
+/// has not been generated.
+fn serve_full(
+    key: &str,
+    size: ImageSize,
+    policy: CachePolicy,
+    conditional: &Conditional,
+    storage: &Storage,
+) -> Result<Cached<Media>, ErrStatus> {
+    let variant = size.key(key);
+    let (blob, etag, policy) = match storage.get(&variant) {
+        Ok(blob) => (blob, caching::version_etag(&variant), policy),
+        Err(StorageError::NotFound) if size != ImageSize::Full => {
+            let blob = storage.get(key).map_err(log_storage_err)?;
+            (blob, caching::version_etag(key), CachePolicy::Short)
+        }
+        Err(e) => return Err(log_storage_err(e)),
+    };
+
+    Ok(conditional.respond(Some(etag), None, policy, Media::Full(blob)))
+}
+
+/// Finds an entry of a user.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+pub(super) fn owned_entry(
+    entry_id: i32,
+    user_id: i32,
+    conn: &PgConnection,
+) -> Result<Entry, ErrStatus> {
+    use db::schema::entries;
+
+    let entry = entries::table
+        .find(entry_id)
+        .first::<Entry>(conn)
+        .map_err(log_db_err)?;
+    if entry.user_id != user_id {
+        return Err(status::Custom(Status::NotFound, ()));
+    }
+
+    Ok(entry)
+}
+
+/// Finds an uploaded, or else a pending, attachment of an entry.
+fn find_by_state(
+    entry_id: i32,
+    attachment_id: i32,
+    uploaded: bool,
+    conn: &PgConnection,
+) -> Result<Attachment, ErrStatus> {
+    use db::schema::attachments;
+
+    attachments::table
+        .find(attachment_id)
+        .filter(attachments::entry_id.eq(entry_id))
+        .filter(attachments::uploaded.eq(uploaded))
+        .first::<Attachment>(conn)
+        .map_err(log_db_err)
+}
+
+/// Finds an uploaded attachment of an entry.
+fn find(
+    entry_id: i32,
+    attachment_id: i32,
+    conn: &PgConnection,
+) -> Result<Attachment, ErrStatus> {
+    find_by_state(entry_id, attachment_id, true, conn)
+}
+
+/// Adds an attachment to the end of the attachments of an entry.
+/// Accepts JPEG, PNG, HEIC and WebP images, MP3, AAC, M4A, Ogg and WAV audio,
+/// and MP4, QuickTime and WebM video.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+/// If the media type is not supported, fails with an `UnsupportedMediaType`
+/// status.
+/// If the media exceeds the maximum upload size, fails with a
+/// `PayloadTooLarge` status.
+/// If the media exceeds the storage quota of the owner of the entry, fails
+/// with a `PayloadTooLarge` status and the remaining quota.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[post("/entry/<entry_id>/attachment", data = "<media>")]
+pub fn create(
+    entry_id: i32,
+    media: Data,
+    auth: UserInfo,
+    storage: State<Storage>,
+    variants: State<VariantGenerator>,
+    gazetteer: State<Gazetteer>,
+    conn: DbConn,
+) -> Result<status::Created<Json<Attachment>>, UploadError> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+
+    let (attachment, _metadata, _hash) = store_upload(
+        entry_id,
+        media,
+        &storage,
+        &variants,
+        &gazetteer,
+        &*conn,
+    )?;
+    let location =
+        format!("/entry/{}/attachment/{}", entry_id, attachment.id);
+
+    Ok(status::Created(location, Some(Json(attachment))))
+}
+
+/// Streams the media of an attachment.
+/// Supports single byte ranges given with a `Range` header, which are
+/// answered with a `PartialContent` status.
+/// When media redirects are enabled, redirects to a short-lived presigned
+/// storage URL instead.
+/// Answers with a `NotModified` status if the client's copy is still current.
+/// If the attachment does not exist, or the entry belongs to another user,
+/// fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/<entry_id>/attachment/<attachment_id>", rank = 2)]
+pub fn get_by_id(
+    entry_id: i32,
+    attachment_id: i32,
+    range: RangeHeader,
+    conditional: Conditional,
+    auth: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<Cached<Media>, ErrStatus> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+    let attachment = find(entry_id, attachment_id, &*conn)?;
+    serve(
+        &attachment.key(),
+        ImageSize::Full,
+        CachePolicy::Immutable,
+        &range,
+        &conditional,
+        &storage,
+    )
+}
+
+#[derive(FromForm)]
+pub struct SizeQuery {
+    size: ImageSize,
+}
+
+/// Streams a variant of the media of an attachment, selected with `?size=`.
+/// Sizes are `thumb`, `medium` and `full`. Until a variant has been generated
+/// the full media is served instead. Supports single byte ranges given with a
+/// `Range` header.
+/// Answers with a `NotModified` status if the client's copy is still current.
+/// If the attachment does not exist, or the entry belongs to another user,
+/// fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/<entry_id>/attachment/<attachment_id>?<query>", rank = 1)]
+pub fn get_variant_by_id(
+    entry_id: i32,
+    attachment_id: i32,
+    query: SizeQuery,
+    range: RangeHeader,
+    conditional: Conditional,
+    auth: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<Cached<Media>, ErrStatus> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+    let attachment = find(entry_id, attachment_id, &*conn)?;
+    serve(
+        &attachment.key(),
+        query.size,
+        CachePolicy::Immutable,
+        &range,
+        &conditional,
+        &storage,
+    )
+}
+
+#[derive(Deserialize)]
+pub struct UploadRequest {
+    mime_type: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+pub struct UploadTicket {
+    attachment: Attachment,
+    upload_url: String,
+    expires_in: u64,
+}
+
+/// Issues a presigned URL through which the client uploads the media of a new
+/// attachment directly to storage, with a `PUT` request that has the given
+/// content type. The attachment stays hidden until the upload is confirmed.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+/// If the media type is not supported, fails with an `UnsupportedMediaType`
+/// status.
+/// If the media exceeds the maximum upload size, fails with a
+/// `PayloadTooLarge` status.
+/// If the media exceeds the storage quota of the owner of the entry, fails
+/// with a `PayloadTooLarge` status and the remaining quota. Until the
+/// upload is confirmed, its declared size counts towards the quota.
+/// If the storage backend does not support direct uploads, fails with a
+/// `NotImplemented` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[post("/entry/<entry_id>/attachment/upload", format = "application/json",
+       data = "<upload>")]
+pub fn request_upload(
+    entry_id: i32,
+    upload: Json<UploadRequest>,
+    auth: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<status::Created<Json<UploadTicket>>, UploadError> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+
+    let media_type = MediaType::from_mime(&upload.mime_type)
+        .ok_or_else(|| status::Custom(Status::UnsupportedMediaType, ()))?;
+    if upload.size > max_size(media_type) {
+        return Err(status::Custom(Status::PayloadTooLarge, ()).into());
+    }
+    let attachment = reserve(entry_id, media_type, Some(upload.size), &*conn)?;
+
+    let url = storage
+        .presign_put(&attachment.key(), media_type.mime(), *PRESIGNED_URL_TTL)
+        .map_err(log_storage_err);
+    let url = match url {
+        Ok(Some(url)) => url,
+        result => {
+            attachment::delete(&attachment, &*conn).map_err(log_db_err)?;
+            let unsupported = status::Custom(Status::NotImplemented, ());
+            return Err(result.err().unwrap_or(unsupported).into());
+        }
+    };
+
+    let location = format!(
+        "/entry/{}/attachment/{}/confirm",
+        entry_id, attachment.id
+    );
+    let ticket = UploadTicket {
+        attachment,
+        upload_url: url,
+        expires_in: PRESIGNED_URL_TTL.as_secs(),
+    };
+
+    Ok(status::Created(location, Some(Json(ticket))))
+}
+
+/// Confirms a direct upload to storage, making its attachment visible.
+/// The uploaded media is checked against the declared type and size limit,
+/// and processed like media uploaded through the server, except that it is
+/// not deduplicated.
+/// If the pending attachment does not exist, or the entry belongs to another
+/// user, fails with a `NotFound` status.
+/// If the media has not been uploaded yet, fails with a `Conflict` status.
+/// If the media does not match its declared type, exceeds the maximum upload
+/// size, or exceeds the storage quota, it is discarded and fails with an
+/// `UnsupportedMediaType` or `PayloadTooLarge` status. The latter comes with
+/// the remaining quota if the storage quota is exceeded.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[put("/entry/<entry_id>/attachment/<attachment_id>/confirm")]
+pub fn confirm_upload(
+    entry_id: i32,
+    attachment_id: i32,
+    auth: UserInfo,
+    storage: State<Storage>,
+    variants: State<VariantGenerator>,
+    gazetteer: State<Gazetteer>,
+    conn: DbConn,
+) -> Result<Json<Attachment>, UploadError> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+    let attachment = find_by_state(entry_id, attachment_id, false, &*conn)?;
+    let key = attachment.upload_key();
+
+    let size = match storage.size(&key) {
+        Ok(size) => size,
+        Err(StorageError::NotFound) => {
+            return Err(status::Custom(Status::Conflict, ()).into())
+        }
+        Err(e) => return Err(log_storage_err(e).into()),
+    };
+
+    let declared = MediaType::from_mime(&attachment.mime_type);
+    let head = if size == 0 {
+        Vec::new()
+    } else {
+        let len = size.min(CAPTURE_LEN as u64);
+        storage
+            .get_range(&key, 0, len - 1)
+            .and_then(Blob::into_bytes)
+            .map_err(log_storage_err)?
+    };
+    let sniffed = MediaType::sniff(&head[..head.len().min(SNIFF_LEN)]);
+
+    let rejection = match declared {
+        _ if sniffed != declared => Some(Status::UnsupportedMediaType),
+        Some(t) if size > max_size(t) => Some(Status::PayloadTooLarge),
+        None => Some(Status::UnsupportedMediaType),
+        Some(_) => None,
+    };
+    if let Some(rejection) = rejection {
+        discard(&attachment, &storage, &*conn)?;
+        return Err(status::Custom(rejection, ()).into());
+    }
+
+    // directly uploaded media is kept under its upload key, as hashing it
+    // would mean downloading all of it again
+    let confirmed = conn.transaction(|| {
+        let owner = attachment::lock_owner(entry_id, &*conn)?;
+        // the declared size of the upload itself is reserved
+        let remaining =
+            remaining_quota(owner, &*conn)? + attachment.size as u64;
+        if size > remaining {
+            return Ok(Err(remaining));
+        }
+
+        attachment::confirm(attachment.id, size as i64, &*conn).map(Ok)
+    }).map_err(log_db_err)?;
+    let attachment = match confirmed {
+        Ok(attachment) => attachment,
+        Err(remaining) => {
+            discard(&attachment, &storage, &*conn)?;
+            return Err(quota_exceeded(entry_id, remaining));
+        }
+    };
+
+    if sniffed.map(|t| t.kind()) == Some(MediaKind::Image) {
+        let metadata = PhotoMetadata::extract(&head);
+        process_image(
+            &attachment,
+            &metadata,
+            true,
+            &variants,
+            &gazetteer,
+            &*conn,
+        )?;
+    }
+
+    Ok(Json(attachment))
+}
+
+/// Deletes a pending attachment along with its rejected direct upload.
+fn discard(
+    attachment: &Attachment,
+    storage: &Storage,
+    conn: &PgConnection,
+) -> Result<(), ErrStatus> {
+    debug!("Rejected direct upload of attachment {}", attachment.id);
+    attachment::delete(attachment, conn).map_err(log_db_err)?;
+    lifecycle::delete(storage, &attachment.upload_key())
+        .map_err(log_storage_err)
+}
+
+/// Reorders the attachments of an entry.
+/// Takes the IDs of all attachments of the entry in their new order.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+/// If the IDs do not match the attachments of the entry, fails with a
+/// `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[put("/entry/<entry_id>/attachment", format = "application/json",
+      data = "<order>")]
+pub fn reorder(
+    entry_id: i32,
+    order: Json<Vec<i32>>,
+    auth: UserInfo,
+    conn: DbConn,
+) -> Result<(), ErrStatus> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+    if !attachment::reorder(entry_id, &order, &*conn).map_err(log_db_err)? {
+        return Err(status::Custom(Status::BadRequest, ()));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct AttachmentUpdate {
+    caption: Option<String>,
+}
+
+/// Updates the caption of an attachment.
+/// If the attachment does not exist, or the entry belongs to another user,
+/// fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[put("/entry/<entry_id>/attachment/<attachment_id>",
+      format = "application/json", data = "<update>")]
+pub fn update(
+    entry_id: i32,
+    attachment_id: i32,
+    update: Json<AttachmentUpdate>,
+    auth: UserInfo,
+    conn: DbConn,
+) -> Result<Json<Attachment>, ErrStatus> {
+    use db::schema::attachments;
+
+    owned_entry(entry_id, auth.id, &*conn)?;
+    let attachment = find(entry_id, attachment_id, &*conn)?;
+    let result = diesel::update(&attachment)
+        .set(attachments::caption.eq(update.into_inner().caption))
+        .get_result::<Attachment>(&*conn)
+        .map_err(log_db_err)?;
+
+    Ok(Json(result))
+}
+
+/// Deletes an attachment along with its stored media, unless the media is
+/// shared with other attachments.
+/// If the attachment does not exist, or the entry belongs to another user,
+/// fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[delete("/entry/<entry_id>/attachment/<attachment_id>")]
+pub fn delete(
+    entry_id: i32,
+    attachment_id: i32,
+    auth: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<(), ErrStatus> {
+    owned_entry(entry_id, auth.id, &*conn)?;
+    let attachment = find(entry_id, attachment_id, &*conn)?;
+
+    match attachment::delete(&attachment, &*conn).map_err(log_db_err)? {
+        Some(key) => lifecycle::delete(&storage, &key).map_err(log_storage_err),
+        None => Ok(()),
+    }
+}
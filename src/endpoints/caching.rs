@@ -0,0 +1,219 @@
+use chrono::NaiveDateTime;
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{Outcome, Request};
+use serde::Serialize;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+/// Format of HTTP dates, which are always in GMT.
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// How long clients may cache a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Clients must revalidate before every use.
+    Revalidate,
+    /// The response never changes, e.g. a stored version of an image.
+    Immutable,
+    /// The response may change soon, e.g. while image variants are generated.
+    Short,
+    /// The response must not be stored at all.
+    NoStore,
+}
+
+impl CachePolicy {
+    fn header(&self) -> &'static str {
+        match *self {
+            CachePolicy::Revalidate => "private, no-cache",
+            CachePolicy::Immutable => "private, max-age=31536000, immutable",
+            CachePolicy::Short => "private, max-age=60",
+            CachePolicy::NoStore => "no-store",
+        }
+    }
+}
+
+/// Creates a strong entity tag from the content hash of a JSON response.
+pub fn json_etag<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    let json = serde_json::to_vec(value)?;
+    let hash = Sha256::digest(&json);
+    let hex = hash.iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    Ok(format!("\"{}\"", hex))
+}
+
+/// Creates a strong entity tag from the version of a resource.
+pub fn version_etag(version: &str) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The conditional request headers of a request.
+#[derive(Default)]
+pub struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<NaiveDateTime>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Conditional {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let headers = request.headers();
+        let if_modified_since = headers
+            .get_one("If-Modified-Since")
+            .and_then(|date| {
+                NaiveDateTime::parse_from_str(date.trim(), HTTP_DATE).ok()
+            });
+
+        Outcome::Success(Conditional {
+            if_none_match: headers.get_one("If-None-Match").map(Into::into),
+            if_modified_since,
+        })
+    }
+}
+
+impl Conditional {
+    /// Whether the client already has the current representation.
+    /// `If-None-Match` takes precedence over `If-Modified-Since`.
+    pub fn is_fresh(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<NaiveDateTime>,
+    ) -> bool {
+        if let Some(ref tags) = self.if_none_match {
+            return etag.map_or(false, |etag| {
+                tags.split(',').map(str::trim).any(|tag| {
+                    tag == "*" || tag.trim_left_matches("W/") == etag
+                })
+            });
+        }
+
+        match (self.if_modified_since, last_modified) {
+            // HTTP dates have a resolution of seconds
+            (Some(since), Some(modified)) => {
+                modified.timestamp() <= since.timestamp()
+            }
+            _ => false,
+        }
+    }
+
+    /// Wraps a response with caching headers, answering with a `NotModified`
+    /// status if the client already has it.
+    pub fn respond<R>(
+        &self,
+        etag: Option<String>,
+        last_modified: Option<NaiveDateTime>,
+        policy: CachePolicy,
+        body: R,
+    ) -> Cached<R> {
+        let fresh =
+            self.is_fresh(etag.as_ref().map(String::as_str), last_modified);
+
+        Cached {
+            etag,
+            last_modified,
+            policy,
+            body: if fresh { None } else { Some(body) },
+        }
+    }
+}
+
+/// A response with caching headers. Without a body, it is a `NotModified`
+/// response.
+pub struct Cached<R> {
+    etag: Option<String>,
+    last_modified: Option<NaiveDateTime>,
+    policy: CachePolicy,
+    body: Option<R>,
+}
+
+impl<R> Cached<R> {
+    /// A `NotModified` response.
+    pub fn not_modified(etag: String, policy: CachePolicy) -> Self {
+        Cached {
+            etag: Some(etag),
+            last_modified: None,
+            policy,
+            body: None,
+        }
+    }
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Cached<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = match self.body {
+            Some(body) => body.respond_to(request)?,
+            None => Response::build().status(Status::NotModified).finalize(),
+        };
+
+        response.set_raw_header("Cache-Control", self.policy.header());
+
+        if let Some(etag) = self.etag {
+            response.set_header(Header::new("ETag", etag));
+        }
+
+        if let Some(modified) = self.last_modified {
+            let date = modified.format(HTTP_DATE).to_string();
+            response.set_header(Header::new("Last-Modified", date));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn conditional(
+        if_none_match: Option<&str>,
+        if_modified_since: Option<NaiveDateTime>,
+    ) -> Conditional {
+        Conditional {
+            if_none_match: if_none_match.map(Into::into),
+            if_modified_since,
+        }
+    }
+
+    #[test]
+    fn etags() {
+        let etag = json_etag(&vec![1, 2, 3]).expect("failed to hash");
+        assert_eq!(etag, json_etag(&vec![1, 2, 3]).expect("failed to hash"));
+        assert!(etag != json_etag(&vec![1, 2]).expect("failed to hash"));
+
+        let etag = Some("\"abc\"");
+        assert!(conditional(Some("\"abc\""), None).is_fresh(etag, None));
+        assert!(
+            conditional(Some("\"x\", W/\"abc\""), None).is_fresh(etag, None)
+        );
+        assert!(conditional(Some("*"), None).is_fresh(etag, None));
+        assert!(!conditional(Some("\"x\""), None).is_fresh(etag, None));
+        assert!(!conditional(None, None).is_fresh(etag, None));
+    }
+
+    #[test]
+    fn modified_since() {
+        let modified =
+            NaiveDate::from_ymd(2018, 4, 1).and_hms_milli(12, 0, 0, 500);
+        let since = NaiveDateTime::parse_from_str(
+            "Sun, 01 Apr 2018 12:00:00 GMT",
+            HTTP_DATE,
+        ).expect("failed to parse date");
+
+        assert!(conditional(None, Some(since)).is_fresh(None, Some(modified)));
+        assert!(
+            !conditional(None, Some(since - ::chrono::Duration::seconds(1)))
+                .is_fresh(None, Some(modified))
+        );
+        // If-None-Match takes precedence
+        assert!(
+            !conditional(Some("\"x\""), Some(since))
+                .is_fresh(Some("\"y\""), Some(modified))
+        );
+    }
+}
@@ -1,167 +1,337 @@
-use std::env;
-
-use diesel;
 use diesel::prelude::*;
 
-use rocket::Data;
-use rocket::http::{ContentType, Status};
+use rocket::http::Status;
 use rocket::response::status;
+use rocket::{Data, State};
 use rocket_contrib::Json;
 
-use chrono::DateTime;
-use futures::stream::Stream;
-use rusoto_core::region::Region;
-use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest, S3,
-                S3Client};
+use chrono::{DateTime, Utc};
 
-use super::{log_db_err, log_err, ErrStatus, Page, PAGE_SIZE};
+use super::attachment::UploadError;
+use super::caching::{self, CachePolicy, Cached, Conditional};
+use super::idempotency::{self, IdempotencyKey, Idempotent};
+use super::media::{Media, RangeHeader};
+use super::{log_db_err, log_err, log_insert_err, ErrStatus, Page,
+            PAGE_SIZE};
 use chrono::FixedOffset;
 use db::DbConn;
-use db::models::entry::{self, Entry, NewEntry};
+use db::models::attachment::{self, Attachment};
+use db::models::entry::{self, Entry, EntryUpdate, NewEntry};
 use db::models::journey::Journey;
 use db::models::user::UserInfo;
+use geo::gazetteer::Gazetteer;
+use geo::zone;
+use geo::{FormattedPoint, PointFormat};
+use media::lifecycle;
+use media::metadata::PhotoMetadata;
+use media::variants::{ImageSize, VariantGenerator};
+use storage::Storage;
+use uuid::Uuid;
 
 /// Creates a new entry.
+/// If the entry has a point but no location, the location is named after the
+/// nearest known place.
+/// Without a time zone, the entry is in the time zone at its point, or in the
+/// time zone of the user.
+/// An entry written offline may give the time it was written, which must fall
+/// within the journey. Other entries are created when received.
+/// A retry of a request with the same `Idempotency-Key` gets the response to
+/// the original request, rather than creating the entry again.
 /// If the journey does not exist, fails with a `NotFound` status.
-/// If the journey has ended already, fails with a `BadRequest` status.
+/// If the entry falls outside of the journey, the point of the entry is not a
+/// valid location, or the time zone is unknown, fails with a `BadRequest`
+/// status.
+/// If the UUID of the entry is taken, fails with a `Conflict` status.
+/// If the key was used for another request, fails with an
+/// `UnprocessableEntity` status.
+/// A retry while the request with the key is still being handled waits for
+/// it to be answered.
 /// If an unexpected error occurs, fails with an `InternalServiceError` status.
 #[post("/entry", format = "application/json", data = "<new_entry>")]
 pub fn create(
     new_entry: Json<NewEntry>,
-    _user: UserInfo,
+    user: UserInfo,
+    key: IdempotencyKey,
+    gazetteer: State<Gazetteer>,
     conn: DbConn,
+) -> Result<Idempotent<status::Created<Json<TimezoneEntry>>>, ErrStatus> {
+    let new_entry = new_entry.into_inner();
+    let hash = idempotency::json_request_hash("POST /entry", &new_entry)?;
+    key.handle(
+        user.id,
+        &hash,
+        || create_entry(new_entry, &gazetteer, &*conn),
+        &*conn,
+    )
+}
+
+fn create_entry(
+    mut new_entry: NewEntry,
+    gazetteer: &Gazetteer,
+    conn: &PgConnection,
 ) -> Result<status::Created<Json<TimezoneEntry>>, ErrStatus> {
     use db::schema::journeys;
 
     let journey = journeys::table
         .find(new_entry.journey_id)
-        .first::<Journey>(&*conn)
+        .first::<Journey>(conn)
         .map_err(log_db_err)?;
 
-    if journey.end_date.is_some() {
+    if !new_entry.fits(&journey, Utc::now()) {
         return Err(status::Custom(Status::BadRequest, ()));
     }
 
-    let entry = entry::create(&new_entry, &*conn).map_err(log_db_err)?;
+    entry::fill_in(&mut new_entry, gazetteer, conn).map_err(log_db_err)?;
+    let entry = entry::create(&new_entry, conn).map_err(log_insert_err)?;
 
     Ok(status::Created(
         String::new(),
-        Some(Json(entry.into())),
+        Some(Json((entry, Vec::new()).into())),
     ))
 }
 
-/// Gets the data body of an entry.
-/// If an unexpected error occurs, fails with an `InternalServiceError` status.
-#[get("/entry/<entry_id>")]
-pub fn get_by_id(
+/// Loads an entry along with its attachments, with its point in `format`.
+fn get(
     entry_id: i32,
-    conn: DbConn,
-) -> Result<Json<TimezoneEntry>, ErrStatus> {
+    format: PointFormat,
+    conditional: &Conditional,
+    conn: &PgConnection,
+) -> Result<Cached<Json<TimezoneEntry>>, ErrStatus> {
     use db::schema::entries::dsl::*;
 
     let entry: Entry = entries
         .find(entry_id)
-        .first(&*conn)
+        .first(conn)
         .map_err(log_db_err)?;
+    let attachments = attachment::load(&entry, conn).map_err(log_db_err)?;
+
+    let modified = entry.updated_at.naive_utc();
+    let entry =
+        TimezoneEntry::from((entry, attachments)).with_point_format(format);
+    let etag = caching::json_etag(&entry).map_err(log_err)?;
+
+    Ok(conditional.respond(
+        Some(etag),
+        Some(modified),
+        CachePolicy::Revalidate,
+        Json(entry),
+    ))
+}
 
-    Ok(Json(entry.into()))
+/// Gets the data body of an entry, along with its attachments.
+/// Answers with a `NotModified` status if the client's copy is still current.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/<entry_id>", rank = 2)]
+pub fn get_by_id(
+    entry_id: i32,
+    conditional: Conditional,
+    conn: DbConn,
+) -> Result<Cached<Json<TimezoneEntry>>, ErrStatus> {
+    get(entry_id, PointFormat::Plain, &conditional, &*conn)
+}
+
+#[derive(FromForm)]
+pub struct FormatQuery {
+    geojson: Option<bool>,
+}
+
+/// Gets the data body of an entry like `get_by_id`, with its point as a
+/// GeoJSON geometry if `?geojson=true` is given.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/<entry_id>?<query>", rank = 1)]
+pub fn get_formatted_by_id(
+    entry_id: i32,
+    query: FormatQuery,
+    conditional: Conditional,
+    conn: DbConn,
+) -> Result<Cached<Json<TimezoneEntry>>, ErrStatus> {
+    let format = PointFormat::from_flag(query.geojson);
+    get(entry_id, format, &conditional, &*conn)
 }
 
 /// Updates an entry.
-/// Takes a NewEntry object.
+/// Takes an EntryUpdate object. A given location replaces the current one,
+/// and `null` clears it. An entry that is moved without a given location is
+/// named after the nearest known place.
+/// If the entry does not exist, fails with a `NotFound` status.
+/// If the point is not a valid location, fails with a `BadRequest` status.
 /// If an unexpected error occurs, fails with an `InternalServiceError` status.
-#[put("/entry/<entry_id>", format = "application/json", data = "<new_entry>")]
+#[put("/entry/<entry_id>", format = "application/json", data = "<changes>")]
 pub fn update(
     entry_id: i32,
-    new_entry: Json<NewEntry>,
+    changes: Json<EntryUpdate>,
     _user: UserInfo,
+    gazetteer: State<Gazetteer>,
     conn: DbConn,
 ) -> Result<(), ErrStatus> {
-    use db::schema::entries::dsl::*;
-
-    let entry = new_entry.into_inner();
-    let target = entries.find(entry_id);
+    if !changes.point.map_or(true, |p| p.is_valid()) {
+        return Err(status::Custom(Status::BadRequest, ()));
+    }
 
-    diesel::update(target)
-        .set(description.eq(entry.description))
-        .execute(&*conn)
+    entry::update(entry_id, &changes, &gazetteer, &*conn)
         .map_err(log_db_err)?;
 
     Ok(())
 }
 
-lazy_static! {
-    static ref S3_CLIENT: S3Client = S3Client::simple(Region::EuCentral1);
-    static ref S3_BUCKET: String =
-        env::var("S3_BUCKET").expect("S3_BUCKET must be set");
-}
-
-/// Puts the image of an entry in the file system.
+/// Adds an image to the attachments of an entry.
+/// The capture time and location are read from the EXIF data of the image,
+/// stored with the entry and returned. If the entry has no coordinates yet,
+/// the location of the image is used.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+/// If the media type is not supported, fails with an `UnsupportedMediaType`
+/// status.
+/// If the image exceeds the maximum upload size, fails with a
+/// `PayloadTooLarge` status.
+/// If the image exceeds the storage quota of the owner of the entry, fails
+/// with a `PayloadTooLarge` status and the remaining quota.
+/// A retry of a request with the same `Idempotency-Key` gets the response to
+/// the original request, rather than adding the image again. If the key was
+/// used for another request, fails with an `UnprocessableEntity` status. If a
+/// request with the key is still being handled, fails with a `Conflict`
+/// status.
 /// If an unexpected error occurs, fails with an `InternalServiceError` status.
 #[post("/entry/<entry_id>/image", data = "<image>")]
 pub fn create_image(
     entry_id: i32,
-    content_type: Option<&ContentType>,
     image: Data,
-    _auth: UserInfo,
+    user: UserInfo,
+    key: IdempotencyKey,
+    storage: State<Storage>,
+    variants: State<VariantGenerator>,
+    gazetteer: State<Gazetteer>,
     conn: DbConn,
-) -> Result<status::Created<()>, ErrStatus> {
-    use db::schema::entries;
+) -> Result<Idempotent<status::Created<Json<PhotoMetadata>>>, UploadError> {
+    let route = format!("POST /entry/{}/image", entry_id);
+    if let Some(earlier) = key.claim(user.id, &*conn)? {
+        let hash =
+            super::attachment::upload_hash(image, earlier.location(), &*conn)?;
+        let hash = idempotency::request_hash(&route, &hash);
+        return Ok(earlier.replay(&hash)?);
+    }
+
+    let stored = super::attachment::owned_entry(entry_id, user.id, &*conn)
+        .map_err(UploadError::from)
+        .and_then(|_| {
+            super::attachment::store_upload(
+                entry_id,
+                image,
+                &storage,
+                &variants,
+                &gazetteer,
+                &*conn,
+            )
+        });
+    let (attachment, metadata, hash) = match stored {
+        Ok(stored) => stored,
+        Err(e) => return Err(key.release(user.id, e, &*conn)),
+    };
+    let location =
+        format!("/entry/{}/attachment/{}", entry_id, attachment.id);
+
+    let created = key.store(
+        user.id,
+        &idempotency::request_hash(&route, &hash),
+        status::Created(location, Some(Json(metadata))),
+        &*conn,
+    )?;
+
+    Ok(created)
+}
+
+/// Finds the storage key of the first image attachment of an entry of a
+/// user. Entries with an image from before attachments existed have their
+/// image stored under their ID.
+/// If the entry does not exist or belongs to another user, fails with a
+/// `NotFound` status.
+fn image_key(
+    entry_id: i32,
+    user_id: i32,
+    conn: &PgConnection,
+) -> Result<String, ErrStatus> {
+    use db::schema::{attachments, entries};
+    use diesel::result::Error;
 
     entries::table
         .find(entry_id)
-        .first::<Entry>(&*conn)
+        .filter(entries::user_id.eq(user_id))
+        .select(entries::id)
+        .first::<i32>(conn)
         .map_err(log_db_err)?;
 
-    let mut buf: Vec<u8> = Vec::new();
-    image.stream_to(&mut buf).map_err(log_err)?;
-
-    let mut request = PutObjectRequest::default();
-    request.content_type = content_type.map(ToString::to_string);
-    request.bucket = S3_BUCKET.clone();
-    request.key = entry_id.to_string();
-    request.body = Some(buf);
-
-    S3_CLIENT
-        .put_object(&request)
-        .sync()
-        .map_err(log_err)?;
+    let first = attachments::table
+        .filter(attachments::entry_id.eq(entry_id))
+        .filter(attachments::mime_type.like("image/%"))
+        .filter(attachments::uploaded.eq(true))
+        .order(attachments::position.asc())
+        .first::<Attachment>(conn);
+
+    match first {
+        Ok(attachment) => Ok(attachment.key()),
+        Err(Error::NotFound) => Ok(entry::legacy_image_key(entry_id)),
+        Err(e) => Err(log_db_err(e)),
+    }
+}
 
-    Ok(status::Created(String::new(), Some(())))
+/// Streams the first image of an entry from storage.
+/// Supports single byte ranges given with a `Range` header.
+/// As the first image changes when attachments are added or reordered,
+/// clients must revalidate their copy.
+/// If the image does not exist, or the entry belongs to another user, fails
+/// with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/<entry_id>/image", rank = 2)]
+pub fn get_image_by_id(
+    entry_id: i32,
+    range: RangeHeader,
+    conditional: Conditional,
+    user: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<Cached<Media>, ErrStatus> {
+    let key = image_key(entry_id, user.id, &*conn)?;
+    super::attachment::serve(
+        &key,
+        ImageSize::Full,
+        CachePolicy::Revalidate,
+        &range,
+        &conditional,
+        &storage,
+    )
 }
 
-/// Logs a `GetObjectError` with error priority.
-/// If the error was `NoSuchKey`, returns a `NotFound` status.
-/// Else, returns an `InternalServiceError` status.
-fn log_rusoto_err(e: GetObjectError) -> ErrStatus {
-    match e {
-        GetObjectError::NoSuchKey(_msg) => status::Custom(Status::NotFound, ()),
-        e => log_err(e),
-    }
+#[derive(FromForm)]
+pub struct ImageQuery {
+    size: ImageSize,
 }
 
-/// Retrieves the image of an entry.
+/// Streams a variant of the first image of an entry, selected with `?size=`.
+/// Sizes are `thumb`, `medium` and `full`. Until a variant has been generated
+/// the full image is served instead. Supports single byte ranges given with a
+/// `Range` header.
+/// If the image does not exist, or the entry belongs to another user, fails
+/// with a `NotFound` status.
 /// If an unexpected error occurs, fails with an `InternalServiceError` status.
-#[get("/entry/<entry_id>/image")]
-pub fn get_image_by_id(entry_id: i32) -> Result<Vec<u8>, ErrStatus> {
-    let mut request = GetObjectRequest::default();
-    request.bucket = S3_BUCKET.clone();
-    request.key = entry_id.to_string();
-
-    let body = S3_CLIENT
-        .get_object(&request)
-        .sync()
-        .map_err(log_rusoto_err)?
-        .body
-        .ok_or_else(|| log_err("Missing body in response to image request"))?
-        .wait()
-        .collect::<Result<Vec<Vec<u8>>, _>>()
-        .map_err(log_err)?
-        .into_iter()
-        .flat_map(IntoIterator::into_iter)
-        .collect();
-
-    Ok(body)
+#[get("/entry/<entry_id>/image?<query>", rank = 1)]
+pub fn get_image_variant_by_id(
+    entry_id: i32,
+    query: ImageQuery,
+    range: RangeHeader,
+    conditional: Conditional,
+    user: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<Cached<Media>, ErrStatus> {
+    let key = image_key(entry_id, user.id, &*conn)?;
+    super::attachment::serve(
+        &key,
+        query.size,
+        CachePolicy::Revalidate,
+        &range,
+        &conditional,
+        &storage,
+    )
 }
 
 /// Deletes an entry.
@@ -172,14 +342,34 @@ pub fn delete(entry_id: i32, conn: DbConn) -> Result<(), ErrStatus> {
     entry::archive(entry_id, &*conn).map_err(log_db_err)
 }
 
+/// Permanently deletes an entry, along with its attachments and their stored
+/// media.
+/// If the entry does not exist, or belongs to another user, fails with a
+/// `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[delete("/entry/<entry_id>/purge")]
+pub fn purge(
+    entry_id: i32,
+    user: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<(), ErrStatus> {
+    let keys = entry::purge(entry_id, user.id, &*conn).map_err(log_db_err)?;
+    lifecycle::delete_all(&storage, &keys);
+
+    Ok(())
+}
+
 #[derive(FromForm)]
 pub struct EntryQuery {
     page: Page,
     journey: Option<i32>,
+    geojson: Option<bool>,
 }
 
 // Note: `offset` usage here has bad performance on large page numbers
 /// Gets a page of entries according to the query-string.
+/// With `geojson=true`, the points of the entries are GeoJSON geometries.
 /// If a nonexistent journey ID is given, fails with a `NotFound` status.
 /// If an unexpected error occurs, fails with an `InternalServiceError` status.
 #[get("/entry/all?<query>")]
@@ -199,32 +389,72 @@ pub fn get_all(
         target = target.filter(entries::journey_id.eq(jid));
     }
 
-    let result = target
+    let entries = target
         .offset(page * PAGE_SIZE)
         .limit(PAGE_SIZE)
         .get_results::<Entry>(&*conn)
-        .map_err(log_db_err)?
+        .map_err(log_db_err)?;
+
+    let format = PointFormat::from_flag(query.geojson);
+    Ok(Json(with_attachments(entries, format, &*conn)?))
+}
+
+/// Loads the attachments of entries, and formats their points as requested.
+pub(super) fn with_attachments(
+    entries: Vec<Entry>,
+    format: PointFormat,
+    conn: &PgConnection,
+) -> Result<Vec<TimezoneEntry>, ErrStatus> {
+    let attachments =
+        attachment::load_for(&entries, conn).map_err(log_db_err)?;
+
+    let result = entries
         .into_iter()
-        .map(Into::into)
+        .zip(attachments)
+        .map(|entry| TimezoneEntry::from(entry).with_point_format(format))
         .collect();
 
-    Ok(Json(result))
+    Ok(result)
 }
 
+/// An entry with its creation time in its local time, and in UTC.
 #[derive(Serialize)]
 pub struct TimezoneEntry {
     pub id: i32,
     pub user_id: i32,
     pub journey_id: i32,
     pub created: DateTime<FixedOffset>,
+    pub created_utc: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    pub time_zone: String,
     pub archived: bool,
     pub description: Option<String>,
-    pub coordinates: Option<String>,
+    pub point: Option<FormattedPoint>,
     pub location: Option<String>,
+    pub photo: Option<PhotoMetadata>,
+    pub attachments: Vec<Attachment>,
+    pub uuid: Uuid,
+    /// When the entry or any of its attachments last changed.
+    pub updated_at: DateTime<Utc>,
+    /// The version of the entry, which changes only when the entry itself
+    /// does. Changes pushed by clients are based on it.
+    pub version: DateTime<Utc>,
 }
 
-impl From<Entry> for TimezoneEntry {
-    fn from(entry: Entry) -> Self {
+impl TimezoneEntry {
+    /// Formats the point of the entry as requested.
+    fn with_point_format(mut self, format: PointFormat) -> Self {
+        self.point = match self.point {
+            Some(FormattedPoint::Plain(point)) => Some(point.format(format)),
+            point => point,
+        };
+        self
+    }
+}
+
+impl From<(Entry, Vec<Attachment>)> for TimezoneEntry {
+    fn from((entry, attachments): (Entry, Vec<Attachment>)) -> Self {
+        let point = entry.point().map(FormattedPoint::Plain);
         let Entry {
             id,
             journey_id,
@@ -232,22 +462,46 @@ impl From<Entry> for TimezoneEntry {
             created,
             archived,
             description,
-            coordinates,
             location,
+            captured_at,
+            photo_latitude,
+            photo_longitude,
+            time_zone,
+            received_at,
+            updated_at,
+            uuid,
+            version,
+            ..
         } = entry;
 
-        let hour = 3600;
-        let created = DateTime::from_utc(created, FixedOffset::east(2 * hour));
+        let photo = PhotoMetadata {
+            captured_at,
+            latitude: photo_latitude,
+            longitude: photo_longitude,
+        };
+        let photo = if photo == PhotoMetadata::default() {
+            None
+        } else {
+            Some(photo)
+        };
 
         TimezoneEntry {
             id,
             journey_id,
             user_id,
-            created,
+            created: zone::localize(created, &time_zone),
+            created_utc: created,
+            received_at,
+            time_zone,
             archived,
             description,
-            coordinates,
+            point,
             location,
+            photo,
+            attachments,
+            uuid,
+            updated_at,
+            version,
         }
     }
 }
@@ -0,0 +1,289 @@
+use std::env;
+use std::io::Cursor;
+
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, status, Responder, Response};
+use rocket::{Outcome, Request};
+use rocket_contrib::Json;
+use serde::Serialize;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+use super::{log_db_err, log_err, ErrStatus};
+use db::models::idempotency::{self, IdempotentRequest, StoredResponse};
+
+/// Keys longer than this are rejected.
+const MAX_KEY_LEN: usize = 255;
+
+/// Requests that were not answered within this many seconds are taken to be
+/// abandoned, e.g. because the server stopped while handling them, so that
+/// they can be retried.
+const ABANDONED_AFTER_SECS: i64 = 5 * 60;
+
+lazy_static! {
+    /// Seconds that responses are kept for retries, defaults to a day.
+    static ref IDEMPOTENCY_KEY_TTL: i64 = env::var("IDEMPOTENCY_KEY_TTL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(24 * 60 * 60);
+}
+
+/// The hex-encoded SHA-256 hash of `bytes`.
+fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Hashes a request from its route and the hex-encoded SHA-256 hash of its
+/// body, so that reusing a key for another request can be detected.
+pub fn request_hash(route: &str, body_digest: &str) -> String {
+    hex_digest(format!("{}\n{}", route, body_digest).as_bytes())
+}
+
+/// Hashes a request from its route and its JSON body.
+pub fn json_request_hash<T: Serialize>(
+    route: &str,
+    body: &T,
+) -> Result<String, ErrStatus> {
+    let json = serde_json::to_vec(body).map_err(log_err)?;
+    Ok(request_hash(route, &hex_digest(&json)))
+}
+
+/// The `Idempotency-Key` header of a request, with which clients mark
+/// retries of a request. Keys are scoped to the user.
+pub struct IdempotencyKey(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = ();
+
+    /// Fails with a `BadRequest` status if the key is empty or too long.
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        match request.headers().get_one("Idempotency-Key") {
+            None => Outcome::Success(IdempotencyKey(None)),
+            Some(key) => {
+                let key = key.trim();
+                if key.is_empty() || key.len() > MAX_KEY_LEN {
+                    debug!("Rejected invalid idempotency key");
+                    return Outcome::Failure((Status::BadRequest, ()));
+                }
+                Outcome::Success(IdempotencyKey(Some(key.to_string())))
+            }
+        }
+    }
+}
+
+impl IdempotencyKey {
+    /// Claims the key of a user for the request being handled.
+    /// Returns the earlier request with the key, if it has been answered.
+    /// If the earlier request is still being handled, fails with a
+    /// `Conflict` status.
+    /// If an unexpected error occurs, fails with an `InternalServiceError`
+    /// status.
+    pub fn claim(
+        &self,
+        user_id: i32,
+        conn: &PgConnection,
+    ) -> Result<Option<Answered>, ErrStatus> {
+        let key = match self.0 {
+            Some(ref key) => key,
+            None => return Ok(None),
+        };
+
+        let now = Utc::now();
+        let earlier = idempotency::claim(
+            user_id,
+            key,
+            now - Duration::seconds(*IDEMPOTENCY_KEY_TTL),
+            now - Duration::seconds(ABANDONED_AFTER_SECS),
+            conn,
+        ).map_err(log_db_err)?;
+
+        match earlier {
+            Some(ref request) if request.status.is_none() => {
+                debug!("Request with idempotency key {} in progress", key);
+                Err(status::Custom(Status::Conflict, ()))
+            }
+            Some(request) => Ok(Some(Answered(request))),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the response to the request that claimed the key, to replay it
+    /// on retries.
+    /// If an unexpected error occurs, fails with an `InternalServiceError`
+    /// status.
+    pub fn store<T: Serialize>(
+        &self,
+        user_id: i32,
+        request_hash: &str,
+        response: status::Created<Json<T>>,
+        conn: &PgConnection,
+    ) -> Result<Idempotent<status::Created<Json<T>>>, ErrStatus> {
+        if let Some(ref key) = self.0 {
+            let body = {
+                let json = response.1.as_ref().map(|json| &json.0);
+                serde_json::to_string(&json).map_err(log_err)?
+            };
+            let stored = StoredResponse {
+                request_hash,
+                status: i32::from(Status::Created.code),
+                location: Some(response.0.as_str()),
+                body: &body,
+            };
+            idempotency::complete(user_id, key, &stored, conn)
+                .map_err(log_db_err)?;
+        }
+
+        Ok(Idempotent::Handled(response))
+    }
+
+    /// Releases the key after the request that claimed it failed with
+    /// `error`, so that the request can be retried. Returns `error`.
+    pub fn release<E>(&self, user_id: i32, error: E, conn: &PgConnection) -> E {
+        if let Some(ref key) = self.0 {
+            if let Err(e) = idempotency::release(user_id, key, conn) {
+                // the claim is abandoned, and can be retried later
+                error!("Failed to release idempotency key -- {:?}", e);
+            }
+        }
+
+        error
+    }
+
+    /// Handles a request that only changes the database with `handler`, in
+    /// one transaction with claiming the key and storing the response, so
+    /// that the changes are kept if and only if the response is.
+    /// Replays the response to an earlier request with the key instead, if
+    /// there is one.
+    pub fn handle<T, F>(
+        &self,
+        user_id: i32,
+        request_hash: &str,
+        handler: F,
+        conn: &PgConnection,
+    ) -> Result<Idempotent<status::Created<Json<T>>>, ErrStatus>
+    where
+        T: Serialize,
+        F: FnOnce() -> Result<status::Created<Json<T>>, ErrStatus>,
+    {
+        conn.transaction::<_, Failure, _>(|| {
+            if let Some(earlier) = self.claim(user_id, conn)? {
+                return Ok(earlier.replay(request_hash)?);
+            }
+
+            let response = handler()?;
+            Ok(self.store(user_id, request_hash, response, conn)?)
+        }).map_err(|failure| match failure {
+            Failure::Status(status) => status,
+            Failure::Database(e) => log_db_err(e),
+        })
+    }
+}
+
+/// Why a transaction of `IdempotencyKey::handle` was rolled back.
+enum Failure {
+    Status(ErrStatus),
+    Database(Error),
+}
+
+impl From<ErrStatus> for Failure {
+    fn from(status: ErrStatus) -> Self {
+        Failure::Status(status)
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Database(e)
+    }
+}
+
+/// An earlier request with the same idempotency key, that has been answered.
+pub struct Answered(IdempotentRequest);
+
+impl Answered {
+    /// The location of what the earlier request created, if any.
+    pub fn location(&self) -> Option<&str> {
+        self.0.location.as_ref().map(String::as_str)
+    }
+
+    /// The response to the earlier request, if it was the same request.
+    /// If the request with the key differed, fails with an
+    /// `UnprocessableEntity` status.
+    pub fn replay<R>(
+        self,
+        request_hash: &str,
+    ) -> Result<Idempotent<R>, ErrStatus> {
+        let same = self.0.request_hash.as_ref().map(String::as_str)
+            == Some(request_hash);
+        if !same {
+            debug!("Idempotency key {} reused by another request", self.0.key);
+            return Err(status::Custom(Status::UnprocessableEntity, ()));
+        }
+
+        Ok(Idempotent::Replayed(self.0))
+    }
+}
+
+/// The response to a request with an idempotency key: either the response of
+/// handling it, or the stored response to an earlier request with the key.
+pub enum Idempotent<R> {
+    Handled(R),
+    Replayed(IdempotentRequest),
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Idempotent<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let stored = match self {
+            Idempotent::Handled(response) => {
+                return response.respond_to(request)
+            }
+            Idempotent::Replayed(stored) => stored,
+        };
+
+        let status = stored
+            .status
+            .and_then(|code| Status::from_code(code as u16))
+            .ok_or(Status::InternalServerError)?;
+
+        let mut response = Response::build();
+        response
+            .status(status)
+            .header(ContentType::JSON)
+            .raw_header("Idempotent-Replayed", "true")
+            .sized_body(Cursor::new(stored.body.unwrap_or_default()));
+        if let Some(location) = stored.location {
+            response.raw_header("Location", location);
+        }
+
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_hashes() {
+        let hash = json_request_hash("POST /journey", &vec!["Trip"]).unwrap();
+        let body_digest = hex_digest(b"[\"Trip\"]");
+
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, request_hash("POST /journey", &body_digest));
+        assert_ne!(
+            hash,
+            json_request_hash("POST /entry", &vec!["Trip"]).unwrap()
+        );
+        assert_ne!(
+            hash,
+            json_request_hash("POST /journey", &vec!["Tour"]).unwrap()
+        );
+    }
+}
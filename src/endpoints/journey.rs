@@ -1,37 +1,88 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Cursor, Read};
+use std::vec;
+
 use diesel;
-use diesel::dsl::now;
 use diesel::prelude::*;
+use rocket::{Data, Request};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
 use rocket_contrib::Json;
 
-use super::{log_db_err, ErrStatus, Page, PAGE_SIZE};
-use db::DbConn;
-use db::models::journey::{self, Journey, NewJourney};
+use super::caching::{self, CachePolicy, Cached, Conditional};
+use super::entry::TimezoneEntry;
+use super::idempotency::{self, IdempotencyKey, Idempotent};
+use super::{log_db_err, log_err, log_insert_err, ErrStatus, Page,
+            PAGE_SIZE};
+use db::{DbConn, Pool};
+use db::models::entry::Entry;
+use db::models::journey::{self, Journey, JourneyUpdate, NewJourney};
+use db::models::track::{self, TrackPoint};
 use db::models::user::UserInfo;
+use geo::export::{Export, ExportFormat, Feature};
+use geo::gazetteer::Gazetteer;
+use geo::gpx::Gpx;
+use geo::stats::{RouteStats, StatsCache};
+use geo::zone;
+use rocket::State;
 
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use chrono::FixedOffset;
 use rocket::response::status;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Maximum size of imported files in bytes, defaults to 10 MiB.
+    static ref MAX_IMPORT_SIZE: u64 = env::var("MAX_IMPORT_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(10 * 1024 * 1024);
+}
 
+/// Creates a new journey.
+/// A retry of a request with the same `Idempotency-Key` gets the response to
+/// the original request, rather than creating the journey again. Keys are
+/// scoped to the user.
+/// If the journey is for another user, fails with a `Forbidden` status.
+/// If the UUID of the journey is taken, fails with a `Conflict` status.
+/// If the key was used for another request, fails with an
+/// `UnprocessableEntity` status.
+/// A retry while the request with the key is still being handled waits for
+/// it to be answered.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
 #[post("/journey", format = "application/json", data = "<journey>")]
 pub fn create(
     journey: Json<NewJourney>,
+    user: UserInfo,
+    key: IdempotencyKey,
     conn: DbConn,
-) -> Result<status::Created<Json<TimezoneJourney>>, ErrStatus> {
+) -> Result<Idempotent<status::Created<Json<TimezoneJourney>>>, ErrStatus> {
     let journey = journey.into_inner();
-    let journey = journey::create(&conn, &journey).map_err(log_db_err)?;
+    if journey.user_id != user.id {
+        debug!("Rejected journey for user {}", journey.user_id);
+        return Err(status::Custom(Status::Forbidden, ()));
+    }
 
-    Ok(status::Created(
-        String::new(),
-        Some(Json(journey.into())),
-    ))
+    let hash = idempotency::json_request_hash("POST /journey", &journey)?;
+    let create = || {
+        journey::create(&conn, &journey)
+            .map_err(log_insert_err)
+            .and_then(|journey| localize_one(journey, &*conn))
+            .map(|journey| status::Created(String::new(), Some(Json(journey))))
+    };
+
+    key.handle(user.id, &hash, create, &*conn)
 }
 
-/// Return a Json Journey object of the journey that matches the id
+/// Return a Json Journey object of the journey that matches the id.
+/// Answers with a `NotModified` status if the client's copy is still current.
 #[get("/journey/<jid>")]
 pub fn get_by_id(
     jid: i32,
+    conditional: Conditional,
     conn: DbConn,
-) -> Result<Json<TimezoneJourney>, ErrStatus> {
+) -> Result<Cached<Json<TimezoneJourney>>, ErrStatus> {
     use db::schema::journeys::dsl::*;
 
     let journey: Journey = journeys
@@ -39,7 +90,274 @@ pub fn get_by_id(
         .first(&*conn)
         .map_err(log_db_err)?;
 
-    Ok(Json(journey.into()))
+    let modified = journey.updated_at.naive_utc();
+    let journey = localize_one(journey, &*conn)?;
+    let etag = caching::json_etag(&journey).map_err(log_err)?;
+
+    Ok(conditional.respond(
+        Some(etag),
+        Some(modified),
+        CachePolicy::Revalidate,
+        Json(journey),
+    ))
+}
+
+/// Gets the route statistics of a journey: distances travelled in total and
+/// per day, the entry furthest from the start, the number of entries, photos
+/// and days, and the bounding box of its entries. The statistics are kept
+/// until the journey or any of its entries change.
+/// If the journey does not exist, fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/journey/<jid>/stats")]
+pub fn stats(
+    jid: i32,
+    conditional: Conditional,
+    cache: State<StatsCache>,
+    conn: DbConn,
+) -> Result<Cached<Json<RouteStats>>, ErrStatus> {
+    use db::schema::journeys;
+
+    let modified = journeys::table
+        .find(jid)
+        .select(journeys::updated_at)
+        .first::<DateTime<Utc>>(&*conn)
+        .map_err(log_db_err)?;
+
+    let stats = match cache.get(jid, modified) {
+        Some(stats) => stats,
+        None => {
+            let stats = journey::stats(jid, &*conn).map_err(log_db_err)?;
+            cache.insert(jid, modified, stats.clone());
+            stats
+        }
+    };
+    let etag = caching::json_etag(&stats).map_err(log_err)?;
+
+    Ok(conditional.respond(
+        Some(etag),
+        Some(modified.naive_utc()),
+        CachePolicy::Revalidate,
+        Json(stats),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct Imported {
+    entries: Vec<TimezoneEntry>,
+    track_points: usize,
+}
+
+/// Imports a GPX file into a journey of the user. Every waypoint becomes an
+/// entry, with its name as description and its time as creation time, and
+/// track points are added to the track of the journey. Entries are located
+/// at the nearest known place.
+/// If the journey does not exist, fails with a `NotFound` status.
+/// If the file is not valid GPX, or has times outside of the journey, fails
+/// with a `BadRequest` status.
+/// If the file is too large, fails with a `PayloadTooLarge` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[post("/journey/<jid>/import/gpx", data = "<gpx>")]
+pub fn import_gpx(
+    jid: i32,
+    gpx: Data,
+    user: UserInfo,
+    gazetteer: State<Gazetteer>,
+    conn: DbConn,
+) -> Result<status::Created<Json<Imported>>, ErrStatus> {
+    use db::schema::journeys;
+
+    let journey = journeys::table
+        .find(jid)
+        .filter(journeys::user_id.eq(user.id))
+        .filter(journeys::archived.eq(false))
+        .first::<Journey>(&*conn)
+        .map_err(log_db_err)?;
+
+    let mut buf = Vec::new();
+    gpx.open()
+        .take(*MAX_IMPORT_SIZE + 1)
+        .read_to_end(&mut buf)
+        .map_err(log_err)?;
+    if buf.len() as u64 > *MAX_IMPORT_SIZE {
+        return Err(status::Custom(Status::PayloadTooLarge, ()));
+    }
+
+    let gpx = Gpx::parse(Cursor::new(buf)).map_err(|e| {
+        debug!("Rejected invalid GPX file -- {}", e);
+        status::Custom(Status::BadRequest, ())
+    })?;
+
+    let now = Utc::now();
+    let untimed = gpx.waypoints.iter().any(|w| w.time.is_none());
+    let in_journey = gpx.times().into_iter().all(|t| journey.accepts(t, now))
+        && (!untimed || journey.accepts(now, now));
+    if !in_journey {
+        debug!("Rejected GPX file with times outside of journey {}", jid);
+        return Err(status::Custom(Status::BadRequest, ()));
+    }
+
+    let (entries, track_points) =
+        track::import(jid, user.id, &gpx, now, &gazetteer, &*conn)
+            .map_err(log_db_err)?;
+
+    Ok(status::Created(
+        String::new(),
+        Some(Json(Imported {
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry, Vec::new()).into())
+                .collect(),
+            track_points,
+        })),
+    ))
+}
+
+/// Gets the track recorded along a journey, in the order it was recorded.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/journey/<jid>/track")]
+pub fn get_track(
+    jid: i32,
+    conn: DbConn,
+) -> Result<Json<Vec<TrackPoint>>, ErrStatus> {
+    let track = track::load(jid, &*conn).map_err(log_db_err)?;
+
+    Ok(Json(track))
+}
+
+/// An exported journey, downloaded as a file.
+pub struct Download {
+    filename: String,
+    format: ExportFormat,
+    export: Export,
+}
+
+impl<'r> Responder<'r> for Download {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        let (top, sub) = self.format.media_type();
+        let disposition = format!("attachment; filename=\"{}\"", self.filename);
+
+        Response::build()
+            .header(ContentType::new(top, sub))
+            .raw_header("Content-Disposition", disposition)
+            .streamed_body(self.export)
+            .ok()
+    }
+}
+
+#[derive(FromForm)]
+pub struct ExportQuery {
+    format: ExportFormat,
+}
+
+/// Exports a journey as a GPX, KML or GeoJSON file. Every entry that is not
+/// archived becomes a waypoint or feature, followed by the route through the
+/// entries in time order. Entries are loaded in batches as the file is sent,
+/// each with a connection of its own.
+/// If the journey does not exist, fails with a `NotFound` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/journey/<jid>/export?<query>", rank = 2)]
+pub fn export(
+    jid: i32,
+    query: ExportQuery,
+    pool: State<Pool>,
+    conn: DbConn,
+) -> Result<Download, ErrStatus> {
+    use db::schema::journeys;
+
+    let journey = journeys::table
+        .find(jid)
+        .first::<Journey>(&*conn)
+        .map_err(log_db_err)?;
+
+    let features = ExportedEntries {
+        journey_id: jid,
+        pool: pool.clone(),
+        after: None,
+        batch: Vec::new().into_iter(),
+        done: false,
+    };
+
+    Ok(Download {
+        filename: format!("journey-{}.{}", jid, query.format.extension()),
+        format: query.format,
+        export: Export::new(query.format, &journey.title, Box::new(features)),
+    })
+}
+
+/// Number of entries loaded at a time while exporting a journey.
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// The entries of an exported journey, loaded in batches of
+/// `EXPORT_BATCH_SIZE` after the last entry of the previous batch. A
+/// connection is only taken from the pool while a batch is loaded, so that
+/// slow downloads do not hold on to one.
+struct ExportedEntries {
+    journey_id: i32,
+    pool: Pool,
+    /// Creation time and ID of the last loaded entry.
+    after: Option<(DateTime<Utc>, i32)>,
+    batch: vec::IntoIter<Entry>,
+    done: bool,
+}
+
+impl ExportedEntries {
+    fn load_batch(&mut self) -> Result<Vec<Entry>, String> {
+        use db::schema::entries;
+
+        let conn = self.pool.get().map_err(|e| e.to_string())?;
+        let mut query = entries::table
+            .filter(entries::journey_id.eq(self.journey_id))
+            .filter(entries::archived.eq(false))
+            .into_boxed();
+        if let Some((created, id)) = self.after {
+            query = query.filter(
+                entries::created.gt(created).or(entries::created
+                    .eq(created)
+                    .and(entries::id.gt(id))),
+            );
+        }
+
+        query
+            .order((entries::created.asc(), entries::id.asc()))
+            .limit(EXPORT_BATCH_SIZE)
+            .load::<Entry>(&*conn)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Iterator for ExportedEntries {
+    type Item = io::Result<Feature>;
+
+    fn next(&mut self) -> Option<io::Result<Feature>> {
+        if let Some(entry) = self.batch.next() {
+            return Some(Ok(Feature {
+                time: entry.created,
+                point: entry.point(),
+                location: entry.location,
+                description: entry.description,
+            }));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        let batch = match self.load_batch() {
+            Ok(batch) => batch,
+            Err(e) => {
+                let id = self.journey_id;
+                error!("Failed to export journey {} -- {}", id, e);
+                self.done = true;
+                let e = io::Error::new(io::ErrorKind::Other, e);
+                return Some(Err(e));
+            }
+        };
+        self.done = (batch.len() as i64) < EXPORT_BATCH_SIZE;
+        self.after = batch.last().map(|entry| (entry.created, entry.id));
+        self.batch = batch.into_iter();
+
+        self.next()
+    }
 }
 
 /// Set a journey status to "archived", simulating deletion
@@ -57,7 +375,10 @@ pub fn delete(jid: i32, user: UserInfo, conn: DbConn) -> Result<(), ErrStatus> {
 
 /// Update the journey that matches the passed id
 #[put("/journey", format = "application/json", data = "<journey>")]
-pub fn update(journey: Json<Journey>, conn: DbConn) -> Result<(), ErrStatus> {
+pub fn update(
+    journey: Json<JourneyUpdate>,
+    conn: DbConn,
+) -> Result<(), ErrStatus> {
     use db::schema::journeys::dsl::*;
 
     let journey = journey.into_inner();
@@ -91,12 +412,9 @@ pub fn get_journeys_by_user(
         .offset(page * PAGE_SIZE)
         .limit(PAGE_SIZE)
         .get_results::<Journey>(&*conn)
-        .map_err(log_db_err)?
-        .into_iter()
-        .map(Into::into)
-        .collect();
+        .map_err(log_db_err)?;
 
-    Ok(Json(result))
+    Ok(Json(localize(result, &*conn)?))
 }
 
 /// Get the current active journey of a user
@@ -115,7 +433,7 @@ pub fn get_active_journey_by_user(
         .first::<Journey>(&*conn)
         .map_err(log_db_err)?;
 
-    Ok(Json(result.into()))
+    Ok(Json(localize_one(result, &*conn)?))
 }
 
 /// Updates the end_date field of a journey.
@@ -135,13 +453,51 @@ pub fn end(
         .filter(journeys::archived.eq(false));
 
     let result = diesel::update(target)
-        .set(journeys::end_date.eq(now.nullable()))
+        .set(journeys::end_date.eq(Some(Utc::now())))
         .get_result::<Journey>(&*conn)
         .map_err(log_db_err)?;
 
-    Ok(Json(result.into()))
+    Ok(Json(localize_one(result, &*conn)?))
 }
 
+/// Renders journeys in the time zones of their owners.
+pub(super) fn localize(
+    journeys: Vec<Journey>,
+    conn: &PgConnection,
+) -> Result<Vec<TimezoneJourney>, ErrStatus> {
+    use db::schema::users;
+
+    let owners = journeys.iter().map(|j| j.user_id).collect::<Vec<_>>();
+    let time_zones = users::table
+        .filter(users::id.eq_any(owners))
+        .select((users::id, users::time_zone))
+        .load::<(i32, String)>(conn)
+        .map_err(log_db_err)?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    Ok(journeys
+        .into_iter()
+        .map(|journey| {
+            let time_zone = time_zones
+                .get(&journey.user_id)
+                .map_or(zone::DEFAULT_TIME_ZONE, String::as_str)
+                .to_string();
+            TimezoneJourney::new(journey, time_zone)
+        })
+        .collect())
+}
+
+fn localize_one(
+    journey: Journey,
+    conn: &PgConnection,
+) -> Result<TimezoneJourney, ErrStatus> {
+    localize(vec![journey], conn)?
+        .pop()
+        .ok_or_else(|| log_err("journey lost while localizing"))
+}
+
+/// A journey with its dates in the local time of its owner, and in UTC.
 #[derive(Serialize)]
 pub struct TimezoneJourney {
     id: i32,
@@ -150,10 +506,19 @@ pub struct TimezoneJourney {
     archived: bool,
     start_date: DateTime<FixedOffset>,
     end_date: Option<DateTime<FixedOffset>>,
+    start_date_utc: DateTime<Utc>,
+    end_date_utc: Option<DateTime<Utc>>,
+    time_zone: String,
+    uuid: Uuid,
+    /// When the journey or any of its entries last changed.
+    updated_at: DateTime<Utc>,
+    /// The version of the journey, which changes only when the journey itself
+    /// does. Changes pushed by clients are based on it.
+    version: DateTime<Utc>,
 }
 
-impl From<Journey> for TimezoneJourney {
-    fn from(journey: Journey) -> Self {
+impl TimezoneJourney {
+    fn new(journey: Journey, time_zone: String) -> Self {
         let Journey {
             id,
             user_id,
@@ -161,21 +526,24 @@ impl From<Journey> for TimezoneJourney {
             archived,
             start_date,
             end_date,
+            updated_at,
+            uuid,
+            version,
         } = journey;
 
-        let hour = 3600;
-        let start_date =
-            DateTime::from_utc(start_date, FixedOffset::east(2 * hour));
-        let end_date = end_date
-            .map(|date| DateTime::from_utc(date, FixedOffset::east(2 * hour)));
-
         TimezoneJourney {
             id,
             user_id,
             title,
             archived,
-            start_date,
-            end_date,
+            start_date: zone::localize(start_date, &time_zone),
+            end_date: end_date.map(|date| zone::localize(date, &time_zone)),
+            start_date_utc: start_date,
+            end_date_utc: end_date,
+            time_zone,
+            uuid,
+            updated_at,
+            version,
         }
     }
 }
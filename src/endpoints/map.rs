@@ -0,0 +1,505 @@
+use diesel::expression::{AppearsOnTable, Expression, NonAggregate,
+                         SelectableExpression};
+use diesel::pg::{Pg, PgConnection};
+use diesel::prelude::*;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::sql_types::{BigInt, Bool, Double, Integer};
+
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::Json;
+
+use super::entry::{self, TimezoneEntry};
+use super::{log_db_err, ErrStatus, Page, PAGE_SIZE};
+use db::DbConn;
+use db::models::entry::Entry;
+use db::models::user::UserInfo;
+use db::schema::entries;
+use geo::{FormattedPoint, Point, PointFormat};
+
+/// Largest search radius, about half the circumference of the earth.
+const MAX_RADIUS_KM: f64 = 20_000.0;
+
+/// Most entries returned by a nearest query.
+const MAX_NEAREST: i64 = 100;
+
+/// Deepest zoom level of map clusters.
+const MAX_ZOOM: u32 = 20;
+
+/// Clusters along each side of a map tile.
+const CLUSTERS_PER_TILE: f64 = 4.0;
+
+/// Most grid cells a bounding box may span when clustering.
+const MAX_CLUSTER_CELLS: f64 = 10_000.0;
+
+/// The location of an entry on the earth's surface, as used by the
+/// `entries_earth` index.
+const ENTRY_EARTH: &str = "ll_to_earth(entries.latitude, entries.longitude)";
+
+/// Writes the location of a point on the earth's surface, with its
+/// coordinates bound as parameters.
+fn push_earth(point: &Point, out: &mut AstPass<Pg>) -> QueryResult<()> {
+    out.push_sql("ll_to_earth(");
+    out.push_bind_param::<Double, _>(&point.latitude)?;
+    out.push_sql(", ");
+    out.push_bind_param::<Double, _>(&point.longitude)?;
+    out.push_sql(")");
+    Ok(())
+}
+
+/// Whether an entry is located within `meters` of `center`.
+#[derive(Debug, Clone, Copy)]
+struct WithinDistance {
+    center: Point,
+    meters: f64,
+}
+
+impl Expression for WithinDistance {
+    type SqlType = Bool;
+}
+
+impl<QS> AppearsOnTable<QS> for WithinDistance {}
+
+impl<QS> SelectableExpression<QS> for WithinDistance {}
+
+impl NonAggregate for WithinDistance {}
+
+impl QueryFragment<Pg> for WithinDistance {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // the box is answered from the index, the distance check is exact
+        out.push_sql("(earth_box(");
+        push_earth(&self.center, &mut out)?;
+        out.push_sql(", ");
+        out.push_bind_param::<Double, _>(&self.meters)?;
+        out.push_sql(&format!(") @> {} AND earth_distance(", ENTRY_EARTH));
+        push_earth(&self.center, &mut out)?;
+        out.push_sql(&format!(", {}) <= ", ENTRY_EARTH));
+        out.push_bind_param::<Double, _>(&self.meters)?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+/// The distance of an entry to a point, as used for ordering by distance.
+#[derive(Debug, Clone, Copy)]
+struct DistanceTo(Point);
+
+impl Expression for DistanceTo {
+    type SqlType = Double;
+}
+
+impl<QS> AppearsOnTable<QS> for DistanceTo {}
+
+impl<QS> SelectableExpression<QS> for DistanceTo {}
+
+impl NonAggregate for DistanceTo {}
+
+impl QueryFragment<Pg> for DistanceTo {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // ordering by cube distance walks the index, and matches the order
+        // of great-circle distances
+        out.push_sql(&format!("{} <-> ", ENTRY_EARTH));
+        push_earth(&self.0, &mut out)
+    }
+}
+
+/// Whether a search radius in kilometers is positive and at most
+/// `MAX_RADIUS_KM`.
+fn is_valid_radius(radius: f64) -> bool {
+    radius > 0.0 && radius <= MAX_RADIUS_KM
+}
+
+/// The number of nearest entries to load when `k` are requested, or `None`
+/// if `k` is not positive.
+fn nearest_limit(k: i64) -> Option<i64> {
+    if k < 1 {
+        None
+    } else {
+        Some(k.min(MAX_NEAREST))
+    }
+}
+
+fn bad_request() -> ErrStatus {
+    status::Custom(Status::BadRequest, ())
+}
+
+/// The entries the caller may see that have a location: entries that are
+/// not archived, in journeys that are not archived, optionally of a single
+/// journey or user.
+fn located_entries(
+    journey: Option<i32>,
+    user: Option<i32>,
+) -> entries::BoxedQuery<'static, Pg> {
+    use db::schema::journeys;
+
+    let active_journeys = journeys::table
+        .filter(journeys::archived.eq(false))
+        .select(journeys::id);
+
+    let mut target = entries::table
+        .filter(entries::archived.eq(false))
+        .filter(entries::journey_id.eq_any(active_journeys))
+        .filter(entries::latitude.is_not_null())
+        .into_boxed();
+
+    if let Some(jid) = journey {
+        target = target.filter(entries::journey_id.eq(jid));
+    }
+
+    if let Some(uid) = user {
+        target = target.filter(entries::user_id.eq(uid));
+    }
+
+    target
+}
+
+/// Filters `target` to the entries inside the box between `south_west` and
+/// `north_east`, which crosses the antimeridian if its west edge is east of
+/// its east edge.
+fn inside_box(
+    target: entries::BoxedQuery<'static, Pg>,
+    south_west: &Point,
+    north_east: &Point,
+) -> entries::BoxedQuery<'static, Pg> {
+    let (south, west) = (south_west.latitude, south_west.longitude);
+    let (north, east) = (north_east.latitude, north_east.longitude);
+    let target = target.filter(entries::latitude.between(south, north));
+
+    if west <= east {
+        target.filter(entries::longitude.between(west, east))
+    } else {
+        target.filter(
+            entries::longitude
+                .ge(west)
+                .or(entries::longitude.le(east)),
+        )
+    }
+}
+
+#[derive(FromForm)]
+pub struct BoxQuery {
+    south: f64,
+    west: f64,
+    north: f64,
+    east: f64,
+    page: Page,
+    journey: Option<i32>,
+    user: Option<i32>,
+    geojson: Option<bool>,
+}
+
+// Note: `offset` usage here has bad performance on large page numbers
+/// Gets a page of entries located inside a bounding box, newest first.
+/// A box with its west edge east of its east edge crosses the antimeridian.
+/// If the bounding box is invalid, fails with a `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/within?<query>")]
+pub fn within(
+    query: BoxQuery,
+    _user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<Vec<TimezoneEntry>>, ErrStatus> {
+    let south_west = Point::new(query.south, query.west);
+    let north_east = Point::new(query.north, query.east);
+    if !south_west.is_valid()
+        || !north_east.is_valid()
+        || query.south > query.north
+    {
+        return Err(bad_request());
+    }
+
+    let target = located_entries(query.journey, query.user);
+    let entries = inside_box(target, &south_west, &north_east)
+        .order(entries::created.desc())
+        .offset(query.page.0 * PAGE_SIZE)
+        .limit(PAGE_SIZE)
+        .get_results::<Entry>(&*conn)
+        .map_err(log_db_err)?;
+
+    let format = PointFormat::from_flag(query.geojson);
+    Ok(Json(entry::with_attachments(entries, format, &*conn)?))
+}
+
+#[derive(FromForm)]
+pub struct RadiusQuery {
+    lat: f64,
+    lon: f64,
+    radius: f64,
+    page: Page,
+    journey: Option<i32>,
+    user: Option<i32>,
+    geojson: Option<bool>,
+}
+
+// Note: `offset` usage here has bad performance on large page numbers
+/// Gets a page of entries located within `radius` kilometers of a point,
+/// newest first.
+/// If the point or radius is invalid, fails with a `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/near?<query>")]
+pub fn near(
+    query: RadiusQuery,
+    _user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<Vec<TimezoneEntry>>, ErrStatus> {
+    let center = Point::new(query.lat, query.lon);
+    if !center.is_valid() || !is_valid_radius(query.radius) {
+        return Err(bad_request());
+    }
+
+    let within = WithinDistance {
+        center,
+        meters: query.radius * 1000.0,
+    };
+
+    let entries = located_entries(query.journey, query.user)
+        .filter(within)
+        .order(entries::created.desc())
+        .offset(query.page.0 * PAGE_SIZE)
+        .limit(PAGE_SIZE)
+        .get_results::<Entry>(&*conn)
+        .map_err(log_db_err)?;
+
+    let format = PointFormat::from_flag(query.geojson);
+    Ok(Json(entry::with_attachments(entries, format, &*conn)?))
+}
+
+#[derive(FromForm)]
+pub struct NearestQuery {
+    lat: f64,
+    lon: f64,
+    k: i64,
+    journey: Option<i32>,
+    user: Option<i32>,
+    geojson: Option<bool>,
+}
+
+/// Gets the `k` entries located nearest to a point, nearest first.
+/// At most 100 entries are returned.
+/// If the point or `k` is invalid, fails with a `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/entry/nearest?<query>")]
+pub fn nearest(
+    query: NearestQuery,
+    _user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<Vec<TimezoneEntry>>, ErrStatus> {
+    let center = Point::new(query.lat, query.lon);
+    let limit = match nearest_limit(query.k) {
+        Some(limit) if center.is_valid() => limit,
+        _ => return Err(bad_request()),
+    };
+
+    let entries = located_entries(query.journey, query.user)
+        .order(DistanceTo(center))
+        .limit(limit)
+        .get_results::<Entry>(&*conn)
+        .map_err(log_db_err)?;
+
+    let format = PointFormat::from_flag(query.geojson);
+    Ok(Json(entry::with_attachments(entries, format, &*conn)?))
+}
+
+/// Parses a bounding box given as `south,west,north,east`. The west edge is
+/// east of the east edge for boxes that cross the antimeridian.
+fn parse_bbox(bbox: &str) -> Option<(Point, Point)> {
+    let edges = bbox
+        .split(',')
+        .map(|edge| edge.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    if edges.len() != 4 {
+        return None;
+    }
+
+    let south_west = Point::new(edges[0], edges[1]);
+    let north_east = Point::new(edges[2], edges[3]);
+    if !south_west.is_valid()
+        || !north_east.is_valid()
+        || south_west.latitude > north_east.latitude
+    {
+        return None;
+    }
+
+    Some((south_west, north_east))
+}
+
+/// The size in degrees of the grid cells that are clustered at `zoom`, where
+/// zoom level 0 shows the world on a single tile.
+fn cell_size(zoom: u32) -> f64 {
+    360.0 / f64::from(1u32 << zoom) / CLUSTERS_PER_TILE
+}
+
+/// The most grid cells at `zoom` that the box between `south_west` and
+/// `north_east` can overlap.
+fn cell_count(south_west: &Point, north_east: &Point, zoom: u32) -> f64 {
+    let size = cell_size(zoom);
+    let height = north_east.latitude - south_west.latitude;
+    let mut width = north_east.longitude - south_west.longitude;
+    if width < 0.0 {
+        // crossing the antimeridian
+        width += 360.0;
+    }
+
+    ((height / size).floor() + 1.0) * ((width / size).floor() + 1.0)
+}
+
+#[derive(FromForm)]
+pub struct ClusterQuery {
+    bbox: String,
+    zoom: u32,
+    journey: Option<i32>,
+    user: Option<i32>,
+    geojson: Option<bool>,
+}
+
+/// The entries of `entries` grouped by the grid cell of `cell_size` degrees
+/// they are located in.
+struct GridClusters {
+    entries: entries::BoxedQuery<'static, Pg>,
+    cell_size: f64,
+}
+
+impl Query for GridClusters {
+    type SqlType = (BigInt, Double, Double, Integer);
+}
+
+impl QueryId for GridClusters {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl RunQueryDsl<PgConnection> for GridClusters {}
+
+impl QueryFragment<Pg> for GridClusters {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql(
+            "SELECT count(*), avg(latitude), avg(longitude), \
+             (array_agg(id ORDER BY created DESC, id DESC))[1] FROM (",
+        );
+        self.entries.walk_ast(out.reborrow())?;
+        out.push_sql(") AS located GROUP BY floor(latitude / ");
+        out.push_bind_param::<Double, _>(&self.cell_size)?;
+        out.push_sql("), floor(longitude / ");
+        out.push_bind_param::<Double, _>(&self.cell_size)?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+#[derive(Queryable)]
+struct GridCell {
+    count: i64,
+    latitude: f64,
+    longitude: f64,
+    /// The ID of the newest entry in the cell.
+    sample: i32,
+}
+
+#[derive(Serialize)]
+pub struct Cluster {
+    count: i64,
+    centroid: FormattedPoint,
+    sample_entry_id: i32,
+}
+
+/// Gets the entries located inside a bounding box given as
+/// `bbox=south,west,north,east`, clustered on a grid that gets finer with
+/// every `zoom` level. Each cluster has the number of its entries, their
+/// centroid and the ID of its most recently created entry.
+/// If the bounding box or zoom level is invalid, or the box spans more than
+/// `MAX_CLUSTER_CELLS` grid cells at the zoom level, fails with a
+/// `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/map/clusters?<query>")]
+pub fn clusters(
+    query: ClusterQuery,
+    _user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<Vec<Cluster>>, ErrStatus> {
+    let (south_west, north_east) =
+        parse_bbox(&query.bbox).ok_or_else(bad_request)?;
+    if query.zoom > MAX_ZOOM
+        || cell_count(&south_west, &north_east, query.zoom) > MAX_CLUSTER_CELLS
+    {
+        return Err(bad_request());
+    }
+
+    let target = located_entries(query.journey, query.user);
+    let clusters = GridClusters {
+        entries: inside_box(target, &south_west, &north_east),
+        cell_size: cell_size(query.zoom),
+    };
+    let cells = clusters.load::<GridCell>(&*conn).map_err(log_db_err)?;
+
+    let format = PointFormat::from_flag(query.geojson);
+    let clusters = cells
+        .into_iter()
+        .map(|cell| Cluster {
+            count: cell.count,
+            centroid: Point::new(cell.latitude, cell.longitude).format(format),
+            sample_entry_id: cell.sample,
+        })
+        .collect();
+
+    Ok(Json(clusters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounding_boxes() {
+        let (south_west, north_east) =
+            parse_bbox("48.5, 2.1,52.4,4.9").expect("invalid bbox");
+        assert_eq!(south_west, Point::new(48.5, 2.1));
+        assert_eq!(north_east, Point::new(52.4, 4.9));
+
+        assert!(parse_bbox("-10,170,10,-170").is_some());
+        assert!(parse_bbox("52.4,2.1,48.5,4.9").is_none());
+        assert!(parse_bbox("48.5,2.1,52.4").is_none());
+        assert!(parse_bbox("48.5,2.1,52.4,190").is_none());
+        assert!(parse_bbox("a,b,c,d").is_none());
+    }
+
+    #[test]
+    fn radius_validation() {
+        assert!(is_valid_radius(0.5));
+        assert!(is_valid_radius(MAX_RADIUS_KM));
+        assert!(!is_valid_radius(0.0));
+        assert!(!is_valid_radius(-1.0));
+        assert!(!is_valid_radius(MAX_RADIUS_KM + 1.0));
+        assert!(!is_valid_radius(::std::f64::NAN));
+    }
+
+    #[test]
+    fn nearest_limits() {
+        assert_eq!(nearest_limit(1), Some(1));
+        assert_eq!(nearest_limit(MAX_NEAREST + 1), Some(MAX_NEAREST));
+        assert_eq!(nearest_limit(0), None);
+        assert_eq!(nearest_limit(-3), None);
+    }
+
+    #[test]
+    fn cell_sizes() {
+        assert_eq!(cell_size(0), 90.0);
+        assert_eq!(cell_size(2), 22.5);
+        assert!(cell_size(MAX_ZOOM) > 0.0);
+    }
+
+    #[test]
+    fn cell_counts() {
+        let world = (Point::new(-90.0, -180.0), Point::new(90.0, 180.0));
+        assert_eq!(cell_count(&world.0, &world.1, 0), 15.0);
+        assert!(cell_count(&world.0, &world.1, 5) <= MAX_CLUSTER_CELLS);
+        assert!(cell_count(&world.0, &world.1, 6) > MAX_CLUSTER_CELLS);
+
+        let antimeridian = (Point::new(-10.0, 170.0), Point::new(10.0, -170.0));
+        assert_eq!(
+            cell_count(&antimeridian.0, &antimeridian.1, 2),
+            cell_count(&Point::new(-10.0, 0.0), &Point::new(10.0, 20.0), 2)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::env;
+use std::io::Cursor;
+use std::time::Duration;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{Outcome, Request};
+
+use storage::{Blob, BlobBody};
+
+lazy_static! {
+    /// Whether media downloads redirect to presigned storage URLs, instead of
+    /// being streamed through the server. Set with `MEDIA_REDIRECTS=true`.
+    pub static ref MEDIA_REDIRECTS: bool = env::var("MEDIA_REDIRECTS")
+        .map(|redirect| redirect == "true")
+        .unwrap_or(false);
+    /// How long presigned storage URLs stay valid, defaults to 5 minutes.
+    pub static ref PRESIGNED_URL_TTL: Duration = Duration::from_secs(
+        env::var("PRESIGNED_URL_TTL")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(300),
+    );
+}
+
+/// A byte range `start..=end` of a media object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The `Range` header of a request, if any.
+pub struct RangeHeader(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RangeHeader {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let range = request.headers().get_one("Range").map(Into::into);
+        Outcome::Success(RangeHeader(range))
+    }
+}
+
+impl RangeHeader {
+    /// Whether the request has a `Range` header.
+    pub fn is_requested(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Resolves the requested range against an object of `total` bytes.
+    /// Returns `Ok(None)` if the full object should be served, either because
+    /// no range was requested or because the header is invalid or requests
+    /// multiple ranges. Fails if the range cannot be satisfied.
+    pub fn resolve(&self, total: u64) -> Result<Option<ByteRange>, ()> {
+        let spec = match self.0 {
+            Some(ref header) if header.starts_with("bytes=") => &header[6..],
+            _ => return Ok(None),
+        };
+
+        if spec.contains(',') {
+            return Ok(None);
+        }
+
+        let mut bounds = spec.splitn(2, '-').map(str::trim);
+        let (first, last) = match (bounds.next(), bounds.next()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(None),
+        };
+
+        if first.is_empty() {
+            // suffix range of the last bytes
+            return match last.parse::<u64>() {
+                Ok(0) => Err(()),
+                Ok(_) if total == 0 => Err(()),
+                Ok(len) => Ok(Some(ByteRange {
+                    start: total.saturating_sub(len),
+                    end: total - 1,
+                })),
+                Err(_) => Ok(None),
+            };
+        }
+
+        let start = match first.parse::<u64>() {
+            Ok(start) if start >= total => return Err(()),
+            Ok(start) => start,
+            Err(_) => return Ok(None),
+        };
+
+        let end = match last {
+            "" => total - 1,
+            last => match last.parse::<u64>() {
+                Ok(end) if end >= start => end.min(total - 1),
+                _ => return Ok(None),
+            },
+        };
+
+        Ok(Some(ByteRange { start, end }))
+    }
+}
+
+/// A media download. Supports partial content, as announced with the
+/// `Accept-Ranges` header, and redirects to presigned storage URLs.
+pub enum Media {
+    Redirect(String),
+    Full(Blob),
+    Partial {
+        blob: Blob,
+        range: ByteRange,
+        total: u64,
+    },
+    Unsatisfiable { total: u64 },
+}
+
+fn content_type(blob: &Blob) -> ContentType {
+    blob.content_type
+        .as_ref()
+        .and_then(|t| ContentType::parse_flexible(t))
+        .unwrap_or(ContentType::Binary)
+}
+
+impl<'r> Responder<'r> for Media {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+
+        match self {
+            Media::Redirect(url) => {
+                response
+                    .status(Status::Found)
+                    .header(Header::new("Location", url));
+            }
+            Media::Full(blob) => {
+                response
+                    .raw_header("Accept-Ranges", "bytes")
+                    .header(content_type(&blob))
+                    .streamed_body::<BlobBody>(blob.body);
+            }
+            Media::Partial { blob, range, total } => {
+                let content_range =
+                    format!("bytes {}-{}/{}", range.start, range.end, total);
+                let length = range.end - range.start + 1;
+                // streamed bodies are sent chunked unless their length is set
+                response
+                    .status(Status::PartialContent)
+                    .raw_header("Accept-Ranges", "bytes")
+                    .raw_header("Content-Length", length.to_string())
+                    .header(content_type(&blob))
+                    .header(Header::new("Content-Range", content_range))
+                    .streamed_body::<BlobBody>(blob.body);
+            }
+            Media::Unsatisfiable { total } => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Accept-Ranges", "bytes")
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes */{}", total),
+                    ))
+                    .sized_body(Cursor::new(""));
+            }
+        }
+
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(header: &str, total: u64) -> Result<Option<(u64, u64)>, ()> {
+        RangeHeader(Some(header.to_string()))
+            .resolve(total)
+            .map(|r| r.map(|r| (r.start, r.end)))
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(resolve("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(resolve("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(resolve("bytes=900-2000", 1000), Ok(Some((900, 999))));
+        assert_eq!(resolve("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(resolve("bytes=-2000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn ignored_ranges() {
+        assert_eq!(RangeHeader(None).resolve(1000), Ok(None));
+        assert_eq!(resolve("items=0-10", 1000), Ok(None));
+        assert_eq!(resolve("bytes=0-10,20-30", 1000), Ok(None));
+        assert_eq!(resolve("bytes=10-5", 1000), Ok(None));
+        assert_eq!(resolve("bytes=a-b", 1000), Ok(None));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(resolve("bytes=1000-", 1000), Err(()));
+        assert_eq!(resolve("bytes=-0", 1000), Err(()));
+        assert_eq!(resolve("bytes=0-", 0), Err(()));
+    }
+}
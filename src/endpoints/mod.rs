@@ -1,13 +1,21 @@
 use std::fmt::Debug;
 
-use diesel::result::Error;
+use diesel::result::{DatabaseErrorKind, Error};
 use rocket::http::RawStr;
 use rocket::http::Status;
 use rocket::request::FromFormValue;
 use rocket::response::status;
 
+use storage::StorageError;
+
+pub mod attachment;
+pub mod caching;
 pub mod entry;
+pub mod idempotency;
 pub mod journey;
+pub mod map;
+pub mod media;
+pub mod sync;
 pub mod user;
 
 type ErrStatus = status::Custom<()>;
@@ -29,6 +37,34 @@ fn log_db_err(e: Error) -> ErrStatus {
     }
 }
 
+/// Logs a diesel error of an insert like `log_db_err`.
+/// If the row violates a unique constraint, such as a UUID that is taken,
+/// returns a `Conflict` status.
+fn log_insert_err(e: Error) -> ErrStatus {
+    match e {
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            debug!("Rejected row violating a unique constraint");
+            status::Custom(Status::Conflict, ())
+        }
+        e => log_db_err(e),
+    }
+}
+
+/// Logs a storage error with error priority.
+/// If the object was not found, returns a `NotFound` status.
+/// If the object exceeded its size limit, returns a `PayloadTooLarge` status.
+/// Else, returns an `InternalServiceError` status.
+fn log_storage_err(e: StorageError) -> ErrStatus {
+    match e {
+        StorageError::NotFound => status::Custom(Status::NotFound, ()),
+        StorageError::TooLarge => {
+            debug!("Rejected object exceeding size limit");
+            status::Custom(Status::PayloadTooLarge, ())
+        }
+        e => log_err(e),
+    }
+}
+
 const PAGE_SIZE: i64 = 10;
 
 struct Page(i64);
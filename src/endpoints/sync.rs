@@ -0,0 +1,364 @@
+use std::cmp;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use diesel::prelude::*;
+use rocket::State;
+use rocket::http::{RawStr, Status};
+use rocket::request::FromFormValue;
+use rocket::response::status;
+use rocket_contrib::Json;
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+use super::entry::{self, TimezoneEntry};
+use super::journey::{self, TimezoneJourney};
+use super::{log_db_err, ErrStatus};
+use db::DbConn;
+use db::models::attachment::Attachment;
+use db::models::sync::{self, Applied, Conflict, Deletion, EntryChange,
+                       JourneyChange};
+use db::models::user::UserInfo;
+use geo::PointFormat;
+use geo::gazetteer::Gazetteer;
+
+/// Changes are stamped with the start of their transaction, so a change that
+/// commits after a sync may be older than changes that sync returned.
+/// Changes more recent than this many seconds are therefore returned again
+/// by the next sync.
+const COMMIT_MARGIN_SECS: i64 = 60;
+
+/// Most rows of each kind returned by a sync.
+const MAX_SYNC_LIMIT: i64 = 500;
+
+/// The point up to which a client has synced. Clients treat it as opaque; it
+/// holds the microseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor(DateTime<Utc>);
+
+impl Cursor {
+    /// The cursor after syncing the changes up to `latest`, when syncing at
+    /// `now` from `since`. Changes that may not have been committed yet are
+    /// left for the next sync.
+    fn next(
+        since: Option<Cursor>,
+        latest: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let settled = now - Duration::seconds(COMMIT_MARGIN_SECS);
+        let cursor = latest.map_or(settled, |latest| cmp::min(latest, settled));
+
+        Cursor(since.map_or(cursor, |since| cmp::max(since.0, cursor)))
+    }
+}
+
+impl<'v> FromFormValue<'v> for Cursor {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, &'v RawStr> {
+        let micros = match form_value.parse::<i64>() {
+            Ok(micros) if micros >= 0 => micros,
+            _ => return Err(form_value),
+        };
+        let nanos = (micros % 1_000_000) as u32 * 1000;
+
+        Utc.timestamp_opt(micros / 1_000_000, nanos)
+            .single()
+            .map(Cursor)
+            .ok_or(form_value)
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let micros = self.0.timestamp() * 1_000_000
+            + i64::from(self.0.timestamp_subsec_micros());
+        serializer.collect_str(&micros)
+    }
+}
+
+/// A row that was deleted or archived, which clients are to remove.
+#[derive(Serialize, Debug)]
+pub struct Tombstone {
+    id: i32,
+    uuid: Uuid,
+    deleted: bool,
+    version: DateTime<Utc>,
+}
+
+impl Tombstone {
+    fn new(id: i32, uuid: Uuid, version: DateTime<Utc>) -> Self {
+        Tombstone {
+            id,
+            uuid,
+            deleted: true,
+            version,
+        }
+    }
+}
+
+impl<'a> From<&'a Deletion> for Tombstone {
+    fn from(deletion: &'a Deletion) -> Self {
+        Tombstone::new(deletion.row_id, deletion.uuid, deletion.deleted_at)
+    }
+}
+
+/// A row that changed, or its tombstone.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Change<T> {
+    Updated(T),
+    Deleted(Tombstone),
+}
+
+#[derive(Serialize)]
+pub struct SyncChanges {
+    /// The cursor to sync from next time.
+    cursor: Cursor,
+    /// Whether changes were left out, which are synced from the cursor right
+    /// away.
+    more: bool,
+    journeys: Vec<Change<TimezoneJourney>>,
+    entries: Vec<Change<TimezoneEntry>>,
+    attachments: Vec<Change<Attachment>>,
+}
+
+#[derive(FromForm)]
+pub struct SyncQuery {
+    since: Option<Cursor>,
+    limit: Option<i64>,
+}
+
+/// The number of rows of each kind to sync when `limit` are requested, or
+/// `None` if `limit` is not positive.
+fn sync_limit(limit: Option<i64>) -> Option<i64> {
+    match limit {
+        Some(limit) if limit < 1 => None,
+        Some(limit) => Some(limit.min(MAX_SYNC_LIMIT)),
+        None => Some(MAX_SYNC_LIMIT),
+    }
+}
+
+/// Gets the journeys, entries and attachments of the user that were created,
+/// updated or archived since the cursor of the previous sync. Archived and
+/// deleted rows come back as tombstones. Without a cursor, gets all of them
+/// that are not archived.
+/// At most `limit` rows of each kind are returned, up to `MAX_SYNC_LIMIT`.
+/// If changes were left out, `more` is set and the rest is synced from the
+/// returned cursor.
+/// If the cursor or limit is not valid, fails with a `BadRequest` status.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/sync?<query>", rank = 1)]
+pub fn changes(
+    query: SyncQuery,
+    user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<SyncChanges>, ErrStatus> {
+    let limit = sync_limit(query.limit)
+        .ok_or_else(|| status::Custom(Status::BadRequest, ()))?;
+
+    load_changes(query.since, limit, &user, &*conn)
+}
+
+/// Gets all journeys, entries and attachments of the user that are not
+/// archived, along with the cursor to sync changes from.
+/// At most `MAX_SYNC_LIMIT` rows of each kind are returned. If rows were
+/// left out, `more` is set and the rest is synced from the returned cursor.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/sync", rank = 2)]
+pub fn full(
+    user: UserInfo,
+    conn: DbConn,
+) -> Result<Json<SyncChanges>, ErrStatus> {
+    load_changes(None, MAX_SYNC_LIMIT, &user, &*conn)
+}
+
+fn load_changes(
+    since: Option<Cursor>,
+    limit: i64,
+    user: &UserInfo,
+    conn: &PgConnection,
+) -> Result<Json<SyncChanges>, ErrStatus> {
+    let now = Utc::now();
+    let changes =
+        sync::changes(user.id, since.map(|cursor| cursor.0), limit, conn)
+            .map_err(log_db_err)?;
+    let cursor = Cursor::next(since, changes.latest(), now);
+    // changes that may not be committed yet are left for the next sync, so
+    // the client is not to sync again right away unless the cursor moved
+    let more = changes.until.is_some() && Some(cursor) != since;
+
+    let sync::Changes {
+        journeys,
+        entries,
+        attachments,
+        deletions,
+        ..
+    } = changes;
+
+    let (archived, journeys): (Vec<_>, Vec<_>) =
+        journeys.into_iter().partition(|journey| journey.archived);
+    let mut journeys = journey::localize(journeys, conn)?
+        .into_iter()
+        .map(Change::Updated)
+        .collect::<Vec<_>>();
+    journeys.extend(archived.iter().map(|journey| {
+        Change::Deleted(Tombstone::new(
+            journey.id,
+            journey.uuid,
+            journey.version,
+        ))
+    }));
+
+    let (archived, entries): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|entry| entry.archived);
+    let mut entries =
+        entry::with_attachments(entries, PointFormat::Plain, conn)?
+            .into_iter()
+            .map(Change::Updated)
+            .collect::<Vec<_>>();
+    entries.extend(archived.iter().map(|entry| {
+        Change::Deleted(Tombstone::new(entry.id, entry.uuid, entry.version))
+    }));
+
+    let mut attachments = attachments
+        .into_iter()
+        .map(Change::Updated)
+        .collect::<Vec<_>>();
+
+    for deletion in &deletions {
+        let tombstone = Change::Deleted(Tombstone::from(deletion));
+        match deletion.kind.as_str() {
+            "journeys" => journeys.push(tombstone),
+            "entries" => entries.push(tombstone),
+            "attachments" => attachments.push(tombstone),
+            kind => warn!("Deletion {} of unknown kind {}", deletion.id, kind),
+        }
+    }
+
+    Ok(Json(SyncChanges {
+        cursor,
+        more,
+        journeys,
+        entries,
+        attachments,
+    }))
+}
+
+/// Journeys and entries that were changed on a client.
+#[derive(Deserialize)]
+pub struct SyncPush {
+    #[serde(default)]
+    journeys: Vec<JourneyChange>,
+    #[serde(default)]
+    entries: Vec<EntryChange>,
+}
+
+#[derive(Serialize)]
+pub struct PushConflict {
+    uuid: Uuid,
+    conflict: Conflict,
+}
+
+#[derive(Serialize, Default)]
+pub struct PushResult {
+    applied: Vec<Applied>,
+    conflicts: Vec<PushConflict>,
+}
+
+impl PushResult {
+    fn add(&mut self, uuid: Uuid, outcome: Result<Applied, Conflict>) {
+        match outcome {
+            Ok(applied) => self.applied.push(applied),
+            Err(conflict) => {
+                debug!("Change to {} conflicts -- {:?}", uuid, conflict);
+                self.conflicts.push(PushConflict { uuid, conflict });
+            }
+        }
+    }
+}
+
+/// Applies journeys and entries that were created or changed on a client,
+/// identified by UUIDs the client may generate. Journeys are applied before
+/// entries, so entries can be pushed along with their new journey.
+/// A change is only applied if its row did not change since the version the
+/// change was based on. Changes that are not applied are reported as
+/// conflicts, while the other changes are applied.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[post("/sync", format = "application/json", data = "<push>")]
+pub fn push(
+    push: Json<SyncPush>,
+    user: UserInfo,
+    gazetteer: State<Gazetteer>,
+    conn: DbConn,
+) -> Result<Json<PushResult>, ErrStatus> {
+    let push = push.into_inner();
+    let mut result = PushResult::default();
+
+    for change in &push.journeys {
+        let outcome =
+            sync::apply_journey(user.id, change, &*conn).map_err(log_db_err)?;
+        result.add(change.uuid, outcome);
+    }
+
+    for change in &push.entries {
+        let outcome = sync::apply_entry(user.id, change, &gazetteer, &*conn)
+            .map_err(log_db_err)?;
+        result.add(change.uuid, outcome);
+    }
+
+    info!(
+        "Applied {} changes of user {}, {} conflicts",
+        result.applied.len(),
+        user.id,
+        result.conflicts.len()
+    );
+
+    Ok(Json(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn cursors() {
+        let time = Utc.ymd(2018, 5, 4).and_hms_micro(9, 30, 0, 123_456);
+        let cursor = Cursor(time);
+
+        let json = serde_json::to_string(&cursor).expect("failed to serialize");
+        assert_eq!(json, "\"1525426200123456\"");
+
+        let value = RawStr::from_str("1525426200123456");
+        assert_eq!(Cursor::from_form_value(value), Ok(cursor));
+        assert!(Cursor::from_form_value(RawStr::from_str("-1")).is_err());
+        assert!(Cursor::from_form_value(RawStr::from_str("soon")).is_err());
+    }
+
+    #[test]
+    fn next_cursor() {
+        let now = Utc.ymd(2018, 5, 4).and_hms(12, 0, 0);
+        let settled = now - Duration::seconds(COMMIT_MARGIN_SECS);
+        let earlier = now - Duration::hours(1);
+        let since = Some(Cursor(now - Duration::days(1)));
+
+        assert_eq!(Cursor::next(since, Some(earlier), now), Cursor(earlier));
+        assert_eq!(Cursor::next(since, Some(now), now), Cursor(settled));
+        assert_eq!(Cursor::next(since, None, now), Cursor(settled));
+        assert_eq!(Cursor::next(None, None, now), Cursor(settled));
+
+        let recent = Some(Cursor(now - Duration::seconds(10)));
+        assert_eq!(Cursor::next(recent, None, now), recent.unwrap());
+    }
+
+    #[test]
+    fn sync_limits() {
+        assert_eq!(sync_limit(None), Some(MAX_SYNC_LIMIT));
+        assert_eq!(sync_limit(Some(20)), Some(20));
+        assert_eq!(sync_limit(Some(MAX_SYNC_LIMIT + 1)), Some(MAX_SYNC_LIMIT));
+        assert_eq!(sync_limit(Some(0)), None);
+    }
+}
@@ -8,14 +8,20 @@ use rand::Rng;
 use rand::os::OsRng;
 use rocket::http::Status;
 use rocket::response::status;
+use rocket::State;
 use rocket_contrib::Json;
 use sendgrid::mail::Mail;
 use sendgrid::sg_client::SGClient;
 
+use super::attachment::STORAGE_QUOTA;
 use super::{log_db_err, log_err, ErrStatus};
 use db::DbConn;
+use db::models::attachment;
+use db::models::journey::Journey;
 use db::models::user::{self, NewUser, User, UserInfo};
 use endpoints::{Page, PAGE_SIZE};
+use media::lifecycle;
+use storage::Storage;
 
 /// Registers a new user.
 /// If the username or email is taken, fails with a `BadRequest` status.
@@ -59,11 +65,19 @@ pub fn update(
     Ok(token)
 }
 
-/// Deletes a user, along with all its journeys and entries.
+/// Deletes a user, along with all its journeys and entries, and their stored
+/// media.
 /// If an unexpected errors occur, fails with an `InternalServiceError` status.
 #[delete("/user")]
-pub fn delete(user: UserInfo, conn: DbConn) -> Result<(), ErrStatus> {
-    user::delete(user, &*conn).map_err(log_db_err)
+pub fn delete(
+    user: UserInfo,
+    storage: State<Storage>,
+    conn: DbConn,
+) -> Result<(), ErrStatus> {
+    let keys = user::delete(user, &*conn).map_err(log_db_err)?;
+    lifecycle::delete_all(&storage, &keys);
+
+    Ok(())
 }
 
 /// Login details of a user
@@ -169,6 +183,47 @@ pub fn get_by_id(
     Ok(Json(user.into()))
 }
 
+#[derive(Serialize)]
+pub struct JourneyUsage {
+    journey_id: i32,
+    title: String,
+    bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    quota: u64,
+    bytes: i64,
+    journeys: Vec<JourneyUsage>,
+}
+
+/// Gets the bytes of media stored by the user, in total and per journey,
+/// along with the storage quota of the user.
+/// If an unexpected error occurs, fails with an `InternalServiceError` status.
+#[get("/user/usage")]
+pub fn usage(user: UserInfo, conn: DbConn) -> Result<Json<Usage>, ErrStatus> {
+    use db::schema::journeys;
+
+    let usage = attachment::usage(user.id, &*conn).map_err(log_db_err)?;
+    let journeys = Journey::belonging_to(&user)
+        .order(journeys::start_date.desc())
+        .load::<Journey>(&*conn)
+        .map_err(log_db_err)?
+        .into_iter()
+        .map(|journey| JourneyUsage {
+            journey_id: journey.id,
+            bytes: usage.get(&journey.id).cloned().unwrap_or(0),
+            title: journey.title,
+        })
+        .collect();
+
+    Ok(Json(Usage {
+        quota: *STORAGE_QUOTA,
+        bytes: usage.values().sum(),
+        journeys,
+    }))
+}
+
 #[derive(FromForm)]
 pub struct UserQuery {
     page: Page,
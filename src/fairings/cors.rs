@@ -0,0 +1,198 @@
+use std::env;
+use std::str::FromStr;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+
+/// The origins that are allowed to make cross-origin requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    All,
+    Some(Vec<String>),
+}
+
+impl AllowedOrigins {
+    fn allows(&self, origin: &str) -> bool {
+        match *self {
+            AllowedOrigins::All => true,
+            AllowedOrigins::Some(ref origins) => {
+                origins.iter().any(|o| o == origin)
+            }
+        }
+    }
+}
+
+/// Fairing that adds CORS headers to responses, and answers preflight
+/// requests for every mounted route.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    allowed_origins: AllowedOrigins,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<String>,
+    max_age: u32,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        use rocket::http::Method::*;
+
+        Cors {
+            allowed_origins: AllowedOrigins::All,
+            allowed_methods: vec![Get, Post, Put, Delete, Options],
+            allowed_headers: vec![
+                "Authorization".to_string(),
+                "Content-Type".to_string(),
+                "Accept".to_string(),
+                "Idempotency-Key".to_string(),
+                "Range".to_string(),
+                "If-None-Match".to_string(),
+                "If-Modified-Since".to_string(),
+            ],
+            max_age: 86_400,
+        }
+    }
+}
+
+/// Response headers that browser clients may read, besides the simple ones.
+const EXPOSED_HEADERS: &str = "Location, Idempotent-Replayed, ETag, \
+                               Last-Modified, Content-Range, Accept-Ranges";
+
+/// Splits a comma separated list, ignoring empty items.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+impl Cors {
+    /// Reads the CORS configuration from the environment.
+    /// `CORS_ALLOWED_ORIGINS`, `CORS_ALLOWED_METHODS` and
+    /// `CORS_ALLOWED_HEADERS` are comma separated lists, an origin of `*`
+    /// allows all origins. Unset variables fall back to the defaults.
+    pub fn from_env() -> Self {
+        let mut cors = Cors::default();
+
+        if let Ok(origins) = env::var("CORS_ALLOWED_ORIGINS") {
+            let origins = split_list(&origins);
+            cors.allowed_origins = if origins.iter().any(|o| o == "*") {
+                AllowedOrigins::All
+            } else {
+                AllowedOrigins::Some(origins)
+            };
+        }
+
+        if let Ok(methods) = env::var("CORS_ALLOWED_METHODS") {
+            cors.allowed_methods = split_list(&methods)
+                .iter()
+                .filter_map(|m| Method::from_str(&m.to_uppercase()).ok())
+                .collect();
+        }
+
+        if let Ok(headers) = env::var("CORS_ALLOWED_HEADERS") {
+            cors.allowed_headers = split_list(&headers);
+            let has_auth = cors.allowed_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case("Authorization"));
+            if !has_auth {
+                cors.allowed_headers.push("Authorization".to_string());
+            }
+        }
+
+        if let Some(max_age) = env::var("CORS_MAX_AGE")
+            .ok()
+            .and_then(|age| age.parse().ok())
+        {
+            cors.max_age = max_age;
+        }
+
+        cors
+    }
+
+    fn allowed_methods(&self) -> String {
+        self.allowed_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) if self.allowed_origins.allows(origin) => origin,
+            _ => return,
+        };
+
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        response.adjoin_raw_header("Vary", "Origin");
+
+        let requested_method = request
+            .headers()
+            .get_one("Access-Control-Request-Method")
+            .and_then(|m| Method::from_str(m).ok());
+
+        let requested_method = match requested_method {
+            Some(m) if request.method() == Method::Options => m,
+            _ => {
+                response.set_raw_header(
+                    "Access-Control-Expose-Headers",
+                    EXPOSED_HEADERS,
+                );
+                return;
+            }
+        };
+
+        if !self.allowed_methods.contains(&requested_method) {
+            debug!("Rejected preflight for method {}", requested_method);
+            return;
+        }
+
+        debug!("Answering preflight request: {}", request);
+        response.set_status(Status::NoContent);
+        response.take_body();
+        response.remove_header("Content-Type");
+        response.set_header(Header::new(
+            "Access-Control-Allow-Methods",
+            self.allowed_methods(),
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Headers",
+            self.allowed_headers.join(", "),
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Max-Age",
+            self.max_age.to_string(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_origins() {
+        let origins = AllowedOrigins::Some(split_list(
+            "https://journaloo.com, ,http://localhost:3000",
+        ));
+
+        assert!(origins.allows("https://journaloo.com"));
+        assert!(origins.allows("http://localhost:3000"));
+        assert!(!origins.allows("https://evil.com"));
+        assert!(AllowedOrigins::All.allows("https://evil.com"));
+    }
+}
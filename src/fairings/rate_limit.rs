@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Data, Outcome, Request, Response};
+
+use db::models::user::UserInfo;
+
+/// Internal header used to carry the retry delay of a limited request from
+/// `on_request` to `on_response`.
+const LIMITED_HEADER: &str = "X-Journaloo-Rate-Limited";
+
+/// Route that no handler is mounted on, limited requests are rerouted here
+/// so their handler never runs.
+const LIMITED_URI: &str = "/__rate_limited";
+
+/// How often the in-memory backend drops buckets that have been refilled.
+const SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// A token bucket configuration: at most `capacity` requests in a burst,
+/// refilled at a rate of `capacity` tokens per `period`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub fn new(capacity: u32, period_secs: u64) -> Self {
+        Limit {
+            capacity,
+            period: Duration::from_secs(period_secs),
+        }
+    }
+
+    /// Parses a limit of the form `<capacity>/<period in seconds>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().splitn(2, '/');
+        let capacity = parts.next()?.trim().parse().ok()?;
+        let period = parts.next()?.trim().parse().ok()?;
+
+        if capacity == 0 || period == 0 {
+            return None;
+        }
+
+        Some(Limit::new(capacity, period))
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.capacity) / as_secs_f64(self.period)
+    }
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// The period of the limit of the bucket, after which it is full again.
+    period: Duration,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(limit.capacity),
+            last: now,
+            period: limit.period,
+        }
+    }
+
+    /// Whether the bucket has been refilled completely at `now`.
+    fn refilled(&self, now: Instant) -> bool {
+        now.duration_since(self.last) >= self.period
+    }
+
+    /// Takes a token from the bucket.
+    /// If the bucket is empty, returns the time until a token is available.
+    fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        let elapsed = as_secs_f64(now.duration_since(self.last));
+        let capacity = f64::from(limit.capacity);
+        self.tokens =
+            (self.tokens + elapsed * limit.refill_per_sec()).min(capacity);
+        self.last = now;
+        self.period = limit.period;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / limit.refill_per_sec();
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+/// Storage for token buckets.
+pub trait RateLimitBackend: Send + Sync {
+    /// Takes a token from the bucket identified by `key`.
+    /// If the bucket is empty, returns the time until a token is available.
+    fn acquire(&self, key: &str, limit: &Limit) -> Result<(), Duration>;
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    last_sweep: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drops the buckets that have been refilled completely, at most once
+    /// per sweep interval.
+    fn sweep(&mut self, now: Instant) {
+        let interval = Duration::from_secs(SWEEP_INTERVAL_SECS);
+        match self.last_sweep {
+            Some(last) if now.duration_since(last) < interval => return,
+            Some(_) => self.buckets.retain(|_, b| !b.refilled(now)),
+            None => (),
+        }
+        self.last_sweep = Some(now);
+    }
+}
+
+/// Keeps token buckets in process memory.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimitBackend for MemoryBackend {
+    fn acquire(&self, key: &str, limit: &Limit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets
+            .lock()
+            .expect("rate limit buckets poisoned");
+
+        buckets.sweep(now);
+        buckets
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now)
+    }
+}
+
+/// A group of routes that share a rate limit.
+/// Segments of `path` equal to `*` match any single segment.
+#[derive(Debug, Clone)]
+pub struct RouteGroup {
+    pub name: &'static str,
+    pub method: Method,
+    pub path: &'static str,
+    pub limit: Limit,
+}
+
+impl RouteGroup {
+    fn matches(&self, method: Method, path: &str) -> bool {
+        if method != self.method {
+            return false;
+        }
+
+        let mut pattern = self.path.split('/').filter(|s| !s.is_empty());
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+
+        loop {
+            match (pattern.next(), segments.next()) {
+                (None, None) => return true,
+                (Some("*"), Some(_)) => (),
+                (Some(p), Some(s)) if p == s => (),
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// The address of the client that connected to the proxy in front of the
+/// app, which is the last hop the proxy added to `X-Forwarded-For`.
+fn forwarded_client(forwarded_for: &str) -> Option<IpAddr> {
+    forwarded_for.rsplit(',').next()?.trim().parse().ok()
+}
+
+/// Fairing that limits how often clients may call expensive routes.
+/// Clients are identified by their user ID when authenticated, and by their
+/// IP address otherwise. Limited requests get a `TooManyRequests` status with
+/// a `Retry-After` header.
+pub struct RateLimiter {
+    groups: Vec<RouteGroup>,
+    backend: Box<RateLimitBackend>,
+    /// Whether the app runs behind a proxy, such as the Heroku router, so
+    /// that the IP address of a client is taken from `X-Forwarded-For`.
+    trust_proxy: bool,
+}
+
+impl RateLimiter {
+    pub fn new<B>(groups: Vec<RouteGroup>, backend: B) -> Self
+    where
+        B: RateLimitBackend + 'static,
+    {
+        RateLimiter {
+            groups,
+            backend: Box::new(backend),
+            trust_proxy: false,
+        }
+    }
+
+    /// Takes the IP address of clients from the `X-Forwarded-For` header
+    /// added by the proxy in front of the app.
+    pub fn trusting_proxy(mut self) -> Self {
+        self.trust_proxy = true;
+        self
+    }
+
+    /// Creates an in-memory rate limiter for the default route groups.
+    /// The limit of a group can be overridden with a
+    /// `RATE_LIMIT_<GROUP>=<capacity>/<period in seconds>` variable.
+    /// Setting `TRUST_PROXY=true` takes client addresses from the
+    /// `X-Forwarded-For` header, which is only safe behind a proxy that
+    /// appends to it, such as the Heroku router.
+    pub fn from_env() -> Self {
+        let mut groups = vec![
+            RouteGroup {
+                name: "signup",
+                method: Method::Post,
+                path: "/user",
+                limit: Limit::new(5, 3600),
+            },
+            RouteGroup {
+                name: "reset_password",
+                method: Method::Put,
+                path: "/user/*/reset",
+                limit: Limit::new(3, 3600),
+            },
+            RouteGroup {
+                name: "image_upload",
+                method: Method::Post,
+                path: "/entry/*/image",
+                limit: Limit::new(30, 60),
+            },
+            RouteGroup {
+                name: "attachment_upload",
+                method: Method::Post,
+                path: "/entry/*/attachment",
+                limit: Limit::new(30, 60),
+            },
+            RouteGroup {
+                name: "direct_upload",
+                method: Method::Post,
+                path: "/entry/*/attachment/upload",
+                limit: Limit::new(30, 60),
+            },
+        ];
+
+        for group in &mut groups {
+            let var = format!("RATE_LIMIT_{}", group.name.to_uppercase());
+            if let Ok(value) = env::var(&var) {
+                match Limit::parse(&value) {
+                    Some(limit) => group.limit = limit,
+                    None => warn!("Ignoring invalid {}: {}", var, value),
+                }
+            }
+        }
+
+        let limiter = RateLimiter::new(groups, MemoryBackend::default());
+        match env::var("TRUST_PROXY") {
+            Ok(ref trust) if trust == "true" => limiter.trusting_proxy(),
+            _ => limiter,
+        }
+    }
+
+    /// Identifies the client of a request.
+    fn client_key(&self, request: &Request) -> Option<String> {
+        if let Outcome::Success(user) = request.guard::<UserInfo>() {
+            return Some(format!("user:{}", user.id));
+        }
+
+        let forwarded = if self.trust_proxy {
+            request
+                .headers()
+                .get("X-Forwarded-For")
+                .last()
+                .and_then(forwarded_client)
+        } else {
+            None
+        };
+
+        forwarded
+            .or_else(|| request.remote().map(|addr| addr.ip()))
+            .map(|ip| format!("ip:{}", ip))
+    }
+}
+
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _data: &Data) {
+        let path = request.uri().path().to_string();
+        let group = match self.groups
+            .iter()
+            .find(|g| g.matches(request.method(), &path))
+        {
+            Some(group) => group,
+            None => return,
+        };
+
+        let client = match self.client_key(request) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let key = format!("{}:{}", group.name, client);
+        if let Err(retry_after) = self.backend.acquire(&key, &group.limit) {
+            let secs = retry_after.as_secs() + 1;
+            debug!("Rate limited {} for {}s: {}", key, secs, request);
+            request.replace_header(Header::new(
+                LIMITED_HEADER,
+                secs.to_string(),
+            ));
+            request.set_uri(LIMITED_URI);
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if request.uri().path() != LIMITED_URI {
+            return;
+        }
+
+        if let Some(secs) = request.headers().get_one(LIMITED_HEADER) {
+            response.set_status(Status::TooManyRequests);
+            response.take_body();
+            response.remove_header("Content-Type");
+            response.set_header(Header::new("Retry-After", secs.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limit() {
+        assert_eq!(Limit::parse("5/60"), Some(Limit::new(5, 60)));
+        assert_eq!(Limit::parse(" 10 / 1 "), Some(Limit::new(10, 1)));
+        assert_eq!(Limit::parse("0/60"), None);
+        assert_eq!(Limit::parse("5"), None);
+        assert_eq!(Limit::parse("a/b"), None);
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limit = Limit::new(2, 10);
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+
+        assert!(bucket.take(&limit, start).is_ok());
+        assert!(bucket.take(&limit, start).is_ok());
+
+        let wait = bucket.take(&limit, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(5));
+
+        let later = start + Duration::from_secs(5);
+        assert!(bucket.take(&limit, later).is_ok());
+        assert!(bucket.take(&limit, later).is_err());
+    }
+
+    #[test]
+    fn sweep_refilled_buckets() {
+        let short = Limit::new(1, 10);
+        let long = Limit::new(1, 3600);
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        buckets.sweep(start);
+        buckets
+            .buckets
+            .insert("short".to_string(), Bucket::full(&short, start));
+        buckets
+            .buckets
+            .insert("long".to_string(), Bucket::full(&long, start));
+
+        // not swept again before the interval elapsed
+        buckets.sweep(start + Duration::from_secs(30));
+        assert_eq!(buckets.buckets.len(), 2);
+
+        buckets.sweep(start + Duration::from_secs(SWEEP_INTERVAL_SECS));
+        assert!(!buckets.buckets.contains_key("short"));
+        assert!(buckets.buckets.contains_key("long"));
+    }
+
+    #[test]
+    fn group_matching() {
+        let group = RouteGroup {
+            name: "image_upload",
+            method: Method::Post,
+            path: "/entry/*/image",
+            limit: Limit::new(1, 1),
+        };
+
+        assert!(group.matches(Method::Post, "/entry/12/image"));
+        assert!(group.matches(Method::Post, "/entry/12/image/"));
+        assert!(!group.matches(Method::Get, "/entry/12/image"));
+        assert!(!group.matches(Method::Post, "/entry/12"));
+        assert!(!group.matches(Method::Post, "/entry/12/image/thumb"));
+    }
+
+    #[test]
+    fn forwarded_clients() {
+        let client = "203.0.113.7".parse::<IpAddr>().unwrap();
+
+        assert_eq!(forwarded_client("203.0.113.7"), Some(client));
+        assert_eq!(forwarded_client("10.0.0.1, 203.0.113.7"), Some(client));
+        assert_eq!(forwarded_client("203.0.113.7, "), None);
+        assert_eq!(forwarded_client("unknown"), None);
+    }
+}
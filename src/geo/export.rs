@@ -0,0 +1,480 @@
+use std::io::{self, Read};
+
+use chrono::{DateTime, Utc};
+use rocket::http::RawStr;
+use rocket::request::FromFormValue;
+use serde_json;
+
+use super::{GeoJsonPoint, Point};
+
+/// Format of exported times, always in UTC.
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// A file format understood by mapping tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Gpx,
+    Kml,
+    GeoJson,
+}
+
+impl ExportFormat {
+    /// The file extension of the format.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Gpx => "gpx",
+            ExportFormat::Kml => "kml",
+            ExportFormat::GeoJson => "geojson",
+        }
+    }
+
+    /// The top-level and sub type of the media type of the format.
+    pub fn media_type(&self) -> (&'static str, &'static str) {
+        match *self {
+            ExportFormat::Gpx => ("application", "gpx+xml"),
+            ExportFormat::Kml => ("application", "vnd.google-earth.kml+xml"),
+            ExportFormat::GeoJson => ("application", "geo+json"),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for ExportFormat {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, &'v RawStr> {
+        match form_value.as_str() {
+            "gpx" => Ok(ExportFormat::Gpx),
+            "kml" => Ok(ExportFormat::Kml),
+            "geojson" => Ok(ExportFormat::GeoJson),
+            _ => Err(form_value),
+        }
+    }
+}
+
+/// An exported entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub time: DateTime<Utc>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub point: Option<Point>,
+}
+
+/// Features read as the file is rendered, e.g. in batches from a database.
+pub type Features = Box<Iterator<Item = io::Result<Feature>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Header,
+    Features,
+    Route,
+    Footer,
+    Done,
+}
+
+/// Renders a journey as a file, one feature at a time as it is read.
+/// Features are written as waypoints or placemarks, followed by the route
+/// through them. GPX and KML leave out features without a point.
+pub struct Export {
+    format: ExportFormat,
+    title: String,
+    features: Features,
+    route: Vec<(DateTime<Utc>, Point)>,
+    stage: Stage,
+    first: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Export {
+    /// Exports `features`, which must be ordered by time. Only the points
+    /// of the route are kept until the end, so the features themselves are
+    /// not all held in memory.
+    pub fn new(format: ExportFormat, title: &str, features: Features) -> Self {
+        Export {
+            format,
+            title: title.to_string(),
+            features,
+            route: Vec::new(),
+            stage: Stage::Header,
+            first: true,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Renders the next part of the file into the buffer.
+    fn render_next(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+
+        match self.stage {
+            Stage::Header => {
+                let header = self.header();
+                self.buf.extend_from_slice(header.as_bytes());
+                self.stage = Stage::Features;
+            }
+            Stage::Features => match self.features.next() {
+                Some(feature) => {
+                    let feature = feature?;
+                    if let Some(point) = feature.point {
+                        self.route.push((feature.time, point));
+                    }
+                    self.feature(&feature)?;
+                }
+                None => self.stage = Stage::Route,
+            },
+            Stage::Route => {
+                self.route()?;
+                self.stage = Stage::Footer;
+            }
+            Stage::Footer => {
+                let footer = match self.format {
+                    ExportFormat::Gpx => "</gpx>\n",
+                    ExportFormat::Kml => "</Document>\n</kml>\n",
+                    ExportFormat::GeoJson => "]}\n",
+                };
+                self.buf.extend_from_slice(footer.as_bytes());
+                self.stage = Stage::Done;
+            }
+            Stage::Done => (),
+        }
+
+        Ok(())
+    }
+
+    fn header(&self) -> String {
+        let title = escape(&self.title);
+
+        match self.format {
+            ExportFormat::Gpx => format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <gpx version=\"1.1\" creator=\"journaloo\" \
+                 xmlns=\"http://www.topografix.com/GPX/1/1\">\n\
+                 <metadata><name>{}</name></metadata>\n",
+                title
+            ),
+            ExportFormat::Kml => format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n\
+                 <Document>\n<name>{}</name>\n",
+                title
+            ),
+            ExportFormat::GeoJson => {
+                "{\"type\":\"FeatureCollection\",\"features\":[\n".to_string()
+            }
+        }
+    }
+
+    fn feature(&mut self, feature: &Feature) -> io::Result<()> {
+        let time = feature.time.format(TIME_FORMAT).to_string();
+        let description = feature.description.as_ref().map(|d| escape(d));
+        let location = feature.location.as_ref().map(|l| escape(l));
+
+        match (self.format, feature.point) {
+            (ExportFormat::Gpx, Some(point)) => {
+                let mut wpt = format!(
+                    "<wpt lat=\"{}\" lon=\"{}\">",
+                    point.latitude, point.longitude
+                );
+                if let Some(altitude) = point.altitude {
+                    wpt.push_str(&format!("<ele>{}</ele>", altitude));
+                }
+                wpt.push_str(&format!("<time>{}</time>", time));
+                if let Some(location) = location {
+                    wpt.push_str(&format!("<name>{}</name>", location));
+                }
+                if let Some(description) = description {
+                    wpt.push_str(&format!("<desc>{}</desc>", description));
+                }
+                wpt.push_str("</wpt>\n");
+                self.buf.extend_from_slice(wpt.as_bytes());
+            }
+            (ExportFormat::Kml, Some(point)) => {
+                let mut placemark = "<Placemark>".to_string();
+                if let Some(location) = location {
+                    placemark.push_str(&format!("<name>{}</name>", location));
+                }
+                if let Some(description) = description {
+                    placemark.push_str(&format!(
+                        "<description>{}</description>",
+                        description
+                    ));
+                }
+                placemark.push_str(&format!(
+                    "<TimeStamp><when>{}</when></TimeStamp>\
+                     <Point><coordinates>{}</coordinates></Point>\
+                     </Placemark>\n",
+                    time,
+                    kml_coordinates(&point)
+                ));
+                self.buf.extend_from_slice(placemark.as_bytes());
+            }
+            (ExportFormat::GeoJson, point) => {
+                let feature = GeoJsonFeature {
+                    kind: "Feature",
+                    geometry: point.map(|p| Geometry::Point(p.to_geojson())),
+                    properties: Properties {
+                        time: Some(time),
+                        description: feature.description.clone(),
+                        location: feature.location.clone(),
+                        route: false,
+                    },
+                };
+                self.json(&feature)?;
+            }
+            (_, None) => (),
+        }
+
+        Ok(())
+    }
+
+    fn route(&mut self) -> io::Result<()> {
+        if self.route.len() < 2 {
+            return Ok(());
+        }
+
+        match self.format {
+            ExportFormat::Gpx => {
+                let mut trk = format!(
+                    "<trk><name>{}</name><trkseg>\n",
+                    escape(&self.title)
+                );
+                for &(time, point) in &self.route {
+                    trk.push_str(&format!(
+                        "<trkpt lat=\"{}\" lon=\"{}\">",
+                        point.latitude, point.longitude
+                    ));
+                    if let Some(altitude) = point.altitude {
+                        trk.push_str(&format!("<ele>{}</ele>", altitude));
+                    }
+                    trk.push_str(&format!(
+                        "<time>{}</time></trkpt>\n",
+                        time.format(TIME_FORMAT)
+                    ));
+                }
+                trk.push_str("</trkseg></trk>\n");
+                self.buf.extend_from_slice(trk.as_bytes());
+            }
+            ExportFormat::Kml => {
+                let coordinates = self.route
+                    .iter()
+                    .map(|&(_, point)| kml_coordinates(&point))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let placemark = format!(
+                    "<Placemark><name>{}</name><LineString>\
+                     <coordinates>{}</coordinates>\
+                     </LineString></Placemark>\n",
+                    escape(&self.title),
+                    coordinates
+                );
+                self.buf.extend_from_slice(placemark.as_bytes());
+            }
+            ExportFormat::GeoJson => {
+                let coordinates = self.route
+                    .iter()
+                    .map(|&(_, point)| point.to_geojson().coordinates)
+                    .collect();
+                let feature = GeoJsonFeature {
+                    kind: "Feature",
+                    geometry: Some(Geometry::LineString(GeoJsonLineString {
+                        kind: "LineString",
+                        coordinates,
+                    })),
+                    properties: Properties {
+                        time: None,
+                        description: None,
+                        location: None,
+                        route: true,
+                    },
+                };
+                self.json(&feature)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a GeoJSON feature, separated from the previous one.
+    fn json(&mut self, feature: &GeoJsonFeature) -> io::Result<()> {
+        if !self.first {
+            self.buf.extend_from_slice(b",\n");
+        }
+        self.first = false;
+
+        serde_json::to_writer(&mut self.buf, feature)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl Read for Export {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.stage == Stage::Done {
+                return Ok(0);
+            }
+            self.render_next()?;
+        }
+
+        let read = buf.len().min(self.buf.len() - self.pos);
+        buf[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+#[derive(Serialize)]
+struct GeoJsonFeature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: Option<Geometry>,
+    properties: Properties,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Geometry {
+    Point(GeoJsonPoint),
+    LineString(GeoJsonLineString),
+}
+
+#[derive(Serialize)]
+struct GeoJsonLineString {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<Vec<f64>>,
+}
+
+#[derive(Serialize)]
+struct Properties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    route: bool,
+}
+
+/// A KML coordinate tuple, longitude first.
+fn kml_coordinates(point: &Point) -> String {
+    match point.altitude {
+        Some(altitude) => {
+            format!("{},{},{}", point.longitude, point.latitude, altitude)
+        }
+        None => format!("{},{}", point.longitude, point.latitude),
+    }
+}
+
+/// Escapes text for use in XML content and attributes.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use geo::gpx::Gpx;
+
+    fn features() -> Vec<Feature> {
+        let time = Utc.ymd(2018, 4, 1).and_hms(10, 0, 0);
+        vec![
+            Feature {
+                time,
+                description: Some("Fish & chips".to_string()),
+                location: Some("Amsterdam".to_string()),
+                point: Some(Point::new(52.37, 4.9)),
+            },
+            Feature {
+                time,
+                description: None,
+                location: None,
+                point: None,
+            },
+            Feature {
+                time,
+                description: None,
+                location: Some("Paris".to_string()),
+                point: Some(Point::new(48.86, 2.35)),
+            },
+        ]
+    }
+
+    fn export(format: ExportFormat) -> String {
+        let mut out = String::new();
+        let features = Box::new(features().into_iter().map(Ok));
+        Export::new(format, "Trip <1>", features)
+            .read_to_string(&mut out)
+            .expect("failed to export");
+        out
+    }
+
+    #[test]
+    fn gpx() {
+        let gpx = Gpx::parse(export(ExportFormat::Gpx).as_bytes())
+            .expect("failed to parse export");
+
+        assert_eq!(gpx.waypoints.len(), 2);
+        assert_eq!(
+            gpx.waypoints[0].description,
+            Some("Fish & chips".to_string())
+        );
+        assert_eq!(gpx.waypoints[1].name, Some("Paris".to_string()));
+        assert_eq!(gpx.track.len(), 2);
+        assert_eq!(gpx.track[1].point.latitude, 48.86);
+    }
+
+    #[test]
+    fn kml() {
+        let kml = export(ExportFormat::Kml);
+
+        assert!(kml.contains("<name>Trip &lt;1&gt;</name>"));
+        assert!(kml.contains("<description>Fish &amp; chips</description>"));
+        assert!(kml.contains("<Placemark><name>Amsterdam</name>"));
+        assert!(kml.contains("<coordinates>4.9,52.37 2.35,48.86<"));
+        assert!(kml.ends_with("</Document>\n</kml>\n"));
+    }
+
+    #[test]
+    fn geojson() {
+        let json: serde_json::Value =
+            serde_json::from_str(&export(ExportFormat::GeoJson))
+                .expect("failed to parse export");
+        let features = json["features"].as_array().expect("no features");
+
+        assert_eq!(features.len(), 4);
+        assert_eq!(features[0]["geometry"]["coordinates"][0], 4.9);
+        assert_eq!(features[0]["properties"]["location"], "Amsterdam");
+        assert!(features[1]["geometry"].is_null());
+        assert_eq!(features[3]["geometry"]["type"], "LineString");
+        assert_eq!(features[3]["properties"]["route"], true);
+    }
+
+    #[test]
+    fn failed_features() {
+        let features = vec![
+            Ok(features()[0].clone()),
+            Err(io::Error::new(io::ErrorKind::Other, "connection lost")),
+        ];
+        let mut export = Export::new(
+            ExportFormat::Gpx,
+            "Trip",
+            Box::new(features.into_iter()),
+        );
+
+        assert!(export.read_to_string(&mut String::new()).is_err());
+    }
+}
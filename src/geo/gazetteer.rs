@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use super::Point;
+
+/// Places further away than this are not considered to name a location.
+const MAX_DISTANCE_KM: f64 = 100.0;
+
+/// A populated place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub name: String,
+    pub country: String,
+    pub point: Point,
+    /// The IANA time zone of the place, if known.
+    pub time_zone: Option<String>,
+}
+
+impl Place {
+    /// The name of the place along with its country.
+    pub fn label(&self) -> String {
+        format!("{}, {}", self.name, self.country)
+    }
+}
+
+/// The position of a place on the unit sphere. Straight-line distances
+/// between these order places the same as great-circle distances.
+type Position = [f64; 3];
+
+fn position(point: &Point) -> Position {
+    let lat = point.latitude.to_radians();
+    let lon = point.longitude.to_radians();
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn squared_distance(a: &Position, b: &Position) -> f64 {
+    (0..3).map(|axis| (a[axis] - b[axis]).powi(2)).sum()
+}
+
+/// Places indexed for nearest neighbour lookups, to name locations without
+/// an external service.
+///
+/// The places are kept as a k-d tree over their positions on the unit
+/// sphere: the median of each slice, split along the axis of its depth, is
+/// its root.
+#[derive(Debug, Default)]
+pub struct Gazetteer {
+    places: Vec<(Position, Place)>,
+}
+
+impl Gazetteer {
+    pub fn new(places: Vec<Place>) -> Self {
+        let mut places = places
+            .into_iter()
+            .map(|place| (position(&place.point), place))
+            .collect::<Vec<_>>();
+        build(&mut places, 0);
+
+        Gazetteer { places }
+    }
+
+    /// Loads the gazetteer from the GeoNames extract at `GAZETTEER_PATH`,
+    /// such as `cities15000.txt`. Countries are named from the GeoNames
+    /// country info file at `GAZETTEER_COUNTRIES_PATH`, or by their ISO code
+    /// otherwise. Without a gazetteer, locations are not named.
+    pub fn from_env() -> Self {
+        let path = match env::var("GAZETTEER_PATH") {
+            Ok(path) => path,
+            Err(_) => {
+                info!("No gazetteer configured, locations are not named");
+                return Gazetteer::default();
+            }
+        };
+
+        let countries = match env::var("GAZETTEER_COUNTRIES_PATH") {
+            Ok(path) => match File::open(&path)
+                .and_then(|file| parse_countries(BufReader::new(file)))
+            {
+                Ok(countries) => countries,
+                Err(e) => {
+                    error!("Failed to load countries {} -- {:?}", path, e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        match File::open(&path)
+            .and_then(|file| parse_places(BufReader::new(file), &countries))
+        {
+            Ok(places) => {
+                info!("Loaded {} places from {}", places.len(), path);
+                Gazetteer::new(places)
+            }
+            Err(e) => {
+                error!("Failed to load gazetteer {} -- {:?}", path, e);
+                Gazetteer::default()
+            }
+        }
+    }
+
+    /// The place nearest to `point`, unless it is too far away to name the
+    /// location.
+    pub fn nearest(&self, point: &Point) -> Option<&Place> {
+        let target = position(point);
+        let mut best = None;
+        nearest(&self.places, 0, &target, &mut best);
+
+        best.and_then(|(_, place)| {
+            if place.point.distance_km(point) <= MAX_DISTANCE_KM {
+                Some(place)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The name of the location of `point`, with its country.
+    pub fn locate(&self, point: &Point) -> Option<String> {
+        self.nearest(point).map(Place::label)
+    }
+
+    /// The time zone at `point`, taken from the nearest place.
+    pub fn time_zone(&self, point: &Point) -> Option<String> {
+        self.nearest(point)
+            .and_then(|place| place.time_zone.clone())
+    }
+}
+
+/// Orders `places` into a k-d tree.
+fn build(places: &mut [(Position, Place)], depth: usize) {
+    if places.len() <= 1 {
+        return;
+    }
+
+    let axis = depth % 3;
+    places.sort_by(|a, b| {
+        a.0[axis]
+            .partial_cmp(&b.0[axis])
+            .expect("positions are finite")
+    });
+
+    let mid = places.len() / 2;
+    let (before, after) = places.split_at_mut(mid);
+    build(before, depth + 1);
+    build(&mut after[1..], depth + 1);
+}
+
+/// Searches the k-d tree `places` for the place nearest to `target`, keeping
+/// the best candidate so far with its squared distance in `best`.
+fn nearest<'a>(
+    places: &'a [(Position, Place)],
+    depth: usize,
+    target: &Position,
+    best: &mut Option<(f64, &'a Place)>,
+) {
+    if places.is_empty() {
+        return;
+    }
+
+    let axis = depth % 3;
+    let mid = places.len() / 2;
+    let (ref position, ref place) = places[mid];
+
+    let distance = squared_distance(position, target);
+    if best.map_or(true, |(best_distance, _)| distance < best_distance) {
+        *best = Some((distance, place));
+    }
+
+    let offset = target[axis] - position[axis];
+    let (near, far) = if offset < 0.0 {
+        (&places[..mid], &places[mid + 1..])
+    } else {
+        (&places[mid + 1..], &places[..mid])
+    };
+
+    nearest(near, depth + 1, target, best);
+    if best.map_or(true, |(best_distance, _)| offset.powi(2) < best_distance) {
+        nearest(far, depth + 1, target, best);
+    }
+}
+
+/// Parses a GeoNames country info file into country names by ISO code.
+fn parse_countries<R: BufRead>(
+    source: R,
+) -> io::Result<HashMap<String, String>> {
+    let mut countries = HashMap::new();
+
+    for line in source.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let columns = line.split('\t').collect::<Vec<_>>();
+        if columns.len() > 4 {
+            countries.insert(columns[0].to_string(), columns[4].to_string());
+        }
+    }
+
+    Ok(countries)
+}
+
+/// Parses a GeoNames extract. Lines that are not valid places are skipped.
+fn parse_places<R: BufRead>(
+    source: R,
+    countries: &HashMap<String, String>,
+) -> io::Result<Vec<Place>> {
+    let mut places = Vec::new();
+
+    for line in source.lines() {
+        let line = line?;
+        let columns = line.split('\t').collect::<Vec<_>>();
+        if columns.len() < 9 {
+            continue;
+        }
+
+        let point = Point::from_columns(
+            columns[4].parse().ok(),
+            columns[5].parse().ok(),
+            None,
+            None,
+        );
+        let point = match point {
+            Some(point) if point.is_valid() => point,
+            _ => continue,
+        };
+
+        let code = columns[8];
+        let time_zone = columns
+            .get(17)
+            .map(|tz| tz.trim())
+            .and_then(|tz| if tz.is_empty() { None } else { Some(tz) })
+            .map(ToString::to_string);
+
+        places.push(Place {
+            name: columns[1].to_string(),
+            country: countries
+                .get(code)
+                .cloned()
+                .unwrap_or_else(|| code.to_string()),
+            point,
+            time_zone,
+        });
+    }
+
+    Ok(places)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CITIES: &str = "\
+2759794\tAmsterdam\tAmsterdam\t\t52.37403\t4.88969\tP\tPPLC\tNL\t\t07\t\t\t\t\
+741636\t\t13\tEurope/Amsterdam\t2017-08-24
+2988507\tParis\tParis\t\t48.85341\t2.3488\tP\tPPLC\tFR
+2800866\tBrussels\tBrussels\t\t50.85045\t4.34878\tP\tPPLC\tBE
+2193733\tAuckland\tAuckland\t\t-36.84853\t174.76349\tP\tPPLA\tNZ
+4030556\tRikitea\tRikitea\t\t-23.1203\t-134.9692\tP\tPPLA2\tPF
+invalid line
+";
+
+    const COUNTRIES: &str = "\
+#ISO\tISO3\tISO-Numeric\tfips\tCountry
+NL\tNLD\t528\tNL\tNetherlands
+FR\tFRA\t250\tFR\tFrance
+";
+
+    fn gazetteer() -> Gazetteer {
+        let countries =
+            parse_countries(COUNTRIES.as_bytes()).expect("failed to parse");
+        let places = parse_places(CITIES.as_bytes(), &countries)
+            .expect("failed to parse");
+        assert_eq!(places.len(), 5);
+
+        Gazetteer::new(places)
+    }
+
+    #[test]
+    fn locate() {
+        let gazetteer = gazetteer();
+
+        assert_eq!(
+            gazetteer.locate(&Point::new(52.3, 4.9)),
+            Some("Amsterdam, Netherlands".to_string())
+        );
+        assert_eq!(
+            gazetteer.locate(&Point::new(48.9, 2.4)),
+            Some("Paris, France".to_string())
+        );
+        assert_eq!(
+            gazetteer.locate(&Point::new(50.9, 4.4)),
+            Some("Brussels, BE".to_string())
+        );
+        assert_eq!(
+            gazetteer.locate(&Point::new(-36.8, 174.7)),
+            Some("Auckland, NZ".to_string())
+        );
+    }
+
+    #[test]
+    fn time_zones() {
+        let gazetteer = gazetteer();
+
+        assert_eq!(
+            gazetteer.time_zone(&Point::new(52.3, 4.9)),
+            Some("Europe/Amsterdam".to_string())
+        );
+        assert_eq!(gazetteer.time_zone(&Point::new(48.9, 2.4)), None);
+    }
+
+    #[test]
+    fn remote() {
+        let gazetteer = gazetteer();
+
+        assert_eq!(gazetteer.locate(&Point::new(0.0, -30.0)), None);
+        assert_eq!(Gazetteer::default().locate(&Point::new(52.3, 4.9)), None);
+    }
+
+    #[test]
+    fn nearest_matches_linear_search() {
+        let places = (-8..9)
+            .flat_map(|lat| {
+                (-18..18).map(move |lon| Place {
+                    name: format!("{},{}", lat, lon),
+                    country: String::new(),
+                    time_zone: None,
+                    point: Point::new(
+                        f64::from(lat) * 10.0,
+                        f64::from(lon) * 10.0,
+                    ),
+                })
+            })
+            .collect::<Vec<_>>();
+        let gazetteer = Gazetteer::new(places.clone());
+
+        for &(lat, lon) in &[(12.3, 45.6), (-78.9, 179.9), (3.0, -171.0)] {
+            let point = Point::new(lat, lon);
+            let target = position(&point);
+            let expected = places
+                .iter()
+                .min_by(|a, b| {
+                    let a = squared_distance(&position(&a.point), &target);
+                    let b = squared_distance(&position(&b.point), &target);
+                    a.partial_cmp(&b).unwrap()
+                })
+                .unwrap();
+
+            let mut best = None;
+            nearest(&gazetteer.places, 0, &target, &mut best);
+            assert_eq!(best.map(|(_, place)| place), Some(expected));
+        }
+    }
+}
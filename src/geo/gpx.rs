@@ -0,0 +1,266 @@
+use std::fmt;
+use std::io::Read;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use xml::reader::{EventReader, XmlEvent};
+
+use super::Point;
+
+/// A named location in a GPX file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waypoint {
+    pub point: Point,
+    pub time: Option<DateTime<Utc>>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// A location recorded along a GPX track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub point: Point,
+    pub time: Option<DateTime<Utc>>,
+}
+
+/// The waypoints and track points of a GPX file, in document order. The
+/// points of all tracks and track segments are joined into one track.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Gpx {
+    pub waypoints: Vec<Waypoint>,
+    pub track: Vec<TrackPoint>,
+}
+
+#[derive(Debug)]
+pub enum GpxError {
+    /// The document is not well-formed XML.
+    Xml(String),
+    /// A point has a missing or invalid location, elevation or time.
+    InvalidPoint(String),
+}
+
+impl fmt::Display for GpxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GpxError::Xml(ref e) => write!(f, "invalid XML: {}", e),
+            GpxError::InvalidPoint(ref e) => write!(f, "invalid point: {}", e),
+        }
+    }
+}
+
+/// The kind of GPX point being parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Waypoint,
+    TrackPoint,
+}
+
+/// A point of which the closing tag has not been read yet.
+struct Partial {
+    kind: Kind,
+    point: Point,
+    time: Option<DateTime<Utc>>,
+    name: Option<String>,
+    description: Option<String>,
+}
+
+impl Gpx {
+    /// Parses a GPX 1.0 or 1.1 document. Route points and extensions are
+    /// ignored.
+    pub fn parse<R: Read>(source: R) -> Result<Self, GpxError> {
+        let mut gpx = Gpx::default();
+        let mut partial: Option<Partial> = None;
+        let mut field: Option<String> = None;
+        let mut text = String::new();
+
+        for event in EventReader::new(source) {
+            match event.map_err(|e| GpxError::Xml(e.to_string()))? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    let kind = match name.local_name.as_str() {
+                        "wpt" => Some(Kind::Waypoint),
+                        "trkpt" => Some(Kind::TrackPoint),
+                        _ => None,
+                    };
+
+                    if let Some(kind) = kind {
+                        let attribute = |key: &str| {
+                            attributes
+                                .iter()
+                                .find(|a| a.name.local_name == key)
+                                .and_then(|a| a.value.trim().parse().ok())
+                        };
+                        let point = Point::from_columns(
+                            attribute("lat"),
+                            attribute("lon"),
+                            None,
+                            None,
+                        );
+
+                        partial = match point {
+                            Some(point) if point.is_valid() => Some(Partial {
+                                kind,
+                                point,
+                                time: None,
+                                name: None,
+                                description: None,
+                            }),
+                            _ => {
+                                return Err(GpxError::InvalidPoint(
+                                    "missing or invalid lat/lon".to_string(),
+                                ))
+                            }
+                        };
+                    } else if partial.is_some() {
+                        field = Some(name.local_name);
+                        text.clear();
+                    }
+                }
+                XmlEvent::Characters(chars) | XmlEvent::CData(chars) => {
+                    if field.is_some() {
+                        text.push_str(&chars);
+                    }
+                }
+                XmlEvent::EndElement { name } => {
+                    let local_name = name.local_name.as_str();
+                    if local_name == "wpt" || local_name == "trkpt" {
+                        if let Some(partial) = partial.take() {
+                            gpx.push(partial);
+                        }
+                    } else if let Some(ref mut partial) = partial {
+                        if field.as_ref().map(String::as_str)
+                            == Some(local_name)
+                        {
+                            set_field(partial, local_name, text.trim())?;
+                        }
+                    }
+
+                    field = None;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(gpx)
+    }
+
+    fn push(&mut self, partial: Partial) {
+        match partial.kind {
+            Kind::Waypoint => self.waypoints.push(Waypoint {
+                point: partial.point,
+                time: partial.time,
+                name: partial.name,
+                description: partial.description,
+            }),
+            Kind::TrackPoint => self.track.push(TrackPoint {
+                point: partial.point,
+                time: partial.time,
+            }),
+        }
+    }
+
+    /// The times recorded for waypoints and track points.
+    pub fn times(&self) -> Vec<DateTime<Utc>> {
+        self.waypoints
+            .iter()
+            .filter_map(|waypoint| waypoint.time)
+            .chain(self.track.iter().filter_map(|point| point.time))
+            .collect()
+    }
+}
+
+/// Stores the text of a child element of a point.
+fn set_field(
+    partial: &mut Partial,
+    field: &str,
+    text: &str,
+) -> Result<(), GpxError> {
+    let some_text = || match text {
+        "" => None,
+        text => Some(text.to_string()),
+    };
+
+    match field {
+        "ele" => {
+            let altitude = text.parse().map_err(|_| {
+                GpxError::InvalidPoint(format!("invalid elevation {}", text))
+            })?;
+            partial.point.altitude = Some(altitude);
+        }
+        "time" => partial.time = Some(parse_time(text)?),
+        "name" => partial.name = some_text(),
+        "desc" => partial.description = some_text(),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Parses a GPX time, which is in UTC unless it states a time zone.
+fn parse_time(text: &str) -> Result<DateTime<Utc>, GpxError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f")
+                .map(|time| DateTime::from_utc(time, Utc))
+        })
+        .map_err(|_| GpxError::InvalidPoint(format!("invalid time {}", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata><name>Trip</name></metadata>
+  <wpt lat="52.37" lon="4.90">
+    <ele>2.5</ele>
+    <time>2018-04-01T10:00:00Z</time>
+    <name>Amsterdam</name>
+  </wpt>
+  <wpt lat="48.86" lon="2.35"><desc><![CDATA[Paris]]></desc></wpt>
+  <trk>
+    <name>Day one</name>
+    <trkseg>
+      <trkpt lat="52.37" lon="4.90">
+        <time>2018-04-01T10:00:00+02:00</time>
+      </trkpt>
+      <trkpt lat="52.36" lon="4.91"/>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+    #[test]
+    fn parse() {
+        let gpx = Gpx::parse(GPX.as_bytes()).expect("failed to parse");
+        let time = Utc.ymd(2018, 4, 1).and_hms(10, 0, 0);
+
+        assert_eq!(gpx.waypoints.len(), 2);
+        let amsterdam = &gpx.waypoints[0];
+        assert_eq!(amsterdam.point.latitude, 52.37);
+        assert_eq!(amsterdam.point.altitude, Some(2.5));
+        assert_eq!(amsterdam.time, Some(time));
+        assert_eq!(amsterdam.name, Some("Amsterdam".to_string()));
+        assert_eq!(gpx.waypoints[1].description, Some("Paris".to_string()));
+        assert_eq!(gpx.waypoints[1].time, None);
+
+        assert_eq!(gpx.track.len(), 2);
+        assert_eq!(gpx.track[0].time, Some(time.with_hour(8).unwrap()));
+        assert_eq!(gpx.track[1].point.longitude, 4.91);
+
+        assert_eq!(gpx.times(), vec![time, time.with_hour(8).unwrap()]);
+    }
+
+    #[test]
+    fn invalid() {
+        let invalid_point = r#"<gpx><wpt lat="95" lon="4"/></gpx>"#;
+        let invalid_time =
+            r#"<gpx><wpt lat="52" lon="4"><time>noon</time></wpt></gpx>"#;
+
+        assert!(Gpx::parse(invalid_point.as_bytes()).is_err());
+        assert!(Gpx::parse(invalid_time.as_bytes()).is_err());
+        assert!(Gpx::parse("<gpx><wpt".as_bytes()).is_err());
+    }
+}
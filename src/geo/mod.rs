@@ -0,0 +1,215 @@
+pub mod export;
+pub mod gazetteer;
+pub mod gpx;
+pub mod stats;
+pub mod zone;
+
+/// Mean radius of the earth.
+pub const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// A location on earth in WGS 84 degrees, optionally with the accuracy of the
+/// measurement and the altitude, both in meters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub altitude: Option<f64>,
+}
+
+impl Point {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Point {
+            latitude,
+            longitude,
+            accuracy: None,
+            altitude: None,
+        }
+    }
+
+    /// Builds a point from nullable columns, if it has a location.
+    pub fn from_columns(
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        accuracy: Option<f64>,
+        altitude: Option<f64>,
+    ) -> Option<Self> {
+        match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => Some(Point {
+                latitude,
+                longitude,
+                accuracy,
+                altitude,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the point lies within the valid coordinate ranges, and has a
+    /// non-negative accuracy.
+    pub fn is_valid(&self) -> bool {
+        let finite = |value: Option<f64>| value.map_or(true, f64::is_finite);
+
+        self.latitude >= -90.0
+            && self.latitude <= 90.0
+            && self.longitude >= -180.0
+            && self.longitude <= 180.0
+            && self.accuracy.map_or(true, |accuracy| accuracy >= 0.0)
+            && finite(self.accuracy)
+            && finite(self.altitude)
+    }
+
+    /// The great-circle distance to another point, ignoring altitude.
+    pub fn distance_km(&self, other: &Point) -> f64 {
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2)
+            + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+
+        2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+    }
+
+    /// The point as a GeoJSON geometry, with the altitude as third
+    /// coordinate if it is known.
+    pub fn to_geojson(&self) -> GeoJsonPoint {
+        let mut coordinates = vec![self.longitude, self.latitude];
+        coordinates.extend(self.altitude);
+
+        GeoJsonPoint {
+            kind: "Point",
+            coordinates,
+        }
+    }
+
+    /// The point in the requested output format.
+    pub fn format(self, format: PointFormat) -> FormattedPoint {
+        match format {
+            PointFormat::Plain => FormattedPoint::Plain(self),
+            PointFormat::GeoJson => FormattedPoint::GeoJson(self.to_geojson()),
+        }
+    }
+}
+
+/// The smallest latitude/longitude rectangle around a set of points.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BoundingBox {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl BoundingBox {
+    /// The bounding box of `points`, if there are any. Boxes do not cross the
+    /// antimeridian.
+    pub fn around<'a, I>(points: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a Point>,
+    {
+        points.into_iter().fold(None, |bounds, point| {
+            let bounds = bounds.unwrap_or(BoundingBox {
+                south: point.latitude,
+                west: point.longitude,
+                north: point.latitude,
+                east: point.longitude,
+            });
+
+            Some(BoundingBox {
+                south: bounds.south.min(point.latitude),
+                west: bounds.west.min(point.longitude),
+                north: bounds.north.max(point.latitude),
+                east: bounds.east.max(point.longitude),
+            })
+        })
+    }
+}
+
+/// A GeoJSON point geometry.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GeoJsonPoint {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<f64>,
+}
+
+/// The formats in which points are returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointFormat {
+    Plain,
+    GeoJson,
+}
+
+impl PointFormat {
+    /// Selects GeoJSON output if `geojson` is set.
+    pub fn from_flag(geojson: Option<bool>) -> Self {
+        if geojson.unwrap_or(false) {
+            PointFormat::GeoJson
+        } else {
+            PointFormat::Plain
+        }
+    }
+}
+
+/// A point serialized in either format.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum FormattedPoint {
+    Plain(Point),
+    GeoJson(GeoJsonPoint),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(Point::new(52.1, 4.3).is_valid());
+        assert!(Point::new(-90.0, 180.0).is_valid());
+        assert!(!Point::new(90.1, 4.3).is_valid());
+        assert!(!Point::new(52.1, -180.5).is_valid());
+
+        let mut point = Point::new(52.1, 4.3);
+        point.accuracy = Some(-1.0);
+        assert!(!point.is_valid());
+    }
+
+    #[test]
+    fn distances() {
+        let amsterdam = Point::new(52.3676, 4.9041);
+        let paris = Point::new(48.8566, 2.3522);
+
+        let distance = amsterdam.distance_km(&paris);
+        assert!((distance - 430.0).abs() < 5.0, "distance was {}", distance);
+        assert_eq!(paris.distance_km(&paris), 0.0);
+    }
+
+    #[test]
+    fn bounds() {
+        let points = [Point::new(52.1, 4.3), Point::new(48.8, 2.3)];
+        let bounds = BoundingBox::around(&points).expect("no bounds");
+
+        assert_eq!(
+            bounds,
+            BoundingBox {
+                south: 48.8,
+                west: 2.3,
+                north: 52.1,
+                east: 4.3,
+            }
+        );
+        assert_eq!(BoundingBox::around(&[]), None);
+    }
+
+    #[test]
+    fn geojson() {
+        let mut point = Point::new(52.1, 4.3);
+        assert_eq!(point.to_geojson().coordinates, vec![4.3, 52.1]);
+
+        point.altitude = Some(12.0);
+        assert_eq!(point.to_geojson().coordinates, vec![4.3, 52.1, 12.0]);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+use super::{BoundingBox, Point};
+
+/// A stop along a route: an entry, with its location if it has one.
+#[derive(Debug, Clone, Copy)]
+pub struct Stop {
+    pub entry_id: i32,
+    /// The local time of the stop.
+    pub time: NaiveDateTime,
+    pub point: Option<Point>,
+}
+
+/// The distance travelled on a single day.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DayDistance {
+    pub date: NaiveDate,
+    pub distance_km: f64,
+}
+
+/// The stop furthest from where the route started.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Furthest {
+    pub entry_id: i32,
+    pub point: Point,
+    pub distance_km: f64,
+}
+
+/// Distance and movement statistics of a route.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteStats {
+    pub entries: usize,
+    pub photos: i64,
+    pub days: usize,
+    pub distance_km: f64,
+    pub distance_per_day: Vec<DayDistance>,
+    pub furthest: Option<Furthest>,
+    pub bounds: Option<BoundingBox>,
+}
+
+impl RouteStats {
+    /// Computes the statistics of the route along `stops`, which must be
+    /// ordered by time. Distances are great-circle distances between
+    /// consecutive located stops, counted on the day of the later stop.
+    pub fn compute(stops: &[Stop], photos: i64) -> Self {
+        let days = stops
+            .iter()
+            .map(|stop| stop.time.date())
+            .collect::<BTreeSet<_>>()
+            .len();
+
+        let located = stops
+            .iter()
+            .filter_map(|stop| stop.point.map(|point| (stop, point)))
+            .collect::<Vec<_>>();
+
+        let mut distance_per_day: Vec<DayDistance> = Vec::new();
+        for pair in located.windows(2) {
+            let (_, from) = pair[0];
+            let (stop, to) = pair[1];
+            let date = stop.time.date();
+            let distance_km = from.distance_km(&to);
+
+            if let Some(day) = distance_per_day.last_mut() {
+                if day.date == date {
+                    day.distance_km += distance_km;
+                    continue;
+                }
+            }
+
+            distance_per_day.push(DayDistance { date, distance_km });
+        }
+
+        let mut furthest: Option<Furthest> = None;
+        if let Some(&(_, start)) = located.first() {
+            for &(stop, point) in &located {
+                let distance_km = start.distance_km(&point);
+                let further = furthest
+                    .as_ref()
+                    .map_or(true, |f| distance_km > f.distance_km);
+
+                if further {
+                    furthest = Some(Furthest {
+                        entry_id: stop.entry_id,
+                        point,
+                        distance_km,
+                    });
+                }
+            }
+        }
+
+        let points = located
+            .iter()
+            .map(|&(_, point)| point)
+            .collect::<Vec<_>>();
+
+        RouteStats {
+            entries: stops.len(),
+            photos,
+            days,
+            distance_km: distance_per_day.iter().map(|d| d.distance_km).sum(),
+            distance_per_day,
+            furthest,
+            bounds: BoundingBox::around(&points),
+        }
+    }
+}
+
+/// Statistics of journeys, kept until the journey changes.
+#[derive(Debug, Default)]
+pub struct StatsCache {
+    stats: RwLock<HashMap<i32, (DateTime<Utc>, RouteStats)>>,
+}
+
+impl StatsCache {
+    /// The statistics of a journey, if they were computed for the version of
+    /// the journey last updated at `version`.
+    pub fn get(
+        &self,
+        journey_id: i32,
+        version: DateTime<Utc>,
+    ) -> Option<RouteStats> {
+        let stats = self.stats.read().ok()?;
+
+        match stats.get(&journey_id) {
+            Some(&(computed, ref stats)) if computed == version => {
+                Some(stats.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Keeps the statistics of the version of a journey last updated at
+    /// `version`, replacing those of earlier versions.
+    pub fn insert(
+        &self,
+        journey_id: i32,
+        version: DateTime<Utc>,
+        stats: RouteStats,
+    ) {
+        if let Ok(mut cached) = self.stats.write() {
+            cached.insert(journey_id, (version, stats));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn stop(entry_id: i32, day: u32, hour: u32, point: Option<Point>) -> Stop {
+        Stop {
+            entry_id,
+            time: NaiveDate::from_ymd(2018, 4, day).and_hms(hour, 0, 0),
+            point,
+        }
+    }
+
+    #[test]
+    fn route() {
+        let start = Point::new(52.0, 4.0);
+        let east = Point::new(52.0, 5.0);
+        let south = Point::new(51.0, 5.0);
+        let stops = [
+            stop(1, 1, 8, Some(start)),
+            stop(2, 1, 12, None),
+            stop(3, 1, 18, Some(east)),
+            stop(4, 2, 9, Some(south)),
+            stop(5, 2, 17, Some(east)),
+        ];
+
+        let stats = RouteStats::compute(&stops, 3);
+        assert_eq!(stats.entries, 5);
+        assert_eq!(stats.photos, 3);
+        assert_eq!(stats.days, 2);
+
+        let first_day = start.distance_km(&east);
+        let second_day = 2.0 * east.distance_km(&south);
+        assert_eq!(stats.distance_per_day.len(), 2);
+        assert_eq!(stats.distance_per_day[0].distance_km, first_day);
+        assert_eq!(stats.distance_per_day[1].distance_km, second_day);
+        assert!((stats.distance_km - first_day - second_day).abs() < 1e-9);
+
+        let furthest = stats.furthest.expect("no furthest stop");
+        assert_eq!(furthest.entry_id, 4);
+        assert_eq!(
+            stats.bounds,
+            Some(BoundingBox {
+                south: 51.0,
+                west: 4.0,
+                north: 52.0,
+                east: 5.0,
+            })
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let cache = StatsCache::default();
+        let stats = RouteStats::compute(&[stop(1, 1, 8, None)], 0);
+        let version = Utc.ymd(2018, 4, 1).and_hms(8, 0, 0);
+        let later = Utc.ymd(2018, 4, 1).and_hms(9, 0, 0);
+
+        assert_eq!(cache.get(1, version), None);
+        cache.insert(1, version, stats.clone());
+        assert_eq!(cache.get(1, version), Some(stats));
+        assert_eq!(cache.get(1, later), None);
+        assert_eq!(cache.get(2, version), None);
+    }
+
+    #[test]
+    fn unlocated() {
+        let stats = RouteStats::compute(&[stop(1, 1, 8, None)], 0);
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.days, 1);
+        assert_eq!(stats.distance_km, 0.0);
+        assert!(stats.distance_per_day.is_empty());
+        assert_eq!(stats.furthest, None);
+        assert_eq!(stats.bounds, None);
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// The time zone of users and entries that have no other time zone.
+pub const DEFAULT_TIME_ZONE: &str = "UTC";
+
+/// Whether `name` is a known IANA time zone, such as `Europe/Amsterdam`.
+pub fn is_valid(name: &str) -> bool {
+    name.parse::<Tz>().is_ok()
+}
+
+/// The time zone called `name`, or UTC for unknown time zones.
+fn time_zone(name: &str) -> Tz {
+    name.parse().unwrap_or(Tz::UTC)
+}
+
+/// Converts an instant to the local time of the time zone called `name`,
+/// with the offset that applies at that instant.
+pub fn localize(time: DateTime<Utc>, name: &str) -> DateTime<FixedOffset> {
+    let offset = time.with_timezone(&time_zone(name)).offset().fix();
+    offset.from_utc_datetime(&time.naive_utc())
+}
+
+/// The wall clock time of an instant in the time zone called `name`.
+pub fn local_time(time: DateTime<Utc>, name: &str) -> NaiveDateTime {
+    time.with_timezone(&time_zone(name)).naive_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn daylight_saving() {
+        let summer = Utc.ymd(2018, 7, 1).and_hms(12, 0, 0);
+        let winter = Utc.ymd(2018, 1, 1).and_hms(12, 0, 0);
+
+        let local = localize(summer, "Europe/Amsterdam");
+        assert_eq!(local.to_rfc3339(), "2018-07-01T14:00:00+02:00");
+        assert_eq!(local, summer);
+        assert_eq!(
+            localize(winter, "Europe/Amsterdam").to_rfc3339(),
+            "2018-01-01T13:00:00+01:00"
+        );
+        assert_eq!(
+            local_time(winter, "America/New_York"),
+            NaiveDate::from_ymd(2018, 1, 1).and_hms(7, 0, 0)
+        );
+    }
+
+    #[test]
+    fn unknown_zones() {
+        assert!(is_valid("Asia/Tokyo"));
+        assert!(!is_valid("Mars/Olympus_Mons"));
+        assert!(is_valid(DEFAULT_TIME_ZONE));
+
+        let time = Utc.ymd(2018, 7, 1).and_hms(12, 0, 0);
+        assert_eq!(
+            localize(time, "Mars/Olympus_Mons").to_rfc3339(),
+            "2018-07-01T12:00:00+00:00"
+        );
+    }
+}
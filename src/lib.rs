@@ -6,9 +6,12 @@
 
 extern crate bcrypt;
 extern crate chrono;
+extern crate chrono_tz;
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
+extern crate exif;
+extern crate image;
 extern crate jsonwebtoken as jwt;
 #[macro_use]
 extern crate lazy_static;
@@ -26,17 +29,32 @@ extern crate sendgrid;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+extern crate uuid;
+extern crate xml;
 extern crate futures;
 
 use std::env;
 
-use endpoints::{entry, journey, user};
+use endpoints::{attachment, entry, journey, map, sync, user};
 use rocket::Rocket;
 
 use db::init_pool;
+use fairings::cors::Cors;
+use fairings::rate_limit::RateLimiter;
+use geo::gazetteer::Gazetteer;
+use geo::stats::StatsCache;
+use media::lifecycle;
+use media::variants::VariantGenerator;
+use storage::init_storage;
 
 mod db;
 mod endpoints;
+mod fairings;
+mod geo;
+mod media;
+mod storage;
 
 lazy_static! {
     static ref SECRET: String =
@@ -48,36 +66,70 @@ pub fn rocket() -> Rocket {
 
     //    let _ = env_logger::try_init();
     let pool = init_pool();
+    let storage = init_storage();
+    let variants = VariantGenerator::start(storage.clone());
+    lifecycle::start_sweeper(storage.clone(), pool.clone());
 
     // Configure our server, and mount all routes.  We don't "launch" the server
     // here, but in our `main` procedure.
-    rocket::ignite().manage(pool).mount(
-        "/",
-        routes![
-            index,
-            user::signup,
-            user::update,
-            user::delete,
-            user::login,
-            user::get_by_id,
-            user::get_all,
-            user::reset_password,
-            journey::create,
-            journey::get_by_id,
-            journey::delete,
-            journey::update,
-            journey::get_journeys_by_user,
-            journey::get_active_journey_by_user,
-            journey::end,
-            entry::create,
-            entry::delete,
-            entry::get_all,
-            entry::update,
-            entry::get_image_by_id,
-            entry::get_by_id,
-            entry::create_image,
-        ],
-    )
+    rocket::ignite()
+        .manage(pool)
+        .manage(storage)
+        .manage(variants)
+        .manage(StatsCache::default())
+        .manage(Gazetteer::from_env())
+        .attach(Cors::from_env())
+        .attach(RateLimiter::from_env())
+        .mount(
+            "/",
+            routes![
+                index,
+                user::signup,
+                user::update,
+                user::delete,
+                user::login,
+                user::get_by_id,
+                user::usage,
+                user::get_all,
+                user::reset_password,
+                journey::create,
+                journey::get_by_id,
+                journey::stats,
+                journey::import_gpx,
+                journey::get_track,
+                journey::export,
+                journey::delete,
+                journey::update,
+                journey::get_journeys_by_user,
+                journey::get_active_journey_by_user,
+                journey::end,
+                entry::create,
+                entry::delete,
+                entry::purge,
+                entry::get_all,
+                entry::update,
+                entry::get_image_by_id,
+                entry::get_image_variant_by_id,
+                entry::get_by_id,
+                entry::get_formatted_by_id,
+                entry::create_image,
+                map::within,
+                map::near,
+                map::nearest,
+                map::clusters,
+                attachment::create,
+                attachment::get_by_id,
+                attachment::get_variant_by_id,
+                attachment::request_upload,
+                attachment::confirm_upload,
+                attachment::reorder,
+                attachment::update,
+                attachment::delete,
+                sync::full,
+                sync::changes,
+                sync::push,
+            ],
+        )
 }
 
 #[get("/")]
@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{self, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+
+use db::Pool;
+use db::models::{attachment, entry};
+use media::variants::ImageSize;
+use storage::{Storage, StorageResult};
+
+/// The record that owns a stored object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Owner {
+    /// Media shared by attachments, stored under its hash.
+    Blob(String),
+    /// Media of an attachment, stored under its ID until it is hashed.
+    Attachment(i32),
+    /// Images from before attachments existed are stored under the ID of
+    /// their entry.
+    Entry(i32),
+}
+
+impl Owner {
+    /// Finds the owner of the object stored under `key`, including variants.
+    /// Returns `None` for keys that are not managed as media, such as
+    /// numeric keys of other objects that only resemble legacy images.
+    fn of(key: &str) -> Option<Owner> {
+        let original = key.split('.').next().unwrap_or(key);
+
+        let owner = if original.starts_with("media/") {
+            Owner::Blob(original["media/".len()..].to_string())
+        } else if original.starts_with("attachments/") {
+            Owner::Attachment(original["attachments/".len()..].parse().ok()?)
+        } else {
+            Owner::Entry(original.parse().ok()?)
+        };
+
+        // only keys exactly as they are stored, e.g. not `+7` or `7.bak`
+        let stored = owner.key();
+        let exact = ImageSize::GENERATED
+            .iter()
+            .chain(&[ImageSize::Full])
+            .any(|size| size.key(&stored) == key);
+
+        if exact {
+            Some(owner)
+        } else {
+            None
+        }
+    }
+
+    /// The storage key of the original media of the owner.
+    fn key(&self) -> String {
+        match *self {
+            Owner::Blob(ref hash) => format!("media/{}", hash),
+            Owner::Attachment(id) => format!("attachments/{}", id),
+            Owner::Entry(id) => entry::legacy_image_key(id),
+        }
+    }
+}
+
+/// Removes the stored media under `key`, including its variants.
+pub fn delete(storage: &Storage, key: &str) -> StorageResult<()> {
+    for size in &ImageSize::GENERATED {
+        storage.delete(&size.key(key))?;
+    }
+
+    storage.delete(key)
+}
+
+/// Removes the stored media under each of `keys`, after their records have
+/// been deleted. Failures are only logged, as the remaining objects are
+/// orphans that the next sweep removes.
+pub fn delete_all(storage: &Storage, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = delete(storage, key) {
+            error!("Failed to delete media {} -- {:?}", key, e);
+        }
+    }
+}
+
+/// Selects the keys whose owner no longer exists. `attachments` are the IDs
+/// of the attachments whose media is not hashed yet.
+fn orphans(
+    keys: Vec<String>,
+    blobs: &HashSet<String>,
+    attachments: &HashSet<i32>,
+    entries: &HashSet<i32>,
+) -> Vec<String> {
+    keys.into_iter()
+        .filter(|key| match Owner::of(key) {
+            Some(Owner::Blob(hash)) => !blobs.contains(&hash),
+            Some(Owner::Attachment(id)) => !attachments.contains(&id),
+            Some(Owner::Entry(id)) => !entries.contains(&id),
+            None => false,
+        })
+        .collect()
+}
+
+/// Deletes the pending attachments of direct uploads that were not confirmed
+/// in time, along with any media uploaded for them, freeing their positions.
+/// Returns the number of deleted attachments.
+fn expire_pending(
+    storage: &Storage,
+    conn: &PgConnection,
+) -> Result<usize, String> {
+    let before = Utc::now() - chrono::Duration::seconds(*PENDING_UPLOAD_TTL);
+    let stale =
+        attachment::stale_pending(before, conn).map_err(|e| e.to_string())?;
+
+    for pending in &stale {
+        let key =
+            attachment::delete(pending, conn).map_err(|e| e.to_string())?;
+        if let Some(key) = key {
+            delete_all(storage, &[key]);
+        }
+    }
+
+    Ok(stale.len())
+}
+
+/// Removes all stored media without a matching attachment or entry.
+/// Returns the number of removed objects.
+fn sweep(storage: &Storage, conn: &PgConnection) -> Result<usize, String> {
+    use db::schema::{attachments, entries, media_blobs};
+
+    // list before loading the records, so objects stored in between are
+    // not mistaken for orphans
+    let keys = storage.list("").map_err(|e| e.to_string())?;
+
+    let hashes = media_blobs::table
+        .select(media_blobs::sha256)
+        .load::<String>(conn)
+        .map_err(|e| e.to_string())?;
+    let attachment_ids = attachments::table
+        .filter(attachments::sha256.is_null())
+        .select(attachments::id)
+        .load::<i32>(conn)
+        .map_err(|e| e.to_string())?;
+    let entry_ids = entries::table
+        .select(entries::id)
+        .load::<i32>(conn)
+        .map_err(|e| e.to_string())?;
+
+    let orphans = orphans(
+        keys,
+        &hashes.into_iter().collect(),
+        &attachment_ids.into_iter().collect(),
+        &entry_ids.into_iter().collect(),
+    );
+
+    for key in &orphans {
+        storage.delete(key).map_err(|e| e.to_string())?;
+    }
+
+    Ok(orphans.len())
+}
+
+lazy_static! {
+    /// Seconds between sweeps for orphaned media, defaults to a day.
+    /// Sweeping is disabled when set to 0.
+    static ref SWEEP_INTERVAL: u64 = env::var("MEDIA_SWEEP_INTERVAL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(24 * 60 * 60);
+    /// Seconds after which pending uploads that were not confirmed are
+    /// deleted, defaults to a day.
+    static ref PENDING_UPLOAD_TTL: i64 = env::var("PENDING_UPLOAD_TTL")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(24 * 60 * 60);
+}
+
+/// Starts a background thread that periodically removes orphaned media,
+/// which remains when deleting stored objects fails after their records
+/// were deleted, and pending uploads that were never confirmed.
+pub fn start_sweeper(storage: Storage, pool: Pool) {
+    if *SWEEP_INTERVAL == 0 {
+        info!("Sweeping for orphaned media is disabled");
+        return;
+    }
+
+    let interval = Duration::from_secs(*SWEEP_INTERVAL);
+
+    thread::Builder::new()
+        .name("media-sweeper".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            let conn = match pool.get() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to sweep media -- {}", e);
+                    continue;
+                }
+            };
+
+            match expire_pending(&storage, &conn) {
+                Ok(removed) => info!("Removed {} pending uploads", removed),
+                Err(e) => error!("Failed to remove pending uploads -- {}", e),
+            }
+
+            match sweep(&storage, &conn) {
+                Ok(removed) => info!("Removed {} orphaned objects", removed),
+                Err(e) => error!("Failed to sweep orphaned media -- {}", e),
+            }
+        })
+        .expect("failed to start media sweeper thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners() {
+        assert_eq!(
+            Owner::of("attachments/12"),
+            Some(Owner::Attachment(12))
+        );
+        assert_eq!(
+            Owner::of("attachments/12.thumb"),
+            Some(Owner::Attachment(12))
+        );
+        assert_eq!(
+            Owner::of("media/ab12.thumb"),
+            Some(Owner::Blob("ab12".to_string()))
+        );
+        assert_eq!(Owner::of("7.medium"), Some(Owner::Entry(7)));
+        assert_eq!(Owner::of("attachments/x"), None);
+        assert_eq!(Owner::of("backups/7"), None);
+        assert_eq!(Owner::of("7.bak"), None);
+        assert_eq!(Owner::of("+7"), None);
+        assert_eq!(Owner::of("007"), None);
+        assert_eq!(Owner::of("attachments/12.tmp"), None);
+    }
+
+    #[test]
+    fn orphaned_keys() {
+        let keys = vec![
+            "attachments/1".to_string(),
+            "attachments/1.thumb".to_string(),
+            "attachments/2".to_string(),
+            "media/ab12".to_string(),
+            "media/cd34.medium".to_string(),
+            "3".to_string(),
+            "4.medium".to_string(),
+            "other".to_string(),
+        ];
+        let blobs = vec!["ab12".to_string()].into_iter().collect();
+        let attachments = vec![1].into_iter().collect();
+        let entries = vec![3].into_iter().collect();
+
+        assert_eq!(
+            orphans(keys, &blobs, &attachments, &entries),
+            vec!["attachments/2", "media/cd34.medium", "4.medium"]
+        );
+    }
+}
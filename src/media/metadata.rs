@@ -0,0 +1,166 @@
+use std::io::{self, Cursor, Read};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{self, Reader, Tag, Value};
+
+use geo::Point;
+
+/// Number of leading bytes kept for EXIF parsing. JPEG files keep their EXIF
+/// data in an APP1 segment near the start, which is at most 64 KiB.
+pub const CAPTURE_LEN: usize = 128 * 1024;
+
+/// The capture time and location of a photo.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct PhotoMetadata {
+    /// The local time of the camera, EXIF does not record a time zone.
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl PhotoMetadata {
+    /// Parses the EXIF data at the start of an image file.
+    /// Returns empty metadata if there is no readable EXIF data.
+    pub fn extract(data: &[u8]) -> Self {
+        let reader = match Reader::new(&mut Cursor::new(data)) {
+            Ok(reader) => reader,
+            Err(e) => {
+                debug!("No EXIF data found -- {:?}", e);
+                return PhotoMetadata::default();
+            }
+        };
+
+        let captured_at = reader
+            .get_field(Tag::DateTimeOriginal, false)
+            .or_else(|| reader.get_field(Tag::DateTime, false))
+            .and_then(|field| match field.value {
+                Value::Ascii(ref strings) => strings.first().cloned(),
+                _ => None,
+            })
+            .and_then(parse_datetime);
+
+        let latitude =
+            coordinate(&reader, Tag::GPSLatitude, Tag::GPSLatitudeRef);
+        let longitude =
+            coordinate(&reader, Tag::GPSLongitude, Tag::GPSLongitudeRef);
+
+        PhotoMetadata {
+            captured_at,
+            latitude,
+            longitude,
+        }
+    }
+
+    /// The location of the photo, if it has a valid one.
+    pub fn point(&self) -> Option<Point> {
+        match Point::from_columns(self.latitude, self.longitude, None, None) {
+            Some(point) if point.is_valid() => Some(point),
+            _ => None,
+        }
+    }
+}
+
+fn parse_datetime(ascii: &[u8]) -> Option<NaiveDateTime> {
+    let dt = exif::DateTime::from_ascii(ascii).ok()?;
+
+    NaiveDate::from_ymd_opt(
+        i32::from(dt.year),
+        u32::from(dt.month),
+        u32::from(dt.day),
+    )?
+        .and_hms_opt(
+            u32::from(dt.hour),
+            u32::from(dt.minute),
+            u32::from(dt.second),
+        )
+}
+
+/// Reads a GPS coordinate in degrees, minutes and seconds, negated when its
+/// reference is south or west.
+fn coordinate(reader: &Reader, tag: Tag, ref_tag: Tag) -> Option<f64> {
+    let dms = match reader.get_field(tag, false)?.value {
+        Value::Rational(ref dms) if dms.len() == 3 => dms,
+        _ => return None,
+    };
+
+    let degrees =
+        dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0;
+    if !degrees.is_finite() {
+        return None;
+    }
+
+    let negative = match reader.get_field(ref_tag, false).map(|f| &f.value) {
+        Some(&Value::Ascii(ref refs)) => {
+            refs.first()
+                .map_or(false, |r| r.starts_with(b"S") || r.starts_with(b"W"))
+        }
+        _ => false,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// Reader that keeps a copy of the leading bytes that pass through it, so
+/// their EXIF data can be parsed after the image has been streamed.
+pub struct Capture<R> {
+    inner: R,
+    head: Vec<u8>,
+}
+
+impl<R: Read> Capture<R> {
+    pub fn new(inner: R) -> Self {
+        Capture {
+            inner,
+            head: Vec::new(),
+        }
+    }
+
+    /// Parses the EXIF data of the bytes read so far.
+    pub fn metadata(&self) -> PhotoMetadata {
+        PhotoMetadata::extract(&self.head)
+    }
+}
+
+impl<R: Read> Read for Capture<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        let keep = read.min(CAPTURE_LEN - self.head.len());
+        self.head.extend_from_slice(&buf[..keep]);
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_exif() {
+        let metadata = PhotoMetadata::extract(b"\x89PNG\r\n\x1a\n");
+        assert_eq!(metadata, PhotoMetadata::default());
+        assert_eq!(metadata.point(), None);
+    }
+
+    #[test]
+    fn datetime() {
+        assert_eq!(
+            parse_datetime(b"2018:03:21 14:05:09"),
+            Some(NaiveDate::from_ymd(2018, 3, 21).and_hms(14, 5, 9))
+        );
+        assert_eq!(parse_datetime(b"0000:00:00 00:00:00"), None);
+    }
+
+    #[test]
+    fn capture_keeps_head() {
+        let data = vec![7; CAPTURE_LEN + 10];
+        let mut capture = Capture::new(Cursor::new(data.clone()));
+
+        let mut result = Vec::new();
+        capture.read_to_end(&mut result).expect("failed to read");
+
+        assert_eq!(result, data);
+        assert_eq!(capture.head.len(), CAPTURE_LEN);
+    }
+}
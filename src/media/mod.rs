@@ -0,0 +1,226 @@
+use std::io::{self, Cursor, Read};
+
+pub mod lifecycle;
+pub mod metadata;
+pub mod variants;
+
+/// Number of leading bytes needed to detect every supported media type.
+pub const SNIFF_LEN: usize = 12;
+
+/// The kinds of media accepted for uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Audio,
+    Video,
+}
+
+/// The media types accepted for uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Jpeg,
+    Png,
+    Heic,
+    Heif,
+    Webp,
+    Mp3,
+    M4a,
+    Aac,
+    Ogg,
+    Wav,
+    Mp4,
+    QuickTime,
+    Webm,
+}
+
+impl MediaType {
+    pub const ALL: [MediaType; 13] = [
+        MediaType::Jpeg,
+        MediaType::Png,
+        MediaType::Heic,
+        MediaType::Heif,
+        MediaType::Webp,
+        MediaType::Mp3,
+        MediaType::M4a,
+        MediaType::Aac,
+        MediaType::Ogg,
+        MediaType::Wav,
+        MediaType::Mp4,
+        MediaType::QuickTime,
+        MediaType::Webm,
+    ];
+
+    /// Looks up a supported media type by its MIME type.
+    pub fn from_mime(mime: &str) -> Option<MediaType> {
+        MediaType::ALL
+            .iter()
+            .find(|t| t.mime().eq_ignore_ascii_case(mime.trim()))
+            .cloned()
+    }
+
+    /// Detects the media type of a file from its leading magic bytes.
+    pub fn sniff(head: &[u8]) -> Option<MediaType> {
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+        if head.starts_with(b"\xff\xd8\xff") {
+            return Some(MediaType::Jpeg);
+        }
+
+        if head.starts_with(PNG) {
+            return Some(MediaType::Png);
+        }
+
+        if head.len() >= 12 && &head[..4] == b"RIFF" {
+            return match &head[8..12] {
+                b"WEBP" => Some(MediaType::Webp),
+                b"WAVE" => Some(MediaType::Wav),
+                _ => None,
+            };
+        }
+
+        // ISO base media files start with a box size followed by `ftyp` and
+        // the major brand
+        if head.len() >= 12 && &head[4..8] == b"ftyp" {
+            return match &head[8..12] {
+                b"heic" | b"heix" | b"hevc" | b"hevx" => Some(MediaType::Heic),
+                b"mif1" | b"msf1" | b"heim" | b"heis" => Some(MediaType::Heif),
+                b"M4A " | b"M4B " => Some(MediaType::M4a),
+                b"qt  " => Some(MediaType::QuickTime),
+                b"isom" | b"iso2" | b"mp41" | b"mp42" | b"avc1" | b"3gp4"
+                | b"3gp5" => Some(MediaType::Mp4),
+                _ => None,
+            };
+        }
+
+        if head.starts_with(b"ID3") {
+            return Some(MediaType::Mp3);
+        }
+
+        if head.starts_with(b"OggS") {
+            return Some(MediaType::Ogg);
+        }
+
+        if head.starts_with(b"\x1a\x45\xdf\xa3") {
+            return Some(MediaType::Webm);
+        }
+
+        // MPEG audio frames start with an 11 bit sync word, ADTS frames of
+        // AAC audio have layer bits of zero
+        if head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0 {
+            return match head[1] & 0x06 {
+                0x00 => Some(MediaType::Aac),
+                _ => Some(MediaType::Mp3),
+            };
+        }
+
+        None
+    }
+
+    pub fn kind(&self) -> MediaKind {
+        match *self {
+            MediaType::Jpeg
+            | MediaType::Png
+            | MediaType::Heic
+            | MediaType::Heif
+            | MediaType::Webp => MediaKind::Image,
+            MediaType::Mp3
+            | MediaType::M4a
+            | MediaType::Aac
+            | MediaType::Ogg
+            | MediaType::Wav => MediaKind::Audio,
+            MediaType::Mp4 | MediaType::QuickTime | MediaType::Webm => {
+                MediaKind::Video
+            }
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match *self {
+            MediaType::Jpeg => "image/jpeg",
+            MediaType::Png => "image/png",
+            MediaType::Heic => "image/heic",
+            MediaType::Heif => "image/heif",
+            MediaType::Webp => "image/webp",
+            MediaType::Mp3 => "audio/mpeg",
+            MediaType::M4a => "audio/mp4",
+            MediaType::Aac => "audio/aac",
+            MediaType::Ogg => "audio/ogg",
+            MediaType::Wav => "audio/wav",
+            MediaType::Mp4 => "video/mp4",
+            MediaType::QuickTime => "video/quicktime",
+            MediaType::Webm => "video/webm",
+        }
+    }
+}
+
+/// A reader with its leading bytes put back in front of the remainder.
+pub type Sniffed<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads the leading bytes of `data` to detect its media type.
+/// Returns the detected type, if supported, along with a reader that still
+/// yields the complete data.
+pub fn sniff<R: Read>(
+    mut data: R,
+) -> io::Result<(Option<MediaType>, Sniffed<R>)> {
+    let mut head = vec![0; SNIFF_LEN];
+    let mut len = 0;
+
+    while len < SNIFF_LEN {
+        match data.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    head.truncate(len);
+    let media_type = MediaType::sniff(&head);
+
+    Ok((media_type, Cursor::new(head).chain(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_types() {
+        let cases: &[(&[u8], Option<MediaType>)] = &[
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", Some(MediaType::Jpeg)),
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", Some(MediaType::Png)),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Some(MediaType::Webp)),
+            (b"\0\0\0\x18ftypheic\0\0\0\0", Some(MediaType::Heic)),
+            (b"\0\0\0\x18ftypmif1\0\0\0\0", Some(MediaType::Heif)),
+            (b"\0\0\0\x18ftypisom\0\0\0\0", Some(MediaType::Mp4)),
+            (b"\0\0\0\x18ftypM4A \0\0\0\0", Some(MediaType::M4a)),
+            (b"\0\0\0\x14ftypqt  \0\0\0\0", Some(MediaType::QuickTime)),
+            (b"\0\0\0\x18ftypcrx \0\0\0\0", None),
+            (b"RIFF\x24\0\0\0WAVEfmt ", Some(MediaType::Wav)),
+            (b"ID3\x04\0\0\0\0\0\0", Some(MediaType::Mp3)),
+            (b"\xff\xfb\x90\x44", Some(MediaType::Mp3)),
+            (b"\xff\xf1\x50\x80", Some(MediaType::Aac)),
+            (b"OggS\0\x02\0\0", Some(MediaType::Ogg)),
+            (b"\x1a\x45\xdf\xa3\x9f\x42\x86", Some(MediaType::Webm)),
+            (b"GIF89a", None),
+            (b"", None),
+        ];
+
+        for &(head, expected) in cases {
+            assert_eq!(MediaType::sniff(head), expected);
+        }
+    }
+
+    #[test]
+    fn sniff_keeps_data() {
+        let data = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR and more".to_vec();
+        let (media_type, mut reader) =
+            sniff(Cursor::new(data.clone())).expect("failed to sniff");
+
+        let mut result = Vec::new();
+        reader.read_to_end(&mut result).expect("failed to read");
+
+        assert_eq!(media_type, Some(MediaType::Png));
+        assert_eq!(result, data);
+    }
+}
@@ -0,0 +1,308 @@
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use image::jpeg::JPEGDecoder;
+use image::png::PNGDecoder;
+use image::{self, DynamicImage, FilterType, GenericImage, ImageDecoder,
+            ImageError, ImageOutputFormat, ImageResult};
+use rocket::http::RawStr;
+use rocket::request::FromFormValue;
+
+use media::MediaType;
+use storage::{Storage, StorageResult};
+
+/// JPEG quality of generated variants.
+const QUALITY: u8 = 85;
+
+/// Images with more pixels than this are not decoded, as decoding them would
+/// take too much memory.
+const MAX_PIXELS: u64 = 50_000_000;
+
+/// The sizes in which images are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSize {
+    Thumb,
+    Medium,
+    Full,
+}
+
+impl ImageSize {
+    /// The sizes that are generated from the full image.
+    pub const GENERATED: [ImageSize; 2] = [ImageSize::Thumb, ImageSize::Medium];
+
+    /// The maximum width and height of the variant, if it is scaled down.
+    fn max_dimension(&self) -> Option<u32> {
+        match *self {
+            ImageSize::Thumb => Some(256),
+            ImageSize::Medium => Some(1280),
+            ImageSize::Full => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ImageSize::Thumb => "thumb",
+            ImageSize::Medium => "medium",
+            ImageSize::Full => "full",
+        }
+    }
+
+    /// The storage key of the variant of the image stored under `key`.
+    pub fn key(&self, key: &str) -> String {
+        match *self {
+            ImageSize::Full => key.to_string(),
+            size => format!("{}.{}", key, size.as_str()),
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for ImageSize {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, &'v RawStr> {
+        match form_value.as_str() {
+            "thumb" => Ok(ImageSize::Thumb),
+            "medium" => Ok(ImageSize::Medium),
+            "full" => Ok(ImageSize::Full),
+            _ => Err(form_value),
+        }
+    }
+}
+
+/// Reads the width and height of a lossy WebP image from its VP8 frame header.
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    // the RIFF header is followed by the `VP8 ` chunk header, the frame tag
+    // and the start code of the key frame
+    if bytes.len() < 30
+        || &bytes[12..16] != b"VP8 "
+        || &bytes[23..26] != b"\x9d\x01\x2a"
+    {
+        return None;
+    }
+
+    let dimension = |i: usize| {
+        (u32::from(bytes[i]) | u32::from(bytes[i + 1]) << 8) & 0x3fff
+    };
+    Some((dimension(26), dimension(28)))
+}
+
+/// Reads the width and height of an image from its header, without decoding
+/// the image.
+fn dimensions(bytes: &[u8]) -> ImageResult<(u32, u32)> {
+    let unsupported =
+        || ImageError::UnsupportedError("unknown image header".to_string());
+
+    let reader = Cursor::new(bytes);
+    match MediaType::sniff(bytes) {
+        Some(MediaType::Jpeg) => JPEGDecoder::new(reader).dimensions(),
+        Some(MediaType::Png) => PNGDecoder::new(reader).dimensions(),
+        Some(MediaType::Webp) => webp_dimensions(bytes).ok_or_else(unsupported),
+        _ => Err(unsupported()),
+    }
+}
+
+/// Whether an image of the given dimensions is small enough to be decoded.
+fn decodable((width, height): (u32, u32)) -> bool {
+    u64::from(width) * u64::from(height) <= MAX_PIXELS
+}
+
+/// Scales an image down to fit `size`, and encodes it as JPEG.
+fn render(
+    image: &DynamicImage,
+    size: ImageSize,
+) -> image::ImageResult<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let mut buf = Vec::new();
+
+    match size.max_dimension() {
+        Some(max) if width > max || height > max => image
+            .resize(max, max, FilterType::Triangle)
+            .write_to(&mut buf, ImageOutputFormat::JPEG(QUALITY))?,
+        _ => image.write_to(&mut buf, ImageOutputFormat::JPEG(QUALITY))?,
+    }
+
+    Ok(buf)
+}
+
+/// Generates and stores all variants of the image stored under `key`.
+fn generate(storage: &Storage, key: &str) -> StorageResult<()> {
+    let original = storage.get(key)?.into_bytes()?;
+
+    // check the dimensions first, as small files may decode to huge images
+    match dimensions(&original) {
+        Ok(size) if decodable(size) => (),
+        Ok((width, height)) => {
+            warn!(
+                "Image {} is too large for variants: {}x{}",
+                key, width, height
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            // e.g. HEIC images, which are only served in full
+            warn!("Cannot read image {} for variants -- {:?}", key, e);
+            return Ok(());
+        }
+    }
+
+    let image = match image::load_from_memory(&original) {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("Cannot decode image {} for variants -- {:?}", key, e);
+            return Ok(());
+        }
+    };
+
+    for size in &ImageSize::GENERATED {
+        match render(&image, *size) {
+            Ok(variant) => {
+                storage.put(
+                    &size.key(key),
+                    Some("image/jpeg"),
+                    &mut Cursor::new(variant),
+                )?;
+            }
+            Err(e) => {
+                error!("Failed to render {:?} of {} -- {:?}", size, key, e)
+            }
+        }
+    }
+
+    debug!("Generated variants of image {}", key);
+    Ok(())
+}
+
+/// Runs a job generating the variants of the image stored under `key`.
+/// A panic in the job, e.g. while decoding a malformed image, is caught so
+/// that it does not stop the background thread.
+/// Returns whether the job succeeded.
+fn run_job<F>(key: &str, job: F) -> bool
+where
+    F: FnOnce() -> StorageResult<()>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(job)) {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("Failed to generate variants of {} -- {:?}", key, e);
+            false
+        }
+        Err(_) => {
+            error!("Generating variants of {} panicked", key);
+            false
+        }
+    }
+}
+
+/// Generates image variants on a background thread, so uploads do not wait
+/// for them.
+pub struct VariantGenerator {
+    jobs: Mutex<Sender<String>>,
+}
+
+impl VariantGenerator {
+    /// Starts the background thread.
+    pub fn start(storage: Storage) -> Self {
+        let (sender, receiver) = channel::<String>();
+
+        thread::Builder::new()
+            .name("image-variants".to_string())
+            .spawn(move || {
+                for key in receiver {
+                    run_job(&key, || generate(&storage, &key));
+                }
+            })
+            .expect("failed to start image variant thread");
+
+        VariantGenerator {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    /// Schedules generation of the variants of the image stored under `key`.
+    pub fn schedule(&self, key: String) {
+        let result = self.jobs
+            .lock()
+            .expect("variant job queue poisoned")
+            .send(key);
+
+        if let Err(e) = result {
+            error!("Failed to schedule image variants -- {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::sync::Arc;
+    use storage::memory::MemoryStore;
+
+    #[test]
+    fn variant_keys() {
+        assert_eq!(ImageSize::Full.key("12"), "12");
+        assert_eq!(ImageSize::Thumb.key("12"), "12.thumb");
+        assert_eq!(ImageSize::Medium.key("12"), "12.medium");
+    }
+
+    #[test]
+    fn image_dimensions() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(300, 200));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("failed to encode image");
+        assert_eq!(dimensions(&png).ok(), Some((300, 200)));
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(b"\x9d\x01\x2a\xff\x3f\xff\x3f");
+        assert_eq!(dimensions(&webp).ok(), Some((16383, 16383)));
+        assert!(!decodable((16383, 16383)));
+        assert!(decodable((8000, 6000)));
+
+        assert!(dimensions(b"ID3 not an image").is_err());
+    }
+
+    #[test]
+    fn generate_variants() {
+        let storage: Storage = Arc::new(MemoryStore::default());
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2000, 1000));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("failed to encode image");
+        storage
+            .put("1", Some("image/png"), &mut Cursor::new(png))
+            .expect("failed to store image");
+
+        generate(&storage, "1").expect("failed to generate variants");
+
+        let thumb = storage
+            .get(&ImageSize::Thumb.key("1"))
+            .expect("missing thumbnail")
+            .into_bytes()
+            .expect("failed to read thumbnail");
+        let thumb =
+            image::load_from_memory(&thumb).expect("failed to decode thumb");
+
+        assert_eq!(thumb.dimensions(), (256, 128));
+    }
+
+    #[test]
+    fn corrupt_images() {
+        let storage: Storage = Arc::new(MemoryStore::default());
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend_from_slice(&[0xff; 64]);
+        storage
+            .put("1", Some("image/png"), &mut Cursor::new(png))
+            .expect("failed to store image");
+
+        assert!(run_job("1", || generate(&storage, "1")));
+        assert!(storage.get(&ImageSize::Thumb.key("1")).is_err());
+
+        assert!(!run_job("2", || panic!("malformed image")));
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Blob, BlobStore, StorageError, StorageResult};
+
+/// Numbers the temporary files written by this process.
+static PARTIAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Stores objects as files below a root directory. The content type of an
+/// object is kept in a `.content-type` file next to it.
+#[derive(Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    /// Creates a store rooted at `root`, creating the directory if needed.
+    pub fn new<P: Into<PathBuf>>(root: P) -> StorageResult<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(LocalStore { root })
+    }
+
+    /// Resolves a key to a path, rejecting keys that escape the root.
+    fn path(&self, key: &str) -> StorageResult<PathBuf> {
+        let relative = Path::new(key);
+        let valid = !key.is_empty()
+            && relative.components().all(|c| match c {
+                Component::Normal(_) => true,
+                _ => false,
+            });
+
+        if !valid {
+            return Err(StorageError::InvalidKey(key.to_string()));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    /// Appends `suffix` to the file name of `path`.
+    fn sibling(path: &Path, suffix: &str) -> PathBuf {
+        let mut name = path.file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_default();
+        name.push(suffix);
+        path.with_file_name(name)
+    }
+}
+
+/// Collects the keys of the objects below `dir`, which has the key `prefix`.
+/// Content type and partially written files are skipped.
+fn collect_keys(
+    dir: &Path,
+    prefix: &str,
+    keys: &mut Vec<String>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let key = format!("{}{}", prefix, name);
+
+        if entry.file_type()?.is_dir() {
+            collect_keys(&entry.path(), &format!("{}/", key), keys)?;
+        } else if !name.ends_with(".content-type")
+            && !name.ends_with(".partial")
+        {
+            keys.push(key);
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file, ignoring files that do not exist.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        data: &mut Read,
+    ) -> StorageResult<u64> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // write to a temporary file first, so failed uploads do not replace
+        // the existing object, named uniquely so that concurrent writes of
+        // the same key do not write to the same file
+        let suffix = format!(
+            ".{}-{}.partial",
+            process::id(),
+            PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let partial = LocalStore::sibling(&path, &suffix);
+        let size = File::create(&partial)
+            .and_then(|mut file| {
+                let size = io::copy(data, &mut file)?;
+                file.flush()?;
+                Ok(size)
+            })
+            .map_err(|e| {
+                let _ = fs::remove_file(&partial);
+                e
+            })?;
+        fs::rename(&partial, &path)?;
+
+        let type_path = LocalStore::sibling(&path, ".content-type");
+        match content_type {
+            Some(content_type) => {
+                File::create(&type_path)?.write_all(content_type.as_bytes())?
+            }
+            None => remove_if_exists(&type_path)?,
+        }
+
+        Ok(size)
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Blob> {
+        let path = self.path(key)?;
+
+        let file = File::open(&path)?;
+        let length = file.metadata()?.len();
+        let content_type =
+            fs::read_to_string(LocalStore::sibling(&path, ".content-type"))
+                .ok();
+
+        Ok(Blob {
+            content_type,
+            length: Some(length),
+            body: Box::new(file),
+        })
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Blob> {
+        let path = self.path(key)?;
+
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let content_type =
+            fs::read_to_string(LocalStore::sibling(&path, ".content-type"))
+                .ok();
+
+        Ok(Blob {
+            content_type,
+            length: Some(end - start + 1),
+            body: Box::new(file.take(end - start + 1)),
+        })
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        let path = self.path(key)?;
+
+        remove_if_exists(&LocalStore::sibling(&path, ".content-type"))?;
+        remove_if_exists(&path)?;
+
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let from = self.path(from)?;
+        let to = self.path(to)?;
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::rename(&from, &to)?;
+
+        let from_type = LocalStore::sibling(&from, ".content-type");
+        let to_type = LocalStore::sibling(&to, ".content-type");
+        match fs::rename(&from_type, &to_type) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                remove_if_exists(&to_type)?
+            }
+            result => result?,
+        }
+
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let mut keys = Vec::new();
+        collect_keys(&self.root, "", &mut keys)?;
+        keys.retain(|key| key.starts_with(prefix));
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+
+    #[test]
+    fn put_get_delete() {
+        let root = env::temp_dir().join("journaloo-local-store-test");
+        let store = LocalStore::new(&root).expect("failed to create store");
+
+        store
+            .put(
+                "images/1",
+                Some("image/jpeg"),
+                &mut Cursor::new(vec![4, 5, 6]),
+            )
+            .expect("failed to put object");
+
+        let blob = store.get("images/1").expect("failed to get object");
+        assert_eq!(blob.content_type, Some("image/jpeg".to_string()));
+        assert_eq!(blob.length, Some(3));
+        assert_eq!(blob.into_bytes().expect("failed to read"), vec![4, 5, 6]);
+
+        store.delete("images/1").expect("failed to delete object");
+        match store.get("images/1") {
+            Err(StorageError::NotFound) => (),
+            Err(e) => panic!("failed to get object -- {:?}", e),
+            Ok(_) => panic!("object not deleted"),
+        }
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn list() {
+        let root = env::temp_dir().join("journaloo-local-store-list");
+        let store = LocalStore::new(&root).expect("failed to create store");
+
+        for key in &["attachments/1", "attachments/1.thumb", "2"] {
+            store
+                .put(key, Some("image/jpeg"), &mut Cursor::new(vec![1]))
+                .expect("failed to put object");
+        }
+
+        let mut keys = store.list("attachments/").expect("failed to list");
+        keys.sort();
+        assert_eq!(keys, vec!["attachments/1", "attachments/1.thumb"]);
+        assert_eq!(store.list("").expect("failed to list").len(), 3);
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let root = env::temp_dir().join("journaloo-local-store-keys");
+        let store = LocalStore::new(&root).expect("failed to create store");
+
+        for key in &["", "../secret", "/etc/passwd", "a/../../b"] {
+            match store.get(key) {
+                Err(StorageError::InvalidKey(_)) => (),
+                Err(e) => panic!("unexpected error for {} -- {:?}", key, e),
+                Ok(_) => panic!("accepted key {}", key),
+            }
+        }
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
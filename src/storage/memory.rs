@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::RwLock;
+
+use super::{Blob, BlobStore, StorageError, StorageResult};
+
+#[derive(Debug)]
+struct Object {
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+/// Keeps objects in process memory, for development and testing.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: RwLock<HashMap<String, Object>>,
+}
+
+impl BlobStore for MemoryStore {
+    fn put(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        data: &mut Read,
+    ) -> StorageResult<u64> {
+        let mut buf = Vec::new();
+        let size = data.read_to_end(&mut buf)? as u64;
+
+        let object = Object {
+            content_type: content_type.map(ToString::to_string),
+            data: buf,
+        };
+
+        self.objects
+            .write()
+            .map_err(|e| StorageError::Backend(e.to_string()))?
+            .insert(key.to_string(), object);
+
+        Ok(size)
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Blob> {
+        let objects = self.objects
+            .read()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let object = objects.get(key).ok_or(StorageError::NotFound)?;
+
+        Ok(Blob {
+            content_type: object.content_type.clone(),
+            length: Some(object.data.len() as u64),
+            body: Box::new(Cursor::new(object.data.clone())),
+        })
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        self.objects
+            .write()
+            .map_err(|e| StorageError::Backend(e.to_string()))?
+            .remove(key);
+
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let mut objects = self.objects
+            .write()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let object = objects.remove(from).ok_or(StorageError::NotFound)?;
+        objects.insert(to.to_string(), object);
+
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let objects = self.objects
+            .read()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        Ok(objects
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_get_delete() {
+        let store = MemoryStore::default();
+        let size = store
+            .put("1", Some("image/png"), &mut Cursor::new(vec![1, 2, 3]))
+            .expect("failed to put object");
+        assert_eq!(size, 3);
+
+        let blob = store.get("1").expect("failed to get object");
+        assert_eq!(blob.content_type, Some("image/png".to_string()));
+        assert_eq!(blob.length, Some(3));
+        assert_eq!(blob.into_bytes().expect("failed to read"), vec![1, 2, 3]);
+
+        store.delete("1").expect("failed to delete object");
+        match store.get("1") {
+            Err(StorageError::NotFound) => (),
+            Err(e) => panic!("failed to get object -- {:?}", e),
+            Ok(_) => panic!("object not deleted"),
+        }
+    }
+
+    #[test]
+    fn list() {
+        let store = MemoryStore::default();
+        for key in &["attachments/1", "attachments/1.thumb", "2"] {
+            store
+                .put(key, None, &mut Cursor::new(vec![1]))
+                .expect("failed to put object");
+        }
+
+        let mut keys = store.list("attachments/").expect("failed to list");
+        keys.sort();
+        assert_eq!(keys, vec!["attachments/1", "attachments/1.thumb"]);
+        assert_eq!(store.list("").expect("failed to list").len(), 3);
+    }
+}
@@ -0,0 +1,320 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+use self::local::LocalStore;
+use self::memory::MemoryStore;
+use self::s3::S3Store;
+
+/// The body of a stored object.
+pub type BlobBody = Box<Read + Send>;
+
+/// A stored object along with its metadata.
+pub struct Blob {
+    pub content_type: Option<String>,
+    pub length: Option<u64>,
+    pub body: BlobBody,
+}
+
+impl Blob {
+    /// Reads the whole body of the object into memory.
+    pub fn into_bytes(mut self) -> StorageResult<Vec<u8>> {
+        let mut data = Vec::new();
+        self.body.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    TooLarge,
+    InvalidKey(String),
+    Io(io::Error),
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::NotFound => write!(f, "object not found"),
+            StorageError::TooLarge => write!(f, "object too large"),
+            StorageError::InvalidKey(ref key) => {
+                write!(f, "invalid object key: {}", key)
+            }
+            StorageError::Io(ref e) => write!(f, "io error: {}", e),
+            StorageError::Backend(ref msg) => {
+                write!(f, "backend error: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn description(&self) -> &str {
+        match *self {
+            StorageError::NotFound => "object not found",
+            StorageError::TooLarge => "object too large",
+            StorageError::InvalidKey(_) => "invalid object key",
+            StorageError::Io(ref e) => e.description(),
+            StorageError::Backend(ref msg) => msg,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        let too_large = e.get_ref().map_or(false, |e| e.is::<TooLarge>());
+
+        match e.kind() {
+            _ if too_large => StorageError::TooLarge,
+            io::ErrorKind::NotFound => StorageError::NotFound,
+            _ => StorageError::Io(e),
+        }
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// A key-value store for media objects.
+pub trait BlobStore: Send + Sync {
+    /// Streams `data` into an object under `key`, replacing any existing
+    /// object. Returns the number of bytes stored.
+    fn put(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        data: &mut Read,
+    ) -> StorageResult<u64>;
+
+    /// Retrieves the object stored under `key`.
+    /// If there is no such object, fails with `StorageError::NotFound`.
+    fn get(&self, key: &str) -> StorageResult<Blob>;
+
+    /// Retrieves the bytes `start..=end` of the object stored under `key`.
+    /// The default implementation skips the leading bytes of the object.
+    fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Blob> {
+        let mut blob = self.get(key)?;
+        io::copy(&mut (&mut blob.body).take(start), &mut io::sink())?;
+
+        Ok(Blob {
+            content_type: blob.content_type,
+            length: Some(end - start + 1),
+            body: Box::new(blob.body.take(end - start + 1)),
+        })
+    }
+
+    /// Retrieves the size in bytes of the object stored under `key`.
+    /// The default implementation opens the object.
+    fn size(&self, key: &str) -> StorageResult<u64> {
+        let blob = self.get(key)?;
+        match blob.length {
+            Some(length) => Ok(length),
+            None => {
+                let mut body = blob.body;
+                Ok(io::copy(&mut body, &mut io::sink())?)
+            }
+        }
+    }
+
+    /// Removes the object stored under `key`, if any.
+    fn delete(&self, key: &str) -> StorageResult<()>;
+
+    /// Lists the keys of all objects whose key starts with `prefix`.
+    fn list(&self, prefix: &str) -> StorageResult<Vec<String>>;
+
+    /// Moves the object stored under `from` to `to`, replacing any existing
+    /// object. The default implementation copies the object.
+    fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let mut blob = self.get(from)?;
+        let content_type = blob.content_type.take();
+        let content_type = content_type.as_ref().map(String::as_str);
+        self.put(to, content_type, &mut blob.body)?;
+        self.delete(from)
+    }
+
+    /// Creates a URL through which clients can download the object stored
+    /// under `key` directly, valid for `ttl`.
+    /// Returns `None` if the backend does not support this.
+    fn presign_get(
+        &self,
+        _key: &str,
+        _ttl: Duration,
+    ) -> StorageResult<Option<String>> {
+        Ok(None)
+    }
+
+    /// Creates a URL through which clients can upload an object under `key`
+    /// directly with a `PUT` request, valid for `ttl`.
+    /// Returns `None` if the backend does not support this.
+    fn presign_put(
+        &self,
+        _key: &str,
+        _content_type: &str,
+        _ttl: Duration,
+    ) -> StorageResult<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// The blob store managed as Rocket state. Shared so background jobs can
+/// access it too.
+pub type Storage = Arc<BlobStore>;
+
+/// Initializes the blob store selected by the `STORAGE_BACKEND` variable.
+/// Supports `s3` (the default), `local` and `memory`.
+pub fn init_storage() -> Storage {
+    let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "s3".into());
+
+    match backend.as_str() {
+        "s3" => Arc::new(
+            S3Store::from_env().expect("failed to initialize S3 storage"),
+        ),
+        "local" => {
+            let root =
+                env::var("STORAGE_PATH").expect("STORAGE_PATH must be set");
+            Arc::new(
+                LocalStore::new(root)
+                    .expect("failed to initialize local storage"),
+            )
+        }
+        "memory" => Arc::new(MemoryStore::default()),
+        other => panic!("unknown STORAGE_BACKEND: {}", other),
+    }
+}
+
+/// Marker error for reads that exceeded their size limit.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "size limit exceeded")
+    }
+}
+
+impl Error for TooLarge {
+    fn description(&self) -> &str {
+        "size limit exceeded"
+    }
+}
+
+/// Reader that fails once more than `limit` bytes have been read. Storing
+/// from it fails with `StorageError::TooLarge` when the limit is exceeded.
+pub struct SizeLimited<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> SizeLimited<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        SizeLimited {
+            inner,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: Read> Read for SizeLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // check whether the stream has ended exactly at the limit
+            let mut probe = [0; 1];
+            return match self.inner.read(&mut probe)? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(io::ErrorKind::Other, TooLarge)),
+            };
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..max])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Reader that computes the SHA-256 hash of the bytes that pass through it.
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Hashing<R> {
+    pub fn new(inner: R) -> Self {
+        Hashing {
+            inner,
+            hasher: Sha256::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The hex-encoded hash of the bytes read so far.
+    pub fn hash(&self) -> String {
+        self.hasher
+            .clone()
+            .result()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn size_limited() {
+        let mut buf = Vec::new();
+        SizeLimited::new(Cursor::new(vec![0; 10]), 10)
+            .read_to_end(&mut buf)
+            .expect("failed to read within limit");
+        assert_eq!(buf.len(), 10);
+
+        let e = SizeLimited::new(Cursor::new(vec![0; 11]), 10)
+            .read_to_end(&mut buf)
+            .unwrap_err();
+        match StorageError::from(e) {
+            StorageError::TooLarge => (),
+            e => panic!("unexpected error -- {:?}", e),
+        }
+    }
+
+    #[test]
+    fn hashing() {
+        let mut data = Hashing::new(Cursor::new(b"abc".to_vec()));
+        io::copy(&mut data, &mut io::sink()).expect("failed to read");
+
+        assert_eq!(
+            data.hash(),
+            "ba7816bf8f01cfea414140de5dae2223\
+             b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use std::env;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::stream::Wait;
+use futures::{Future, Stream};
+use rusoto_core::region::Region;
+use rusoto_core::{DefaultCredentialsProvider, ProvideAwsCredentials};
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+use rusoto_s3::{AbortMultipartUploadRequest, CompleteMultipartUploadRequest,
+                CompletedMultipartUpload, CompletedPart, CopyObjectRequest,
+                CreateMultipartUploadRequest, DeleteObjectRequest,
+                GetObjectError, GetObjectRequest, HeadObjectError,
+                HeadObjectRequest, ListObjectsV2Request, PutObjectRequest,
+                S3, S3Client, StreamingBody, UploadPartRequest};
+
+use super::{Blob, BlobStore, StorageError, StorageResult};
+
+/// Size of the parts of multipart uploads. Objects smaller than this are
+/// uploaded with a single request. S3 requires parts of at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Stores objects in an S3 bucket, or any S3-compatible service such as
+/// MinIO when a custom endpoint is configured.
+pub struct S3Store {
+    client: S3Client,
+    region: Region,
+    credentials: DefaultCredentialsProvider,
+    bucket: String,
+    prefix: String,
+}
+
+fn backend_err<E: ToString>(e: E) -> StorageError {
+    StorageError::Backend(e.to_string())
+}
+
+/// The `x-amz-copy-source` value of an object, which must be URL-encoded.
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut source = format!("{}/", bucket);
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            source.push(byte as char);
+        } else {
+            source.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    source
+}
+
+/// Reads until `buf` is full or the reader is exhausted.
+fn read_part(data: &mut Read, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.clear();
+    data.take(PART_SIZE as u64).read_to_end(buf)
+}
+
+impl S3Store {
+    /// Creates a store for a bucket.
+    /// If no AWS credentials can be found, fails with
+    /// `StorageError::Backend`.
+    pub fn new(
+        region: Region,
+        bucket: String,
+        prefix: String,
+    ) -> StorageResult<Self> {
+        let credentials = DefaultCredentialsProvider::new().map_err(|e| {
+            backend_err(format!("failed to initialize AWS credentials: {}", e))
+        })?;
+
+        Ok(S3Store {
+            client: S3Client::simple(region.clone()),
+            region,
+            credentials,
+            bucket,
+            prefix,
+        })
+    }
+
+    /// Configures the store from the environment.
+    /// `S3_BUCKET` is required. `S3_REGION` defaults to `eu-central-1`,
+    /// `S3_ENDPOINT` selects a custom S3-compatible endpoint, and
+    /// `S3_KEY_PREFIX` is prepended to every object key.
+    /// If the bucket is not set or the region is not valid, fails with
+    /// `StorageError::Backend`.
+    pub fn from_env() -> StorageResult<Self> {
+        let bucket = env::var("S3_BUCKET")
+            .map_err(|_| backend_err("S3_BUCKET must be set"))?;
+        let region_name =
+            env::var("S3_REGION").unwrap_or_else(|_| "eu-central-1".into());
+
+        let region = match env::var("S3_ENDPOINT") {
+            Ok(endpoint) => Region::Custom {
+                name: region_name,
+                endpoint,
+            },
+            Err(_) => Region::from_str(&region_name).map_err(|e| {
+                backend_err(format!("invalid S3_REGION {}: {}", region_name, e))
+            })?,
+        };
+
+        let prefix = env::var("S3_KEY_PREFIX").unwrap_or_default();
+
+        S3Store::new(region, bucket, prefix)
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn presign_option(ttl: Duration) -> PreSignedRequestOption {
+        PreSignedRequestOption { expires_in: ttl }
+    }
+
+    /// Retrieves an object, or the byte range of it given as a `Range`
+    /// header value.
+    fn get_object(
+        &self,
+        key: &str,
+        range: Option<String>,
+    ) -> StorageResult<Blob> {
+        let mut request = GetObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(key);
+        request.range = range;
+
+        let output = self.client
+            .get_object(&request)
+            .sync()
+            .map_err(|e| match e {
+                GetObjectError::NoSuchKey(_msg) => StorageError::NotFound,
+                e => backend_err(e),
+            })?;
+
+        let body = output
+            .body
+            .ok_or_else(|| backend_err("missing body in response"))?;
+
+        Ok(Blob {
+            content_type: output.content_type,
+            length: output.content_length.map(|l| l as u64),
+            body: Box::new(BodyReader::new(body)),
+        })
+    }
+
+    /// Uploads the remainder of `data` as the parts of a multipart upload,
+    /// starting with the already read `first` part.
+    fn put_multipart(
+        &self,
+        key: String,
+        content_type: Option<&str>,
+        first: Vec<u8>,
+        data: &mut Read,
+    ) -> StorageResult<u64> {
+        let mut request = CreateMultipartUploadRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = key.clone();
+        request.content_type = content_type.map(ToString::to_string);
+
+        let upload_id = self.client
+            .create_multipart_upload(&request)
+            .sync()
+            .map_err(backend_err)?
+            .upload_id
+            .ok_or_else(|| backend_err("missing upload id in response"))?;
+
+        match self.upload_parts(&key, &upload_id, first, data) {
+            Ok((size, parts)) => {
+                let mut request = CompleteMultipartUploadRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = key;
+                request.upload_id = upload_id;
+                request.multipart_upload = Some(CompletedMultipartUpload {
+                    parts: Some(parts),
+                });
+
+                self.client
+                    .complete_multipart_upload(&request)
+                    .sync()
+                    .map_err(backend_err)?;
+
+                Ok(size)
+            }
+            Err(e) => {
+                let mut request = AbortMultipartUploadRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = key;
+                request.upload_id = upload_id;
+
+                if let Err(abort) =
+                    self.client.abort_multipart_upload(&request).sync()
+                {
+                    error!("Failed to abort multipart upload -- {:?}", abort);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        data: &mut Read,
+    ) -> StorageResult<(u64, Vec<CompletedPart>)> {
+        let mut parts = Vec::new();
+        let mut size = 0;
+        let mut part = first;
+
+        while !part.is_empty() {
+            let part_number = parts.len() as i64 + 1;
+            size += part.len() as u64;
+
+            let mut request = UploadPartRequest::default();
+            request.bucket = self.bucket.clone();
+            request.key = key.to_string();
+            request.upload_id = upload_id.to_string();
+            request.part_number = part_number;
+            request.body = Some(part);
+
+            let e_tag = self.client
+                .upload_part(&request)
+                .sync()
+                .map_err(backend_err)?
+                .e_tag;
+
+            parts.push(CompletedPart {
+                e_tag,
+                part_number: Some(part_number),
+            });
+
+            part = Vec::with_capacity(PART_SIZE);
+            read_part(data, &mut part)?;
+        }
+
+        Ok((size, parts))
+    }
+}
+
+impl BlobStore for S3Store {
+    fn put(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        data: &mut Read,
+    ) -> StorageResult<u64> {
+        let key = self.key(key);
+        let mut first = Vec::with_capacity(PART_SIZE);
+        let read = read_part(data, &mut first)?;
+
+        if read == PART_SIZE {
+            return self.put_multipart(key, content_type, first, data);
+        }
+
+        let mut request = PutObjectRequest::default();
+        request.content_type = content_type.map(ToString::to_string);
+        request.bucket = self.bucket.clone();
+        request.key = key;
+        request.body = Some(first);
+
+        self.client
+            .put_object(&request)
+            .sync()
+            .map_err(backend_err)?;
+
+        Ok(read as u64)
+    }
+
+    fn get(&self, key: &str) -> StorageResult<Blob> {
+        self.get_object(key, None)
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Blob> {
+        self.get_object(key, Some(format!("bytes={}-{}", start, end)))
+    }
+
+    fn size(&self, key: &str) -> StorageResult<u64> {
+        let mut request = HeadObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(key);
+
+        let output = self.client
+            .head_object(&request)
+            .sync()
+            .map_err(|e| match e {
+                HeadObjectError::NoSuchKey(_msg) => StorageError::NotFound,
+                e => backend_err(e),
+            })?;
+
+        output
+            .content_length
+            .map(|l| l as u64)
+            .ok_or_else(|| backend_err("missing length in response"))
+    }
+
+    fn delete(&self, key: &str) -> StorageResult<()> {
+        let mut request = DeleteObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(key);
+
+        self.client
+            .delete_object(&request)
+            .sync()
+            .map_err(backend_err)?;
+
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> StorageResult<()> {
+        let mut request = CopyObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(to);
+        request.copy_source = copy_source(&self.bucket, &self.key(from));
+
+        self.client
+            .copy_object(&request)
+            .sync()
+            .map_err(backend_err)?;
+
+        self.delete(from)
+    }
+
+    fn list(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let mut request = ListObjectsV2Request::default();
+            request.bucket = self.bucket.clone();
+            request.prefix = Some(self.key(prefix));
+            request.continuation_token = continuation_token;
+
+            let output = self.client
+                .list_objects_v2(&request)
+                .sync()
+                .map_err(backend_err)?;
+
+            let objects = output.contents.unwrap_or_default();
+            keys.extend(
+                objects
+                    .into_iter()
+                    .filter_map(|object| object.key)
+                    .filter_map(|key| {
+                        key.get(self.prefix.len()..).map(ToString::to_string)
+                    }),
+            );
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    continuation_token = Some(token)
+                }
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    fn presign_get(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> StorageResult<Option<String>> {
+        let mut request = GetObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(key);
+
+        let credentials =
+            self.credentials.credentials().wait().map_err(backend_err)?;
+        let url = request.get_presigned_url(
+            &self.region,
+            &credentials,
+            &S3Store::presign_option(ttl),
+        );
+
+        Ok(Some(url))
+    }
+
+    fn presign_put(
+        &self,
+        key: &str,
+        content_type: &str,
+        ttl: Duration,
+    ) -> StorageResult<Option<String>> {
+        let mut request = PutObjectRequest::default();
+        request.bucket = self.bucket.clone();
+        request.key = self.key(key);
+        request.content_type = Some(content_type.to_string());
+
+        let credentials =
+            self.credentials.credentials().wait().map_err(backend_err)?;
+        let url = request.get_presigned_url(
+            &self.region,
+            &credentials,
+            &S3Store::presign_option(ttl),
+        );
+
+        Ok(Some(url))
+    }
+}
+
+/// Adapts a streaming S3 response body to a blocking reader, so it can be
+/// streamed to the client chunk by chunk.
+struct BodyReader {
+    chunks: Wait<StreamingBody>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl BodyReader {
+    fn new(body: StreamingBody) -> Self {
+        BodyReader {
+            chunks: body.wait(),
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_sources() {
+        assert_eq!(
+            copy_source("media", "attachments/1"),
+            "media/attachments/1"
+        );
+        assert_eq!(
+            copy_source("media", "uploads/a b+c%.jpg"),
+            "media/uploads/a%20b%2Bc%25.jpg"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+#![allow(non_upper_case_globals)]
+
+extern crate dotenv;
+extern crate journaloo_server;
+extern crate jsonwebtoken as jwt;
+#[macro_use]
+extern crate lazy_static;
+extern crate rocket;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use journaloo_server::rocket as launch;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::{Client, LocalRequest};
+
+#[derive(Serialize, Debug)]
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub password: &'a str,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct UserInfo {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewJourney<'a> {
+    pub user_id: i32,
+    pub title: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewEntry {
+    pub user_id: i32,
+    pub journey_id: i32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Created {
+    pub id: i32,
+}
+
+/// A 1x1 transparent PNG image.
+const PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d,
+    0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4, 0x89, 0x00, 0x00, 0x00,
+    0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49,
+    0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+];
+
+lazy_static! {
+    static ref SECRET: String = {
+        dotenv::dotenv().ok();
+        env::var("JWT_SECRET").expect("SECRET must be set")
+    };
+    static ref client: Client =
+        Client::new(launch()).expect("valid rocket instance");
+}
+
+/// Signs up a user with a unique name, returning its info and auth token.
+fn signup(name: &str) -> (UserInfo, String) {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock before epoch");
+    let username = format!("{}{}", name, since_epoch.subsec_nanos());
+    let email = format!("{}@doe.com", username);
+    let user = NewUser {
+        username: &username,
+        email: &email,
+        password: "asdf",
+    };
+
+    let mut response = client
+        .post("/user")
+        .header(ContentType::JSON)
+        .body(serde_json::to_string(&user).expect("failed to serialize"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+
+    let token = response.body_string().expect("no body found");
+    let info = jwt::decode::<UserInfo>(
+        &token,
+        SECRET.as_bytes(),
+        &jwt::Validation::default(),
+    ).expect("failed to decode auth token");
+
+    (info.claims, token)
+}
+
+fn authorized<'c>(request: LocalRequest<'c>, token: &str) -> LocalRequest<'c> {
+    request.header(Header::new("Authorization", token.to_string()))
+}
+
+fn created_id(request: LocalRequest, token: &str) -> i32 {
+    let mut response = authorized(request, token).dispatch();
+    assert_eq!(response.status(), Status::Created);
+
+    let created: Created =
+        serde_json::from_str(&response.body_string().expect("no body found"))
+            .expect("failed to deserialize");
+    created.id
+}
+
+#[test]
+fn other_users_refused() {
+    let (owner, owner_token) = signup("owner");
+    let (_, other_token) = signup("other");
+
+    let journey = NewJourney {
+        user_id: owner.id,
+        title: "Attachments",
+    };
+    let journey = serde_json::to_string(&journey).expect("failed to serialize");
+    let journey_id = created_id(
+        client.post("/journey").header(ContentType::JSON).body(journey),
+        &owner_token,
+    );
+    let entry = NewEntry {
+        user_id: owner.id,
+        journey_id,
+    };
+    let entry = serde_json::to_string(&entry).expect("failed to serialize");
+    let entry_id = created_id(
+        client.post("/entry").header(ContentType::JSON).body(entry),
+        &owner_token,
+    );
+    let base = format!("/entry/{}/attachment", entry_id);
+    let attachment_id =
+        created_id(client.post(base.clone()).body(PNG), &owner_token);
+    let path = format!("{}/{}", base, attachment_id);
+
+    let requests = vec![
+        client.post(base.clone()).body(PNG),
+        client.get(path.clone()),
+        client.get(format!("{}?size=thumb", path)),
+        client
+            .post(format!("{}/upload", base))
+            .header(ContentType::JSON)
+            .body(r#"{"mime_type": "image/png", "size": 67}"#),
+        client.put(format!("{}/confirm", path)),
+        client
+            .put(base.clone())
+            .header(ContentType::JSON)
+            .body(format!("[{}]", attachment_id)),
+        client
+            .put(path.clone())
+            .header(ContentType::JSON)
+            .body(r#"{"caption": "Not mine"}"#),
+        client.delete(path.clone()),
+    ];
+    for request in requests {
+        let response = authorized(request, &other_token).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    let response = authorized(client.get(path), &owner_token).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
@@ -0,0 +1,55 @@
+extern crate journaloo_server;
+extern crate rocket;
+
+use journaloo_server::rocket as launch;
+use rocket::http::{Header, Status};
+use rocket::local::Client;
+
+#[test]
+fn preflight() {
+    let client = Client::new(launch()).expect("valid rocket instance");
+    let response = client
+        .options("/entry/1")
+        .header(Header::new("Origin", "http://localhost:3000"))
+        .header(Header::new("Access-Control-Request-Method", "PUT"))
+        .header(Header::new(
+            "Access-Control-Request-Headers",
+            "Authorization",
+        ))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NoContent);
+
+    let headers = response.headers();
+    assert_eq!(
+        headers.get_one("Access-Control-Allow-Origin"),
+        Some("http://localhost:3000")
+    );
+    assert!(
+        headers
+            .get_one("Access-Control-Allow-Headers")
+            .expect("no allowed headers")
+            .contains("Authorization")
+    );
+    assert!(
+        headers
+            .get_one("Access-Control-Allow-Methods")
+            .expect("no allowed methods")
+            .contains("PUT")
+    );
+}
+
+#[test]
+fn simple_request() {
+    let client = Client::new(launch()).expect("valid rocket instance");
+    let response = client
+        .get("/")
+        .header(Header::new("Origin", "http://localhost:3000"))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.headers().get_one("Access-Control-Allow-Origin"),
+        Some("http://localhost:3000")
+    );
+}